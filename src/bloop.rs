@@ -1,17 +1,24 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use eyre::Result;
 use itertools::Itertools;
 use midly::live::LiveEvent;
-use midly::num::{u4, u7};
-use midly::MidiMessage;
+use midly::live::SystemCommon;
+use midly::num::{u14, u4, u7};
+use midly::{MidiMessage, PitchBend};
 
-use crate::key_effect::KeyEffect;
-use crate::key_tracker::{ChannelSet, KeySet, KeyStatus, PerKey};
+use crate::key_effect::{release, KeyEffect};
+use crate::key_tracker::{iter_u7, ChannelSet, KeySet, KeyStatus, PerKey};
+use crate::rng::Rng;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct TimedMidiMessage {
     pub time: Instant,
+    /// Channel on which the message arrived, after the bloop's
+    /// [`InputChannelMap`].
+    pub channel: u4,
     pub message: MidiMessage,
 }
 
@@ -23,6 +30,8 @@ pub struct BloopPlayback {
     index: usize,
     /// Time offset compared to the recording of the buffer.
     offset: Duration,
+    /// Time at which to play the event at `index`, after timing humanization.
+    next_event_time: Option<Instant>,
 }
 impl BloopPlayback {
     pub fn new(offset: Duration) -> Self {
@@ -30,10 +39,151 @@ impl BloopPlayback {
             keys_pressed: KeySet::new(),
             index: 0,
             offset,
+            next_event_time: None,
         }
     }
 }
 
+/// Schedule of playbacks of a loop, which start at regular intervals.
+///
+/// Each start time is computed from an integer loop index rather than by
+/// repeatedly adding the loop duration, so that scheduling never drifts no
+/// matter how long the loop plays.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct PlaybackSchedule {
+    /// Start time of loop index 0.
+    anchor: Instant,
+    /// Duration of each loop.
+    duration: Duration,
+    /// Index of the next loop to start.
+    next_index: u64,
+}
+impl PlaybackSchedule {
+    /// Number of loops after which the anchor is moved forward, to keep the
+    /// index small.
+    const REBASE_INTERVAL: u64 = 1024;
+
+    /// Returns the start time of the next loop, or `None` if it is too far in
+    /// the future to represent.
+    fn next_time(self) -> Option<Instant> {
+        self.anchor
+            .checked_add(duration_times(self.duration, self.next_index)?)
+    }
+    /// Returns the schedule after the next loop has started.
+    fn advance(self) -> Self {
+        match self.next_time() {
+            // Moving the anchor to the start of a loop is exact, so this never
+            // causes drift.
+            Some(anchor) if self.next_index >= Self::REBASE_INTERVAL => Self {
+                anchor,
+                next_index: 1,
+                ..self
+            },
+            _ => Self {
+                next_index: self.next_index.saturating_add(1),
+                ..self
+            },
+        }
+    }
+}
+
+/// Momentary repetition of a short slice of a loop.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct Stutter {
+    /// Start time of the slice, measured against the original recording.
+    slice_start: Instant,
+    /// Duration of the slice.
+    length: Duration,
+    /// Time at which the current repetition of the slice started.
+    window_start: Instant,
+    /// Keys held at the start of the slice, which are pressed at the start of
+    /// each repetition.
+    start_keys: KeySet,
+    /// Index into the recording buffer of the first event in the slice.
+    start_index: usize,
+    /// Index into the recording buffer of the next event to play.
+    index: usize,
+    /// Keys currently pressed by this repetition.
+    keys_pressed: KeySet,
+}
+
+/// State of an arpeggiator, which plays the keys held on the input one at a
+/// time.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+struct ArpeggiatorState {
+    /// Time of the next step, if the arpeggiator is running.
+    next_step: Option<Instant>,
+    /// Key played most recently, which the pattern continues from.
+    last_key: Option<u7>,
+    /// Key that the arpeggiator is sounding, if any.
+    sounding: Option<u7>,
+    /// Whether an up-down pattern is going down.
+    is_descending: bool,
+}
+
+/// Which of live input and playback controls a controller on the output, for
+/// [`GlobalConfig::soft_takeover`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+struct ControllerTakeover {
+    /// Value most recently sent, if any.
+    value: Option<u7>,
+    /// Whether live input (rather than playback) sent that value.
+    is_live: bool,
+    /// Value most recently ignored from the other source, if any.
+    ignored: Option<u7>,
+}
+
+/// Bank and program selected on each MIDI channel, as far as they are known.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+struct ProgramState([ChannelProgram; 16]);
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+struct ChannelProgram {
+    /// Bank select MSB, if it has been sent.
+    bank_msb: Option<u7>,
+    /// Bank select LSB, if it has been sent.
+    bank_lsb: Option<u7>,
+    /// Program, if it has been sent.
+    program: Option<u7>,
+}
+impl ProgramState {
+    /// Updates the state for a MIDI message on `channel`.
+    fn update(&mut self, channel: u4, message: MidiMessage) {
+        let state = &mut self.0[channel.as_int() as usize];
+        match message {
+            MidiMessage::Controller { controller, value } => match controller.as_int() {
+                BANK_SELECT_MSB_CONTROLLER => state.bank_msb = Some(value),
+                BANK_SELECT_LSB_CONTROLLER => state.bank_lsb = Some(value),
+                _ => (),
+            },
+            MidiMessage::ProgramChange { program } => state.program = Some(program),
+            _ => (),
+        }
+    }
+    /// Returns the messages that select the known bank and program on each
+    /// channel whose program is known.
+    fn messages(&self) -> Vec<(u4, MidiMessage)> {
+        let mut messages = vec![];
+        for (i, state) in self.0.iter().enumerate() {
+            let Some(program) = state.program else {
+                continue;
+            };
+            let channel = u4::from(i as u8);
+            let bank_selects = [
+                (BANK_SELECT_MSB_CONTROLLER, state.bank_msb),
+                (BANK_SELECT_LSB_CONTROLLER, state.bank_lsb),
+            ];
+            for (controller, value) in bank_selects {
+                if let Some(value) = value {
+                    let controller = u7::from(controller);
+                    messages.push((channel, MidiMessage::Controller { controller, value }));
+                }
+            }
+            messages.push((channel, MidiMessage::ProgramChange { program }));
+        }
+        messages
+    }
+}
+
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct MidiPassThrough {
     keys: PerKey<ChannelSet>,
@@ -59,7 +209,7 @@ impl MidiPassThrough {
 
             // Allow note-off events only if the key is no longer held by the
             // user.
-            KeyEffect::Release { key } => {
+            KeyEffect::Release { key, .. } => {
                 self.keys[key].set_off(channel);
                 !self.keys[key].any()
             }
@@ -67,36 +217,89 @@ impl MidiPassThrough {
             // Allow polyphonic aftertouch only if the key is held.
             KeyEffect::Aftertouch { key } => self.keys[key].any(),
 
+            // Allow channel aftertouch only if we're listening and some key is
+            // held.
+            KeyEffect::ChannelAftertouch => {
+                self.is_listening && self.keys.iter().any(|(_, channels)| channels.any())
+            }
+
             // Allow other events iff we're listening.
             _ => self.is_listening,
         }
     }
+    /// Same as [`Self::filter_midi()`], except that releases of keys that were
+    /// never let through are blocked too, so that a bloop that isn't
+    /// listening stays silent.
+    pub fn filter_live_midi(&mut self, channel: u4, message: MidiMessage) -> bool {
+        match KeyEffect::from(message) {
+            KeyEffect::Release { key, .. } if !self.keys[key].contains(channel) => false,
+            _ => self.filter_midi(channel, message),
+        }
+    }
 }
 
 pub struct Bloop {
+    /// Index of the bloop, used to order simultaneous output events.
+    id: usize,
     /// MIDI output channel.
-    midi_out_tx: flume::Sender<LiveEvent<'static>>,
+    midi_out_tx: flume::Sender<OutputEvent>,
     /// User configuration.
     config: BloopConfig,
+    /// User configuration shared by all bloops.
+    global_config: GlobalConfig,
 
     /// State of MIDI passthrough (MIDI input -> output).
+    ///
+    /// Every bloop receives all MIDI input (subject to its input ports and key
+    /// range), but only bloops that are listening pass it through to their
+    /// output channel. By default only the first bloop listens, so that a
+    /// played note sounds once rather than on every bloop's channel. The
+    /// monitor can be used instead to pass input through on a channel of its
+    /// own.
     passthru: MidiPassThrough,
     /// State of MIDI recording (MIDI input -> loop buffer).
+    ///
+    /// The recorder listens exactly when recording is in progress, the bloop is
+    /// record-armed, and the transport is not paused. It is independent of
+    /// `passthru`, and is only ever set by [`Self::update_recorder`]. Overdubs
+    /// record separately and do not use it.
     recorder: MidiPassThrough,
+    /// Whether MIDI input should be captured while recording.
+    is_record_armed: bool,
+    /// Whether recording has been scheduled and has not yet started.
+    is_recording_scheduled: bool,
+    /// Whether recording has started and not yet stopped.
+    is_recording_in_progress: bool,
+    /// Whether the transport is paused.
+    is_paused: bool,
     /// Whether playback should make sound (loop buffer -> output).
     is_playback_active: bool,
+    /// Whether the bloop sends nothing at all, while its playbacks keep
+    /// running in time.
+    is_bypassed: bool,
 
     /// Input and output keys state.
     keys: PerKey<KeyStatus>,
+    /// Keys that have been pressed on the MIDI output and not yet released.
+    keys_sounding: KeySet,
+    /// Keys pressed on the MIDI input within the key range and not yet
+    /// released, so that their releases are honored even if the range changes.
+    keys_accepted: KeySet,
 
     /// Buffer of recorded MIDI messages.
     recording_buffer: Vec<TimedMidiMessage>,
 
-    /// Keys held at the start of the recording, with their corresponding
-    /// velocities.
-    recording_start_state: Vec<(u7, u7)>,
+    /// Keys held at the start of the recording, as `(channel, key, velocity)`.
+    recording_start_state: Vec<(u4, u7, u7)>,
+    /// Pitch bend on the input at the start of the recording.
+    recording_start_pitch_bend: PitchBend,
+    /// Bank and program selected on the input at the start of the recording.
+    recording_start_programs: ProgramState,
     /// Keys held at the end of the recording.
     recording_end_state: KeySet,
+    /// Number of events recorded past the end of the loop that were wrapped
+    /// back to its start.
+    wrapped_event_count: usize,
 
     /// Start time of recording. When recording or playing, this must be `Some`.
     recording_start_time: Option<Instant>,
@@ -106,92 +309,552 @@ pub struct Bloop {
 
     /// Playbacks in progress.
     playbacks: Vec<BloopPlayback>,
-    /// Next playback offset.
-    next_queued_playback_time: Option<Instant>,
+    /// Delay of each playback relative to the boundaries of the recording,
+    /// from [`BloopConfig::phase_offset`] when the loop started playing.
+    phase_shift: Duration,
+    /// Schedule of future playbacks, if the loop is playing.
+    playback_schedule: Option<PlaybackSchedule>,
+    /// Time at which to restart the loop from its beginning, if it has been
+    /// retriggered.
+    retrigger_time: Option<Instant>,
+
+    /// Start and end time of an overdub, which records on top of the existing
+    /// loop instead of replacing it.
+    overdub_span: Option<(Instant, Instant)>,
+    /// Messages recorded during the overdub, with times mapped onto the loop.
+    overdub_buffer: Vec<TimedMidiMessage>,
+    /// Events added to the loop by each finished overdub that has not been
+    /// committed, oldest first, so that they can be removed again.
+    overdub_layers: Vec<Vec<TimedMidiMessage>>,
+    /// Time at which a punch-in started, if one is in progress. Playback is
+    /// silent from there until the punch-out, and input in between replaces
+    /// that part of the loop.
+    punch_start: Option<Instant>,
+    /// Messages recorded during the punch-in.
+    punch_buffer: Vec<TimedMidiMessage>,
+
+    /// Source of randomness for humanization.
+    rng: Rng,
+    /// Start time of a beat and the duration of each beat, once the tempo is
+    /// known.
+    beat_grid: Option<(Instant, Duration)>,
+    /// Breakpoints of the gain applied to playback velocities, as
+    /// `(loop_phase, gain)` sorted by phase.
+    gain_curve: Vec<(f32, f32)>,
+    /// Slice of the loop being repeated, if any.
+    stutter: Option<Stutter>,
+    /// Most recent volume sent in place of a playback velocity, if any.
+    last_volume: Option<u7>,
+    /// Time at which playback most recently pressed each key.
+    playback_press_times: PerKey<Option<Instant>>,
+    /// Releases from playback that are delayed to make notes last at least the
+    /// minimum note length, sorted by time.
+    deferred_releases: Vec<TimedMidiMessage>,
+    /// Most recent pitch bend received on the input.
+    input_pitch_bend: PitchBend,
+    /// Bank and program most recently selected on the input.
+    input_programs: ProgramState,
+    /// Pitch bend messages to send at future times, sorted by time, which
+    /// smooth the return to the starting pitch bend at each loop boundary.
+    pitch_bend_ramp: VecDeque<TimedMidiMessage>,
+    /// State of the arpeggiator, if [`BloopConfig::arpeggiator`] is set.
+    arpeggiator: ArpeggiatorState,
+    /// Soft takeover state of each controller.
+    controllers: PerKey<ControllerTakeover>,
+    /// Keys held by a freeze, which sustain until unfrozen.
+    frozen_keys: KeySet,
+    /// Number of semitones by which every key sent to the output is
+    /// transposed.
+    transpose: i8,
+    /// Velocity with which each key in `keys_sounding` was pressed on the
+    /// output, so that it can be pressed again when transposed.
+    sounding_velocities: PerKey<u7>,
+    /// Output channel on which each key in `keys_sounding` was pressed, so
+    /// that it is released on the same channel.
+    sounding_channels: PerKey<u4>,
+    /// Keys pressed in place of each key held on the input, if
+    /// [`BloopConfig::chord`] was set when it was pressed.
+    chord_keys: PerKey<KeySet>,
+    /// Channel on which each key latched by [`BloopConfig::latch`] was
+    /// pressed on the input, before `input_channel_map`.
+    latched_keys: PerKey<Option<u4>>,
+    /// Keys whose next release on the input is ignored, because the press
+    /// before it unlatched the key.
+    latch_releases_to_ignore: KeySet,
 }
 
 impl Bloop {
-    pub fn new(midi_out_tx: flume::Sender<LiveEvent<'static>>, output_channel: u4) -> Self {
+    pub fn new(midi_out_tx: flume::Sender<OutputEvent>, id: usize) -> Self {
         Self {
+            id,
             midi_out_tx,
-            config: BloopConfig { output_channel },
+            config: BloopConfig {
+                output_channel: u4::from(id as u8),
+                input_channel_map: InputChannelMap::default(),
+                program: None,
+                max_playbacks: None,
+                playback_limit: PlaybackLimit::default(),
+                input_ports: None,
+                key_range: (0.into(), 127.into()),
+                groove: None,
+                velocity_humanization: 0,
+                timing_humanization_ms: 0,
+                length_measures: None,
+                free_length: false,
+                arpeggiator: None,
+                phase_offset: 0.0,
+                velocity_to_volume: false,
+                chord: None,
+                track_output: false,
+                keep_input_channels: false,
+                launch_quantize: LaunchQuantize::default(),
+                live_level: 1.0,
+                loop_level: 1.0,
+                record_mask: RecordMask::default(),
+                mask_passthrough: false,
+                latch: false,
+            },
+            global_config: GlobalConfig::default(),
 
-            passthru: MidiPassThrough::with_listening(true),
+            passthru: MidiPassThrough::with_listening(id == 0),
             recorder: MidiPassThrough::new(),
+            is_record_armed: true,
+            is_recording_scheduled: false,
+            is_recording_in_progress: false,
+            is_paused: false,
             is_playback_active: true,
+            is_bypassed: false,
 
             keys: PerKey::default(),
+            keys_sounding: KeySet::new(),
+            keys_accepted: KeySet::new(),
 
             recording_buffer: vec![],
             recording_start_state: vec![],
+            recording_start_pitch_bend: PitchBend::mid_raw_value(),
+            recording_start_programs: ProgramState::default(),
             recording_end_state: KeySet::new(),
+            wrapped_event_count: 0,
             recording_start_time: None,
             recording_end_time: None,
 
             playbacks: vec![],
-            next_queued_playback_time: None,
+            phase_shift: Duration::ZERO,
+            playback_schedule: None,
+            retrigger_time: None,
+
+            overdub_span: None,
+            overdub_buffer: vec![],
+            overdub_layers: vec![],
+            punch_start: None,
+            punch_buffer: vec![],
+
+            rng: Rng::new(id as u64),
+            beat_grid: None,
+            gain_curve: vec![],
+            stutter: None,
+            last_volume: None,
+            playback_press_times: PerKey::default(),
+            deferred_releases: vec![],
+            input_pitch_bend: PitchBend::mid_raw_value(),
+            input_programs: ProgramState::default(),
+            pitch_bend_ramp: VecDeque::new(),
+            arpeggiator: ArpeggiatorState::default(),
+            controllers: PerKey::default(),
+            frozen_keys: KeySet::new(),
+            transpose: 0,
+            sounding_velocities: PerKey::default(),
+            sounding_channels: PerKey::default(),
+            chord_keys: PerKey::default(),
+            latched_keys: PerKey::default(),
+            latch_releases_to_ignore: KeySet::new(),
         }
     }
 
-    /// Returns whether a key is held by the user or by any playback of the
-    /// loop.
+    /// Returns whether a key is held by the user, the arpeggiator, or any
+    /// playback of the loop that is not paused.
+    ///
+    /// When arpeggiating, keys held by the user are only sounding if the
+    /// arpeggiator is playing them.
     fn is_key_held(&self, key: u7) -> bool {
-        self.keys[key].input.any()
+        let is_held_by_user = match self.config.arpeggiator {
+            Some(_) => self.arpeggiator.sounding == Some(key),
+            None => self.keys[key].input.any(),
+        };
+        is_held_by_user
+            || self.frozen_keys.contains(key)
             || (self.is_playback_active
-                && self
-                    .playbacks
-                    .iter()
-                    .any(|playback| playback.keys_pressed.contains(key)))
+                && !self.is_paused
+                && self.playback_keys_pressed().contains(key))
     }
 
-    /// Sends a MIDI message.
+    /// Sends a MIDI message that was intended to be sent at `time`. `channel`
+    /// is the channel on which it arrived or was recorded.
     ///
-    /// Ignores note-off events for keys that should remain held.
-    fn send(&self, message: MidiMessage) {
-        // If something else is keeping the key held, don't release it yet.
-        match KeyEffect::from(message) {
+    /// Ignores note-off events for keys that should remain held, and converts
+    /// others to the configured form. Ignores note-on events for keys that are
+    /// already sounding, unless [`GlobalConfig::allow_unmatched_note_on`] is
+    /// set. Releases and aftertouch for a sounding key are sent on the channel
+    /// that pressed it.
+    ///
+    /// Drops every message while its output channel is muted or the bloop is
+    /// bypassed. Keys released meanwhile are no longer sounding, so unmuting
+    /// never retriggers them.
+    fn send_at(&mut self, time: Instant, channel: u4, message: MidiMessage) {
+        let channel = match KeyEffect::from(message) {
+            KeyEffect::Release { key, .. } | KeyEffect::Aftertouch { key }
+                if self.keys_sounding.contains(key) =>
+            {
+                self.sounding_channels[key]
+            }
+            _ => self.output_channel(channel),
+        };
+        if self.is_bypassed || self.is_muted(channel) {
+            if let KeyEffect::Release { key, .. } = KeyEffect::from(message) {
+                self.keys_sounding.remove(key);
+            }
+            return;
+        }
+        let message = match KeyEffect::from(message) {
+            KeyEffect::Press { key, .. } if self.keys_sounding.contains(key) => {
+                if !self.global_config.allow_unmatched_note_on {
+                    return;
+                }
+                message
+            }
+            KeyEffect::Press { key, vel } => {
+                self.keys_sounding.insert(key);
+                self.sounding_velocities[key] = vel;
+                self.sounding_channels[key] = channel;
+                message
+            }
+            // If something else is keeping the key held, don't release it yet.
             KeyEffect::Release { key, .. } if self.is_key_held(key) => return,
+            KeyEffect::Release { key, vel } => {
+                self.keys_sounding.remove(key);
+                self.release_message(key, vel)
+            }
+            _ => message,
+        };
+        self.send_output(time, channel, message);
+    }
+    /// Sends a MIDI message from playback of the loop, scaling the velocity of
+    /// each note-on by [`BloopConfig::loop_level`].
+    ///
+    /// If [`BloopConfig::velocity_to_volume`] is set, the velocity of each
+    /// note-on is sent as a volume change instead, unless the volume is
+    /// already at that level, and the note is played at full velocity.
+    ///
+    /// Releases are delayed as needed to make each note last at least
+    /// [`GlobalConfig::min_note_length_ms`].
+    fn send_playback(&mut self, time: Instant, channel: u4, message: MidiMessage) {
+        let message = scale_velocity(message, self.config.loop_level);
+        match KeyEffect::from(message) {
+            KeyEffect::Press { key, .. } => {
+                // A delayed release must not cut off the new note.
+                self.send_deferred_releases(time, |event| match event.message.into() {
+                    KeyEffect::Release { key: k, .. } => k == key,
+                    _ => false,
+                });
+                self.playback_press_times[key] = Some(time);
+            }
+            KeyEffect::Release { key, .. } => {
+                let min_length =
+                    Duration::from_millis(self.global_config.min_note_length_ms as u64);
+                let earliest_release = self.playback_press_times[key].map(|t| t + min_length);
+                // Don't let the note sound past the start of the next pass.
+                let latest_release = self.playback_schedule.and_then(PlaybackSchedule::next_time);
+                if let Some(release_time) = earliest_release {
+                    let release_time = option_at_most(latest_release, release_time);
+                    if release_time > time {
+                        let event = TimedMidiMessage {
+                            time: release_time,
+                            channel,
+                            message,
+                        };
+                        let i = self
+                            .deferred_releases
+                            .partition_point(|e| e.time <= release_time);
+                        self.deferred_releases.insert(i, event);
+                        return;
+                    }
+                }
+            }
             _ => (),
         }
-
-        let channel = self.config.output_channel;
-        let event = LiveEvent::Midi { channel, message };
-        if let Err(e) = self.midi_out_tx.send(event) {
+        match message {
+            MidiMessage::NoteOn { key, vel }
+                if self.config.velocity_to_volume && vel.as_int() > 0 =>
+            {
+                if self.last_volume != Some(vel) {
+                    self.last_volume = Some(vel);
+                    let volume = MidiMessage::Controller {
+                        controller: VOLUME_CONTROLLER.into(),
+                        value: vel,
+                    };
+                    self.send_output(time, self.output_channel(channel), volume);
+                }
+                let vel = u7::max_value();
+                self.send_at(time, channel, MidiMessage::NoteOn { key, vel });
+            }
+            MidiMessage::PitchBend { .. } => {
+                // A recorded bend takes over from the ramp.
+                self.pitch_bend_ramp.clear();
+                self.send_at(time, channel, message);
+            }
+            MidiMessage::Controller { controller, value } => {
+                if self.take_over_controller(controller, value, false) {
+                    self.send_at(time, channel, message);
+                }
+            }
+            _ => self.send_at(time, channel, message),
+        }
+    }
+    /// Returns whether to send a controller value from live input or playback.
+    ///
+    /// With [`GlobalConfig::soft_takeover`], values from one source are ignored
+    /// while the other source controls the controller, until they reach or
+    /// cross the value it last sent. Then the source takes over.
+    fn take_over_controller(&mut self, controller: u7, value: u7, is_live: bool) -> bool {
+        if !self.global_config.soft_takeover {
+            return true;
+        }
+        let state = &mut self.controllers[controller];
+        let takes_over = match state.value {
+            Some(current) if state.is_live != is_live => {
+                let ignored = state.ignored.unwrap_or(value);
+                let (low, high) = (ignored.min(value), ignored.max(value));
+                (low..=high).contains(&current)
+            }
+            _ => true,
+        };
+        if takes_over {
+            *state = ControllerTakeover {
+                value: Some(value),
+                is_live,
+                ignored: None,
+            };
+        } else {
+            state.ignored = Some(value);
+        }
+        takes_over
+    }
+    /// Sends delayed releases that match `filter` at `time`, or when they are
+    /// due if that is sooner.
+    fn send_deferred_releases(
+        &mut self,
+        time: Instant,
+        filter: impl Fn(&TimedMidiMessage) -> bool,
+    ) {
+        let (to_send, to_keep) = std::mem::take(&mut self.deferred_releases)
+            .into_iter()
+            .partition(|event| filter(event));
+        self.deferred_releases = to_keep;
+        for event in to_send {
+            self.send_at(event.time.min(time), event.channel, event.message);
+        }
+    }
+    /// Sends delayed releases that are due, and returns the time of the next
+    /// one.
+    fn do_deferred_releases(&mut self, now: Instant) -> Option<Instant> {
+        let due = self
+            .deferred_releases
+            .partition_point(|event| event.time <= now);
+        for event in self.deferred_releases.drain(..due).collect_vec() {
+            self.send_at(event.time, event.channel, event.message);
+        }
+        self.deferred_releases.first().map(|event| event.time)
+    }
+    /// Sends a MIDI message on an output channel without any processing other
+    /// than transposition. Keys transposed out of range are dropped, and
+    /// nothing is sent while the bloop is bypassed.
+    fn send_output(&self, time: Instant, channel: u4, message: MidiMessage) {
+        if self.is_bypassed {
+            return;
+        }
+        let transpose = |key: u7| {
+            let key = key.as_int() as i16 + self.transpose as i16;
+            (0..=127).contains(&key).then(|| u7::from(key as u8))
+        };
+        let message = match message {
+            MidiMessage::NoteOn { key, vel } => match transpose(key) {
+                Some(key) => MidiMessage::NoteOn { key, vel },
+                None => return,
+            },
+            MidiMessage::NoteOff { key, vel } => match transpose(key) {
+                Some(key) => MidiMessage::NoteOff { key, vel },
+                None => return,
+            },
+            MidiMessage::Aftertouch { key, vel } => match transpose(key) {
+                Some(key) => MidiMessage::Aftertouch { key, vel },
+                None => return,
+            },
+            _ => message,
+        };
+        let event = OutputEvent {
+            time,
+            source: self.id,
+            event: LiveEvent::Midi { channel, message },
+        };
+        let is_release = matches!(KeyEffect::from(message), KeyEffect::Release { .. });
+        let result = match self.midi_out_tx.try_send(event) {
+            // Never drop a release, because that would leave a stuck note.
+            Err(flume::TrySendError::Full(event)) if is_release => {
+                self.midi_out_tx.send(event).map_err(|e| e.to_string())
+            }
+            Err(flume::TrySendError::Full(_)) => {
+                Err("output queue is full; dropping event".to_owned())
+            }
+            result => result.map_err(|e| e.to_string()),
+        };
+        if let Err(e) = result {
             log::error!("Error sending MIDI event: {e}");
         }
     }
+    /// Returns the output channel for a message that arrived or was recorded
+    /// on `channel`.
+    fn output_channel(&self, channel: u4) -> u4 {
+        match self.config.keep_input_channels {
+            true => channel,
+            false => self.config.output_channel,
+        }
+    }
+    /// Returns whether an output channel is muted by
+    /// [`GlobalConfig::muted_channels`].
+    fn is_muted(&self, channel: u4) -> bool {
+        self.global_config.muted_channels.contains(channel)
+    }
+    /// Releases every key the bloop is sounding, even keys that the user or a
+    /// playback is still holding.
+    pub fn release_all_sounding(&mut self, now: Instant) {
+        self.frozen_keys = KeySet::new();
+        for key in std::mem::take(&mut self.keys_sounding).iter_keys() {
+            let message = self.release_message(key, None);
+            self.send_output(now, self.sounding_channels[key], message);
+        }
+    }
+
+    /// Sets the number of semitones by which output is transposed, releasing
+    /// every sounding key at its old pitch and pressing it at the new one.
+    pub fn set_transpose(&mut self, now: Instant, transpose: i8) {
+        if transpose == self.transpose {
+            return;
+        }
+        for key in self.keys_sounding.iter_keys() {
+            let message = self.release_message(key, None);
+            self.send_output(now, self.sounding_channels[key], message);
+        }
+        self.transpose = transpose;
+        for key in self.keys_sounding.iter_keys() {
+            let vel = self.sounding_velocities[key];
+            self.send_output(
+                now,
+                self.sounding_channels[key],
+                MidiMessage::NoteOn { key, vel },
+            );
+        }
+    }
 
+    /// Returns a message that releases `key` in the configured form.
+    ///
+    /// `vel` is the release velocity, if known.
+    fn release_message(&self, key: u7, vel: Option<u7>) -> MidiMessage {
+        let default_vel = self.keys[key]
+            .last_release_velocity
+            .unwrap_or(self.global_config.release_velocity);
+        self.global_config
+            .note_off_style
+            .release_message(key, vel, default_vel)
+    }
+
+    /// Returns the keys held by playback, which are the keys held by the
+    /// stutter if there is one.
     pub fn playback_keys_pressed(&self) -> KeySet {
+        if let Some(stutter) = &self.stutter {
+            return stutter.keys_pressed;
+        }
         self.playbacks
             .iter()
             .map(|playback| playback.keys_pressed)
             .fold(KeySet::new(), |a, b| a | b)
     }
-    pub fn release_keys(&self, keys_to_release: KeySet) {
+    pub fn release_keys(&mut self, time: Instant, keys_to_release: KeySet) {
         for key in keys_to_release.iter_keys() {
-            self.send(MidiMessage::NoteOn { key, vel: 0.into() });
+            self.send_at(time, self.keys[key].last_channel, release(key));
         }
     }
 
     /// Cancels all in-progress playbacks of the loop.
     pub fn cancel_recording(&mut self) {
+        self.overdub_span = None;
+        self.overdub_buffer.clear();
+        self.punch_start = None;
+        self.punch_buffer.clear();
         if self.recording_start_time.is_some() {
             self.recording_start_time = None;
             self.recording_end_time = None;
-            self.recorder.is_listening = false;
+            self.is_recording_scheduled = false;
+            self.is_recording_in_progress = false;
+            self.update_recorder();
         }
     }
-    pub fn cancel_all_playbacks(&mut self) {
+    pub fn cancel_all_playbacks(&mut self, now: Instant) {
         let keys_to_release = self.playback_keys_pressed();
+        // Live input controls every controller once nothing is playing.
+        self.controllers = PerKey::default();
+        self.stutter = None;
         self.playbacks.clear();
+        self.pitch_bend_ramp.clear();
+        self.send_deferred_releases(now, |_| true);
         self.cancel_next_playback();
-        self.release_keys(keys_to_release);
+        self.retrigger_time = None;
+        self.release_keys(now, keys_to_release);
     }
     pub fn cancel_next_playback(&mut self) {
-        self.next_queued_playback_time = None;
+        self.playback_schedule = None;
     }
-    pub fn is_recording(&self) -> bool {
-        let now = Instant::now();
+    /// Stops recording and playback, and discards the recording.
+    pub fn clear(&mut self, now: Instant) {
+        self.cancel_recording();
+        self.cancel_all_playbacks(now);
+        self.recording_buffer.clear();
+        self.overdub_layers.clear();
+        self.recording_start_state.clear();
+        self.recording_start_programs = ProgramState::default();
+        self.recording_end_state = KeySet::new();
+        self.wrapped_event_count = 0;
+    }
+    /// Returns whether the bloop has a recorded loop, even if it is not
+    /// playing.
+    pub fn has_recording(&self) -> bool {
+        !self.recording_buffer.is_empty() || self.is_playing_back()
+    }
+    /// Returns whether the bloop has no loop and is not recording or waiting to
+    /// record.
+    pub fn is_empty(&self) -> bool {
+        !self.has_recording() && self.recording_start_time.is_none()
+    }
+    /// Returns whether playing the loop would make no sound, because it
+    /// presses no keys and sends nothing other than releases.
+    fn is_silent(&self) -> bool {
+        self.recording_start_state.is_empty()
+            && self
+                .recording_buffer
+                .iter()
+                .all(|event| matches!(KeyEffect::from(event.message), KeyEffect::Release { .. }))
+    }
+    /// Returns whether the loop is playing or scheduled to play.
+    pub fn is_playing_back(&self) -> bool {
+        !self.playbacks.is_empty() || self.playback_schedule.is_some()
+    }
+    /// Returns whether a recording that was scheduled covers `now`.
+    ///
+    /// A loop whose recording has finished is not recording, even if it has
+    /// since been moved or lengthened so that its span covers `now`.
+    pub fn is_recording(&self, now: Instant) -> bool {
+        if !self.is_recording_scheduled && !self.is_recording_in_progress {
+            return false;
+        }
         let past_start = self
             .recording_start_time
             .is_some_and(|start_time| start_time <= now);
@@ -200,384 +863,4603 @@ impl Bloop {
             .is_some_and(|end_time| end_time <= now);
         past_start && !past_end
     }
+    /// Toggles passthrough of live input. This does not affect what is
+    /// recorded, which depends only on the record arm.
     pub fn toggle_listening(&mut self) {
         self.passthru.is_listening = !self.passthru.is_listening;
-        if self.is_recording() {
-            self.recorder.is_listening = self.passthru.is_listening;
+    }
+    pub fn toggle_record_arm(&mut self) {
+        self.is_record_armed = !self.is_record_armed;
+        self.update_recorder();
+    }
+    /// Makes the recorder listen iff recording is in progress, the bloop is
+    /// record-armed, and the transport is not paused.
+    fn update_recorder(&mut self) {
+        self.recorder.is_listening =
+            self.is_recording_in_progress && self.is_record_armed && !self.is_paused;
+    }
+    /// Returns whether recording has started and not yet stopped.
+    pub fn is_recording_in_progress(&self) -> bool {
+        self.is_recording_in_progress
+    }
+    /// Bypasses the bloop, so that it sends nothing while its playbacks keep
+    /// running in time, or stops bypassing it, pressing the keys that
+    /// playbacks are holding.
+    pub fn toggle_bypass(&mut self, now: Instant) {
+        if self.is_bypassed {
+            self.is_bypassed = false;
+            if self.is_playback_active {
+                self.press_playback_keys(now);
+                self.restore_playback_programs(now);
+            }
+        } else {
+            self.pitch_bend_ramp.clear();
+            self.send_deferred_releases(now, |_| true);
+            self.stop_arpeggiator(now);
+            for key in std::mem::take(&mut self.keys_sounding).iter_keys() {
+                let message = self.release_message(key, None);
+                self.send_output(now, self.sounding_channels[key], message);
+            }
+            self.is_bypassed = true;
         }
     }
-    pub fn toggle_playing(&mut self) {
+    pub fn toggle_playing(&mut self, now: Instant) {
         self.is_playback_active = !self.is_playback_active;
         if self.is_playback_active {
-            // Press keys that should be held.
-            for key in self.playback_keys_pressed().iter_keys() {
-                // Is the user helding the key already?
-                if !self.keys[key].input.any() {
-                    // The user is not holding the key, so we should press it.
-                    let vel = self.keys[key].last_velocity;
-                    self.send(MidiMessage::NoteOn { key, vel });
-                }
-            }
+            self.press_playback_keys(now);
+            self.restore_playback_programs(now);
         } else {
             // Release keys that should not be pressed.
-            self.release_keys(self.playback_keys_pressed());
+            self.release_keys(now, self.playback_keys_pressed());
+            self.send_deferred_releases(now, |_| true);
         }
     }
-    pub fn start_recording(&mut self, start: Instant, end: Option<Instant>) {
-        self.recording_start_time = Some(start);
-        self.recording_end_time = end;
+    /// Presses keys that playbacks should be holding.
+    fn press_playback_keys(&mut self, time: Instant) {
+        for key in self.playback_keys_pressed().iter_keys() {
+            // Is the user helding the key already?
+            if !self.keys[key].input.any() {
+                // The user is not holding the key, so we should press it.
+                let KeyStatus {
+                    last_velocity: vel,
+                    last_channel: channel,
+                    ..
+                } = self.keys[key];
+                self.send_at(time, channel, MidiMessage::NoteOn { key, vel });
+            }
+        }
     }
-    pub fn start_playing(&mut self, duration: Duration) {
-        log::trace!("Start playing");
+    /// Selects the bank and program that playback would have selected by
+    /// `time`, if the loop changes them.
+    fn restore_playback_programs(&mut self, time: Instant) {
+        let Some(end_time) = self.recording_end_time else {
+            return;
+        };
+        if self.playbacks.is_empty() || self.programs_at(end_time) == self.recording_start_programs
+        {
+            return;
+        }
+        let Some(position) = self.loop_time(time) else {
+            return;
+        };
+        for (channel, message) in self.programs_at(position).messages() {
+            self.send_playback(time, channel, message);
+        }
+    }
+    /// Silences playback and stops recording input while the transport is
+    /// paused.
+    pub fn pause(&mut self, now: Instant) {
+        self.is_paused = true;
+        self.update_recorder();
+        self.pitch_bend_ramp.clear();
+        self.send_deferred_releases(now, |_| true);
+        self.stop_arpeggiator(now);
+        if self.is_playback_active {
+            self.release_keys(now, self.playback_keys_pressed());
+        }
+    }
+    /// Resumes after the transport was paused for `paused_duration`, shifting
+    /// every scheduled and recorded time later by that much so that the loop
+    /// continues from where it was paused.
+    pub fn resume(&mut self, now: Instant, paused_duration: Duration) {
+        let shift = |t: &mut Instant| *t += paused_duration;
+        self.recording_start_time.as_mut().map(shift);
+        self.recording_end_time.as_mut().map(shift);
+        for event in self
+            .recording_buffer
+            .iter_mut()
+            .chain(&mut self.overdub_buffer)
+            .chain(self.overdub_layers.iter_mut().flatten())
+            .chain(&mut self.punch_buffer)
+        {
+            shift(&mut event.time);
+        }
+        self.punch_start.as_mut().map(shift);
+        if let Some((start, end)) = &mut self.overdub_span {
+            shift(start);
+            shift(end);
+        }
+        if let Some(schedule) = &mut self.playback_schedule {
+            shift(&mut schedule.anchor);
+        }
+        self.retrigger_time.as_mut().map(shift);
+        for playback in &mut self.playbacks {
+            playback.next_event_time.as_mut().map(shift);
+        }
+        if let Some(stutter) = &mut self.stutter {
+            shift(&mut stutter.window_start);
+        }
+
+        self.is_paused = false;
+        self.update_recorder();
+        if self.is_playback_active {
+            self.press_playback_keys(now);
+        }
+    }
+    /// Restarts the loop from its beginning at `time`, keeping the recording.
+    ///
+    /// Keys that the loop holds at its start keep sounding; other keys held by
+    /// playback are released. Any phase offset takes effect the next time the
+    /// loop starts playing.
+    pub fn reanchor(&mut self, time: Instant) {
+        let Some((start_time, end_time)) = self.loop_span(time) else {
+            return;
+        };
+        if !self.is_playing_back() {
+            return;
+        }
+        let Some(position) = self.loop_time(time).map(|t| t - start_time) else {
+            return;
+        };
+        // The punch-in is measured against the old start of the loop.
+        self.punch_out(time);
 
-        self.recorder.is_listening = false;
+        // Shift the recording so that `time` is the start of a pass. Overdub
+        // events are stored relative to the loop, so they move with it.
+        let shift = |t: &mut Instant| *t += position;
+        self.recording_start_time = Some(start_time + position);
+        self.recording_end_time = Some(end_time + position);
+        for event in self
+            .recording_buffer
+            .iter_mut()
+            .chain(&mut self.overdub_buffer)
+            .chain(self.overdub_layers.iter_mut().flatten())
+        {
+            shift(&mut event.time);
+        }
+        self.phase_shift = Duration::ZERO;
 
-        self.recording_end_state = self
-            .keys
+        let start_keys: KeySet = self
+            .recording_start_state
             .iter()
-            .map(|(_, status)| status.input.any())
+            .map(|&(_, key, _)| key)
             .collect();
-
-        let Some(start_time) = self.recording_start_time else {
-            log::error!("cannot start playing with no start time");
+        let keys_to_release = self
+            .playback_keys_pressed()
+            .iter_keys()
+            .filter(|&key| !start_keys.contains(key))
+            .collect();
+        self.stutter = None;
+        self.playbacks.clear();
+        self.pitch_bend_ramp.clear();
+        if self.is_playback_active {
+            self.release_keys(time, keys_to_release);
+        }
+        self.playback_schedule = Some(PlaybackSchedule {
+            anchor: time,
+            duration: end_time - start_time,
+            next_index: 0,
+        });
+    }
+    /// Moves the playhead forward by `amount` (or backward, if `is_backward`)
+    /// at `now`, without playing the events in between. Keys that should be
+    /// held at the new position are pressed, and other keys that playbacks
+    /// were holding are released.
+    ///
+    /// Only the playback at the new position continues; any others that
+    /// overlap it are dropped.
+    pub fn scrub(&mut self, now: Instant, amount: Duration, is_backward: bool) {
+        let Some((start_time, end_time)) = self.loop_span(now) else {
+            return;
+        };
+        if !self.is_playing_back() {
+            return;
+        }
+        let Some(position) = self.loop_time(now).map(|t| t - start_time) else {
+            return;
+        };
+        let loop_duration = end_time - start_time;
+        let loop_nanos = loop_duration.as_nanos();
+        let amount_nanos = amount.as_nanos() % loop_nanos;
+        let new_nanos = match is_backward {
+            false => position.as_nanos() + amount_nanos,
+            true => position.as_nanos() + loop_nanos - amount_nanos,
+        } % loop_nanos;
+        let Some(new_start) = now.checked_sub(Duration::from_nanos(new_nanos as u64)) else {
             return;
         };
-        self.recording_end_time = Some(start_time + duration);
+        // The punch-in is measured against the old start of the loop.
+        self.punch_out(now);
 
-        self.next_queued_playback_time = self.recording_end_time;
-    }
+        // Shift the recording so that `now` is at the new position. Overdub
+        // events are stored relative to the loop, so they move with it.
+        let rebase = |t: &mut Instant| *t = new_start + t.saturating_duration_since(start_time);
+        self.recording_start_time = Some(new_start);
+        self.recording_end_time = Some(new_start + loop_duration);
+        for event in self
+            .recording_buffer
+            .iter_mut()
+            .chain(&mut self.overdub_buffer)
+            .chain(self.overdub_layers.iter_mut().flatten())
+        {
+            rebase(&mut event.time);
+        }
+        self.phase_shift = Duration::ZERO;
 
-    pub fn recv_midi(&mut self, channel: u4, event: TimedMidiMessage) {
-        if self.passthru.filter_midi(channel, event.message) {
-            match KeyEffect::from(event.message) {
-                KeyEffect::Press { key, vel } => {
-                    self.keys[key].input.set_on(channel);
+        let old_keys = self.playback_keys_pressed();
+        let presses = self.presses_held_at(now);
+        let mut playback = BloopPlayback::new(Duration::ZERO);
+        playback.index = self
+            .recording_buffer
+            .partition_point(|event| event.time < now);
+        playback.keys_pressed = presses.iter().map(|&(_, key, _)| key).collect();
+        self.stutter = None;
+        self.pitch_bend_ramp.clear();
+        self.playbacks = vec![playback];
+        self.playback_schedule = Some(PlaybackSchedule {
+            anchor: new_start,
+            duration: loop_duration,
+            next_index: 1,
+        });
+
+        if self.is_playback_active {
+            let keys_to_release = old_keys
+                .iter_keys()
+                .filter(|&key| !self.playbacks[0].keys_pressed.contains(key))
+                .collect();
+            self.release_keys(now, keys_to_release);
+            for (channel, key, vel) in presses {
+                if !old_keys.contains(key) && !self.keys[key].input.any() {
                     self.keys[key].last_velocity = vel;
+                    self.keys[key].last_channel = channel;
+                    self.send_at(now, channel, MidiMessage::NoteOn { key, vel });
                 }
-                KeyEffect::Release { key } => self.keys[key].input.set_off(channel),
-                KeyEffect::Aftertouch { .. } | KeyEffect::None => (),
             }
-            self.send(event.message);
+            self.restore_playback_programs(now);
         }
+    }
+    /// Shifts playback so that the earliest note in the loop falls on the
+    /// nearest step of the master grid, for a loop that was recorded slightly
+    /// off the beat.
+    pub fn align(&mut self, now: Instant, grid: AlignGrid) {
+        let Some((origin, beat)) = self.beat_grid else {
+            return;
+        };
+        let step = match grid {
+            AlignGrid::Beat => beat,
+            AlignGrid::Bar => beat * self.global_config.beats_per_measure.max(1),
+        };
+        let Some((start_time, _)) = self.loop_span(now) else {
+            return;
+        };
+        let Some(first_note) = self
+            .recording_buffer
+            .iter()
+            .find(|event| matches!(KeyEffect::from(event.message), KeyEffect::Press { .. }))
+        else {
+            return;
+        };
+        let Some(position) = self.loop_time(now).map(|t| t - start_time) else {
+            return;
+        };
+        let Some(pass_start) = now.checked_sub(position) else {
+            return;
+        };
+        // Time at which the note plays in the current pass.
+        let note_time = pass_start + (first_note.time - start_time);
 
-        if self.recorder.filter_midi(channel, event.message) {
-            match KeyEffect::from(event.message) {
-                KeyEffect::Press { key, vel } => {
-                    self.keys[key].recording.set_on(channel);
-                    self.keys[key].last_velocity = vel;
-                }
-                KeyEffect::Release { key } => self.keys[key].recording.set_off(channel),
-                KeyEffect::Aftertouch { .. } | KeyEffect::None => (),
-            }
-            self.recording_buffer.push(event);
+        let step_nanos = step.as_nanos() as i128;
+        if step_nanos == 0 {
+            return;
+        }
+        let offset_nanos = match note_time >= origin {
+            true => (note_time - origin).as_nanos() as i128,
+            false => -((origin - note_time).as_nanos() as i128),
+        };
+        let late_nanos = offset_nanos.rem_euclid(step_nanos);
+        if late_nanos <= step_nanos / 2 {
+            // The note is late, so move the playhead forward.
+            self.scrub(now, Duration::from_nanos(late_nanos as u64), false);
+        } else {
+            let early_nanos = step_nanos - late_nanos;
+            self.scrub(now, Duration::from_nanos(early_nanos as u64), true);
         }
     }
+    /// Restarts the loop from its beginning at `time`, whether or not it is
+    /// playing. Playbacks in progress continue until then.
+    pub fn retrigger(&mut self, time: Instant) {
+        if self.is_recording_in_progress || self.loop_span(time).is_none() {
+            return;
+        }
+        self.retrigger_time = Some(time);
+    }
 
-    pub fn do_events_and_return_wake_time(&mut self, now: Instant) -> Option<Instant> {
+    pub fn start_recording(&mut self, start: Instant, end: Option<Instant>) {
+        self.is_recording_scheduled = true;
+        self.recording_start_time = Some(start);
+        self.recording_end_time = end;
+    }
+    /// Ends a recording that has no end time after the whole number of
+    /// `loop_duration`s nearest to its length so far, and at least one.
+    pub fn end_open_recording(&mut self, now: Instant, loop_duration: Duration) {
+        if !self.is_recording(now) || self.recording_end_time.is_some() {
+            return;
+        }
+        let Some(start_time) = self.recording_start_time else {
+            return;
+        };
+        let loops =
+            (now - start_time).as_secs_f64() / loop_duration.as_secs_f64().max(f64::EPSILON);
+        let loops = (loops.round() as u32).max(1);
+        log::trace!("End recording after {loops} loops");
+        // If this is in the past, the recording stops as soon as events are
+        // next processed, and events recorded since then wrap around.
+        self.recording_end_time = Some(start_time + loop_duration * loops);
+    }
+    /// Schedules an overdub on top of the existing loop.
+    pub fn start_overdub(&mut self, start: Instant, end: Instant) {
+        self.overdub_span = Some((start, end));
+        self.overdub_buffer.clear();
+    }
+    /// Returns whether an overdub is in progress.
+    fn is_overdubbing(&self, now: Instant) -> bool {
+        self.overdub_span
+            .is_some_and(|(start, end)| (start..end).contains(&now))
+    }
+    /// Maps `time` onto the first pass of the loop.
+    fn loop_time(&self, time: Instant) -> Option<Instant> {
         let start_time = self.recording_start_time?;
-
-        if now <= start_time {
-            // We are not ready to start recording.
-            return Some(start_time);
+        let loop_nanos = (self.recording_end_time? - start_time).as_nanos();
+        if loop_nanos == 0 {
+            return None;
         }
+        let nanos = time
+            .saturating_duration_since(start_time + self.phase_shift)
+            .as_nanos()
+            % loop_nanos;
+        Some(start_time + Duration::from_nanos(nanos as u64))
+    }
+    /// Merges the overdub into the loop.
+    fn finish_overdub(&mut self) {
+        let Some((_, overdub_end)) = self.overdub_span.take() else {
+            return;
+        };
+        let mut events = std::mem::take(&mut self.overdub_buffer);
 
-        if self.is_recording() && !self.recorder.is_listening {
-            // Start recording!
-            log::trace!("Start recording");
-            self.recorder.is_listening = self.passthru.is_listening;
-            self.recording_buffer.clear();
-            self.recording_start_state = self
-                .keys
-                .iter()
-                .filter(|(_, status)| status.input.any())
-                .map(|(i, status)| (i, status.last_velocity))
-                .collect_vec();
+        // Release keys that are still held at the end of the overdub.
+        let mut keys_held = KeySet::new();
+        for event in &events {
+            keys_held.update(event.message);
         }
+        if let Some(time) = self.loop_time(overdub_end) {
+            events.extend(keys_held.iter_keys().map(|key| {
+                let channel = self.keys[key].last_channel;
+                let message = release(key);
+                TimedMidiMessage {
+                    time,
+                    channel,
+                    message,
+                }
+            }));
+        }
+        // This sort is stable, so events keep their order within the overdub.
+        events.sort_by_key(|event| event.time);
 
-        let end_time = self.recording_end_time?;
-        let loop_duration = end_time - start_time;
-
-        if self.recorder.is_listening {
-            if now <= end_time {
-                // We are not ready to stop recording. Keep recording.
-                return Some(end_time);
+        // Keep each playback at the same event. Existing events come before
+        // new events at the same time.
+        for playback in &mut self.playbacks {
+            if let Some(next_event) = self.recording_buffer.get(playback.index) {
+                playback.index += events.partition_point(|e| e.time < next_event.time);
             } else {
-                // Stop recording and start playing!
-                self.start_playing(loop_duration);
+                playback.index += events.len();
             }
         }
+        self.overdub_layers.push(events.clone());
+        self.recording_buffer.extend(events);
+        self.recording_buffer.sort_by_key(|event| event.time);
+    }
+    /// Removes the events of the most recent overdub from the loop, releasing
+    /// any keys that only it was holding.
+    pub fn undo_layer(&mut self, now: Instant) {
+        let Some(layer) = self.overdub_layers.pop() else {
+            return;
+        };
+        for event in layer {
+            // Events from a layer come after identical events from earlier
+            // layers.
+            let Some(i) = self.recording_buffer.iter().rposition(|e| *e == event) else {
+                continue;
+            };
+            self.recording_buffer.remove(i);
+            let indices = self
+                .playbacks
+                .iter_mut()
+                .map(|playback| &mut playback.index)
+                .chain(
+                    self.stutter
+                        .iter_mut()
+                        .flat_map(|stutter| [&mut stutter.start_index, &mut stutter.index]),
+                );
+            for index in indices {
+                if *index > i {
+                    *index -= 1;
+                }
+            }
+        }
+
+        let mut keys_to_release = KeySet::new();
+        for i in 0..self.playbacks.len() {
+            let keys_held = self.keys_held_before(self.playbacks[i].index);
+            let playback = &mut self.playbacks[i];
+            for key in playback.keys_pressed.iter_keys() {
+                if !keys_held.contains(key) {
+                    keys_to_release.insert(key);
+                }
+            }
+            playback.keys_pressed = keys_held;
+            playback.next_event_time = None;
+        }
+        if self.is_playback_active {
+            self.release_keys(now, keys_to_release);
+        }
+    }
+    /// Stops the loop, but keeps holding the keys it is sounding until
+    /// [`Self::unfreeze()`].
+    pub fn freeze(&mut self, now: Instant) {
+        if !self.is_playback_active {
+            return;
+        }
+        let keys = self.playback_keys_pressed();
+        self.frozen_keys = self.frozen_keys | keys;
+        self.cancel_all_playbacks(now);
+        for key in keys.iter_keys() {
+            if !self.keys_sounding.contains(key) {
+                let KeyStatus {
+                    last_velocity: vel,
+                    last_channel: channel,
+                    ..
+                } = self.keys[key];
+                self.send_at(now, channel, MidiMessage::NoteOn { key, vel });
+            }
+        }
+    }
+    /// Releases the keys held by a freeze.
+    pub fn unfreeze(&mut self, now: Instant) {
+        let keys = std::mem::take(&mut self.frozen_keys);
+        self.release_keys(now, keys);
+    }
+    /// Keeps every overdub in the loop, so that they can no longer be undone.
+    pub fn commit_overdubs(&mut self) {
+        self.overdub_layers.clear();
+    }
+    /// Starts replacing the loop from `now`. Playback is silent and input is
+    /// recorded until [`Self::punch_out()`].
+    pub fn punch_in(&mut self, now: Instant) {
+        if !self.is_record_armed
+            || self.punch_start.is_some()
+            || self.playbacks.is_empty()
+            || self.overdub_span.is_some()
+            || self.stutter.is_some()
+            || self.loop_time(now).is_none()
+        {
+            return;
+        }
+        log::trace!("Punch in");
+        self.punch_start = Some(now);
+        self.punch_buffer.clear();
+        let keys_to_release = self.playback_keys_pressed();
+        for playback in &mut self.playbacks {
+            playback.keys_pressed = KeySet::new();
+        }
+        if self.is_playback_active {
+            self.release_keys(now, keys_to_release);
+        }
+    }
+    /// Replaces the part of the loop played since [`Self::punch_in()`] with
+    /// the input recorded since then. A punch-in never lasts longer than the
+    /// loop.
+    ///
+    /// Keys that the old loop holds into the replaced part are released where
+    /// it starts, and keys still held in the new input are released where it
+    /// ends. Releases elsewhere in the loop of keys whose presses were
+    /// replaced are dropped.
+    pub fn punch_out(&mut self, now: Instant) {
+        let Some(punch_start) = self.punch_start.take() else {
+            return;
+        };
+        let mut events = std::mem::take(&mut self.punch_buffer);
+        let Some((start_time, end_time)) = self.recording_start_time.zip(self.recording_end_time)
+        else {
+            return;
+        };
+        let Some(window_start) = self.loop_time(punch_start) else {
+            return;
+        };
+        log::trace!("Punch out");
+        let loop_duration = end_time - start_time;
+        let length = now
+            .saturating_duration_since(punch_start)
+            .min(loop_duration);
+        events.retain(|event| event.time <= punch_start + length);
+
+        // Lay out the loop starting from the punch-in, so that the replaced
+        // part is contiguous even if it wraps around the end of the loop.
+        let position = |time: Instant| match time >= window_start {
+            true => time - window_start,
+            false => time + loop_duration - window_start,
+        };
+        let keys_cut = self.keys_held_at(window_start);
+        let mut keys_held = KeySet::new();
+        let release_at = |pos: Duration, key: u7| {
+            let channel = self.keys[key].last_channel;
+            (pos, channel, release(key))
+        };
+        let mut rotated = keys_cut
+            .iter_keys()
+            .map(|key| release_at(Duration::ZERO, key))
+            .collect_vec();
+        for event in &events {
+            keys_held.update(event.message);
+            rotated.push((event.time - punch_start, event.channel, event.message));
+        }
+        if length < loop_duration {
+            rotated.extend(keys_held.iter_keys().map(|key| release_at(length, key)));
+            rotated.extend(
+                self.recording_buffer
+                    .iter()
+                    .map(|event| (position(event.time), event.channel, event.message))
+                    .filter(|&(pos, _, _)| pos >= length),
+            );
+        }
+        // This sort is stable, so simultaneous events keep their order.
+        rotated.sort_by_key(|&(pos, _, _)| pos);
+
+        // Find the keys held at the start of the loop, which is where the
+        // seam falls in this layout. Keys cut at the punch-in are released
+        // before the seam.
+        let seam = position(start_time);
+        let mut keys_held = KeySet::new();
+        let mut presses = PerKey::<(u4, u7)>::default();
+        let mut programs = self.programs_at(window_start);
+        for &(_, channel, message) in rotated[keys_cut.iter_keys().count()..]
+            .iter()
+            .take_while(|&&(pos, _, _)| pos < seam)
+        {
+            keys_held.update(message);
+            programs.update(channel, message);
+            if let KeyEffect::Press { key, vel } = KeyEffect::from(message) {
+                presses[key] = (channel, vel);
+            }
+        }
+        self.recording_start_state = keys_held
+            .iter_keys()
+            .map(|key| {
+                let (channel, vel) = presses[key];
+                (channel, key, vel)
+            })
+            .collect();
+        self.recording_start_programs = programs;
+
+        let mut buffer = rotated
+            .into_iter()
+            .map(|(pos, channel, message)| {
+                let mut time = window_start + pos;
+                if time >= end_time {
+                    time -= loop_duration;
+                }
+                TimedMidiMessage {
+                    time,
+                    channel,
+                    message,
+                }
+            })
+            .collect_vec();
+        buffer.sort_by_key(|event| event.time);
+        // Drop releases of keys that are no longer pressed.
+        buffer.retain(|event| match KeyEffect::from(event.message) {
+            KeyEffect::Release { key, .. } if !keys_held.contains(key) => false,
+            _ => {
+                keys_held.update(event.message);
+                true
+            }
+        });
+        self.recording_end_state = keys_held;
+        self.recording_buffer = buffer;
+        // Overdubs can no longer be separated from the new loop content.
+        self.overdub_layers.clear();
+
+        // Keep each playback at the same time.
+        for i in 0..self.playbacks.len() {
+            let offset = self.playbacks[i].offset;
+            let index = self
+                .recording_buffer
+                .partition_point(|event| event.time + offset <= now);
+            let keys_pressed = self.keys_held_before(index);
+            let playback = &mut self.playbacks[i];
+            playback.index = index;
+            playback.keys_pressed = keys_pressed;
+            playback.next_event_time = None;
+        }
+    }
+    pub fn start_playing(&mut self, duration: Duration) {
+        log::trace!("Start playing");
+
+        self.is_recording_in_progress = false;
+        self.update_recorder();
+
+        self.recording_end_state = self
+            .keys
+            .iter()
+            .map(|(_, status)| status.input.any())
+            .collect();
+
+        let Some(start_time) = self.recording_start_time else {
+            log::error!("cannot start playing with no start time");
+            return;
+        };
+        let end_time = start_time + duration;
+        self.recording_end_time = Some(end_time);
+
+        let cut_keys = match self.global_config.held_notes_at_loop_end {
+            HeldNotePolicy::HoldThrough => KeySet::new(),
+            HeldNotePolicy::CutAtBoundary => {
+                // Keys that were held when recording started are pressed again
+                // by the next playback, so let them sustain across the seam.
+                let start_keys: KeySet = self
+                    .recording_start_state
+                    .iter()
+                    .map(|&(_, key, _)| key)
+                    .collect();
+                self.keys_held_at(end_time)
+                    .iter_keys()
+                    .filter(|&key| !start_keys.contains(key))
+                    .collect()
+            }
+        };
+        // The releases of cut keys are injected at the end of the loop, so
+        // drop the ones that were played after it.
+        let mut releases_to_drop = cut_keys;
+        self.recording_buffer
+            .retain(|event| match KeyEffect::from(event.message) {
+                KeyEffect::Release { key, .. } if event.time >= end_time => {
+                    !releases_to_drop.remove(key)
+                }
+                _ => true,
+            });
+
+        // Wrap events recorded past the end of the loop (such as a note-off
+        // just after the boundary) back to its start, so that they play at the
+        // start of the next pass instead of overlapping it.
+        self.wrapped_event_count = 0;
+        for event in &mut self.recording_buffer {
+            if event.time >= end_time {
+                let nanos = (event.time - start_time).as_nanos() % duration.as_nanos().max(1);
+                event.time = start_time + Duration::from_nanos(nanos as u64);
+                self.wrapped_event_count += 1;
+            }
+        }
+        // This sort is stable, so wrapped events keep their order.
+        self.recording_buffer.sort_by_key(|event| event.time);
+
+        // Release cut keys just before the end of the loop, after every other
+        // event.
+        let cut_time = end_time - Duration::from_nanos(1);
+        self.recording_buffer
+            .extend(cut_keys.iter_keys().map(|key| TimedMidiMessage {
+                time: cut_time,
+                channel: self.keys[key].last_channel,
+                message: release(key),
+            }));
+
+        // Shift playbacks later by a fraction of the loop, and fill the gap
+        // before the first one with the end of the loop.
+        self.phase_shift = duration.mul_f32(self.config.phase_offset.rem_euclid(1.0));
+        if !self.phase_shift.is_zero() {
+            let resume_time = end_time - self.phase_shift;
+            let mut playback = BloopPlayback::new(self.phase_shift);
+            playback.index = self
+                .recording_buffer
+                .partition_point(|event| event.time < resume_time);
+            playback.keys_pressed = self.keys_held_at(resume_time);
+            for key in playback.keys_pressed.iter_keys() {
+                if self.is_playback_active && !self.keys_sounding.contains(key) {
+                    let KeyStatus {
+                        last_velocity: vel,
+                        last_channel: channel,
+                        ..
+                    } = self.keys[key];
+                    self.send_at(end_time, channel, MidiMessage::NoteOn { key, vel });
+                }
+            }
+            self.playbacks.push(playback);
+        }
+
+        self.playback_schedule = Some(PlaybackSchedule {
+            anchor: start_time + self.phase_shift,
+            duration,
+            next_index: 1,
+        });
+    }
+
+    /// Returns whether the bloop accepts MIDI messages from the named input
+    /// port. Messages from the computer keyboard (`None`) are always accepted.
+    pub fn accepts_input_from(&self, port: Option<&str>) -> bool {
+        match (&self.config.input_ports, port) {
+            (Some(ports), Some(port)) => ports.contains(port),
+            _ => true,
+        }
+    }
+
+    /// Returns whether a MIDI message is within the bloop's key range, and
+    /// tracks which keys were pressed within it.
+    fn accept_key(&mut self, message: MidiMessage) -> bool {
+        let (low, high) = self.config.key_range;
+        let key_range = low.min(high)..=low.max(high);
+        match KeyEffect::from(message) {
+            KeyEffect::Press { key, .. } => {
+                let is_in_range = key_range.contains(&key);
+                if is_in_range {
+                    self.keys_accepted.insert(key);
+                }
+                is_in_range
+            }
+            KeyEffect::Release { key, .. } => {
+                self.keys_accepted.remove(key) || key_range.contains(&key)
+            }
+            KeyEffect::Aftertouch { key } => self.keys_accepted.contains(key),
+            KeyEffect::ChannelAftertouch | KeyEffect::None => true,
+        }
+    }
+
+    pub fn recv_midi(&mut self, event: TimedMidiMessage) {
+        let channel = self.config.input_channel_map.map(event.channel);
+
+        if !self.accept_key(event.message) {
+            return;
+        }
+        let Some(message) = self.latch(event.channel, event.message) else {
+            return;
+        };
+        let event = TimedMidiMessage { message, ..event };
+        if let MidiMessage::PitchBend { bend } = event.message {
+            self.input_pitch_bend = bend;
+        }
+
+        for message in self.expand_chord(event.message) {
+            let event = TimedMidiMessage {
+                time: event.time,
+                channel,
+                message,
+            };
+            self.recv_expanded_midi(event);
+        }
+    }
+    /// Applies [`BloopConfig::latch`] to a MIDI message from the input on
+    /// `channel`, returning the message to handle instead, if any.
+    ///
+    /// A press latches the key, and the next press of it releases it instead.
+    /// Releases on the input are ignored.
+    fn latch(&mut self, channel: u4, message: MidiMessage) -> Option<MidiMessage> {
+        match KeyEffect::from(message) {
+            KeyEffect::Release { key, .. } if self.latch_releases_to_ignore.remove(key) => None,
+            _ if !self.config.latch => Some(message),
+            KeyEffect::Press { key, .. } => match self.latched_keys[key].take() {
+                Some(_) => {
+                    self.latch_releases_to_ignore.insert(key);
+                    Some(release(key))
+                }
+                None => {
+                    self.latched_keys[key] = Some(channel);
+                    Some(message)
+                }
+            },
+            KeyEffect::Release { key, .. } if self.latched_keys[key].is_some() => None,
+            _ => Some(message),
+        }
+    }
+    /// Releases every key latched by [`BloopConfig::latch`] at `time`.
+    fn release_latched_keys(&mut self, time: Instant) {
+        for (key, channel) in std::mem::take(&mut self.latched_keys).iter() {
+            if let Some(channel) = *channel {
+                self.recv_midi(TimedMidiMessage {
+                    time,
+                    channel,
+                    message: release(key),
+                });
+            }
+        }
+    }
+    /// Expands a key on the input into the configured chord.
+    ///
+    /// A release releases exactly the keys that the press expanded into,
+    /// except those that another held key's chord still holds. Keys outside
+    /// the MIDI range are dropped.
+    fn expand_chord(&mut self, message: MidiMessage) -> Vec<MidiMessage> {
+        let with_key = |key| match message {
+            MidiMessage::NoteOn { vel, .. } => MidiMessage::NoteOn { key, vel },
+            MidiMessage::NoteOff { vel, .. } => MidiMessage::NoteOff { key, vel },
+            MidiMessage::Aftertouch { vel, .. } => MidiMessage::Aftertouch { key, vel },
+            _ => message,
+        };
+        match KeyEffect::from(message) {
+            KeyEffect::Press { key, .. } => {
+                let Some(intervals) = &self.config.chord else {
+                    return vec![message];
+                };
+                let keys: KeySet = intervals
+                    .iter()
+                    .map(|&interval| key.as_int() as i16 + interval as i16)
+                    .filter(|k| (0..=127).contains(k))
+                    .map(|k| u7::from(k as u8))
+                    .collect();
+                self.chord_keys[key] = keys;
+                keys.iter_keys().map(with_key).collect()
+            }
+            KeyEffect::Release { key, .. } => {
+                let keys = std::mem::take(&mut self.chord_keys[key]);
+                if keys.is_empty() {
+                    return vec![message];
+                }
+                let still_held = self
+                    .chord_keys
+                    .iter()
+                    .fold(KeySet::new(), |a, (_, &b)| a | b);
+                keys.iter_keys()
+                    .filter(|&k| !still_held.contains(k))
+                    .map(with_key)
+                    .collect()
+            }
+            KeyEffect::Aftertouch { key } if !self.chord_keys[key].is_empty() => {
+                self.chord_keys[key].iter_keys().map(with_key).collect()
+            }
+            _ => vec![message],
+        }
+    }
+    /// Handles a MIDI message from the input, after expanding chords.
+    fn recv_expanded_midi(&mut self, event: TimedMidiMessage) {
+        let channel = event.channel;
+        self.input_programs.update(channel, event.message);
+        let is_recordable = self.config.record_mask.allows(event.message);
+        if self.is_record_armed && is_recordable && self.is_overdubbing(event.time) {
+            if let Some(time) = self.loop_time(event.time) {
+                self.overdub_buffer.push(TimedMidiMessage { time, ..event });
+            }
+        }
+        if self.is_record_armed
+            && is_recordable
+            && self.punch_start.is_some_and(|t| t <= event.time)
+        {
+            self.punch_buffer.push(event);
+        }
+
+        let is_passed = is_recordable || !self.config.mask_passthrough;
+        if self.passthru.filter_live_midi(channel, event.message) && is_passed {
+            match KeyEffect::from(event.message) {
+                KeyEffect::Press { key, vel } => {
+                    self.keys[key].input.set_on(channel);
+                    self.keys[key].last_velocity = vel;
+                    self.keys[key].last_channel = channel;
+                }
+                KeyEffect::Release { key, vel } => {
+                    self.keys[key].input.set_off(channel);
+                    if vel.is_some() {
+                        self.keys[key].last_release_velocity = vel;
+                    }
+                }
+                KeyEffect::Aftertouch { .. } | KeyEffect::ChannelAftertouch | KeyEffect::None => (),
+            }
+            match KeyEffect::from(event.message) {
+                // The arpeggiator plays held keys itself, and a released key
+                // drops out of it immediately.
+                KeyEffect::Release { key, .. } if self.config.arpeggiator.is_some() => {
+                    if self.arpeggiator.sounding == Some(key) && !self.keys[key].input.any() {
+                        self.release_arpeggiator_key(event.time);
+                    }
+                }
+                KeyEffect::Press { .. } if self.config.arpeggiator.is_some() => (),
+                _ => {
+                    let is_taken_over = match event.message {
+                        MidiMessage::Controller { controller, value } => {
+                            self.take_over_controller(controller, value, true)
+                        }
+                        _ => true,
+                    };
+                    if is_taken_over {
+                        let message = scale_velocity(event.message, self.config.live_level);
+                        self.send_at(event.time, channel, message);
+                    }
+                }
+            }
+        }
+
+        if self.recorder.filter_midi(channel, event.message) && is_recordable {
+            match KeyEffect::from(event.message) {
+                KeyEffect::Press { key, vel } => {
+                    self.keys[key].recording.set_on(channel);
+                    self.keys[key].last_velocity = vel;
+                    self.keys[key].last_channel = channel;
+                }
+                KeyEffect::Release { key, .. } => self.keys[key].recording.set_off(channel),
+                KeyEffect::Aftertouch { .. } | KeyEffect::ChannelAftertouch | KeyEffect::None => (),
+            }
+            self.recording_buffer.push(event);
+        }
+    }
+
+    pub fn do_events_and_return_wake_time(&mut self, now: Instant) -> Option<Instant> {
+        let loop_wake_time = self.do_loop_events(now);
+        let wake_time = match self.do_arpeggiator_events(now) {
+            Some(arpeggiator_time) => Some(option_at_most(loop_wake_time, arpeggiator_time)),
+            None => loop_wake_time,
+        };
+        let wake_time = match self.do_pitch_bend_ramp(now) {
+            Some(ramp_time) => Some(option_at_most(wake_time, ramp_time)),
+            None => wake_time,
+        };
+        match self.do_deferred_releases(now) {
+            Some(release_time) => Some(option_at_most(wake_time, release_time)),
+            None => wake_time,
+        }
+    }
+
+    /// Plays events from the recorded loop, and returns the time of the next
+    /// one.
+    fn do_loop_events(&mut self, now: Instant) -> Option<Instant> {
+        let start_time = self.recording_start_time?;
+
+        if now < start_time {
+            // We are not ready to start recording.
+            return Some(start_time);
+        }
+
+        if self.is_recording(now) && !self.is_recording_in_progress {
+            // Start recording!
+            log::trace!("Start recording");
+            // Stop playing the loop that this recording replaces.
+            self.cancel_all_playbacks(start_time);
+            self.is_recording_scheduled = false;
+            self.is_recording_in_progress = true;
+            self.update_recorder();
+            self.recording_buffer.clear();
+            self.overdub_layers.clear();
+            self.recording_start_state = self
+                .keys
+                .iter()
+                .filter(|(_, status)| status.input.any())
+                .map(|(i, status)| (status.last_channel, i, status.last_velocity))
+                .collect_vec();
+            self.recording_start_pitch_bend = self.input_pitch_bend;
+            self.recording_start_programs = self.input_programs;
+        }
+
+        let end_time = self.recording_end_time?;
+        let loop_duration = end_time - start_time;
+
+        if self.is_recording_in_progress {
+            if now <= end_time {
+                // We are not ready to stop recording. Keep recording.
+                return Some(end_time);
+            } else {
+                // Stop recording and start playing!
+                self.start_playing(loop_duration);
+            }
+        }
+
+        if self.overdub_span.is_some_and(|(_, end)| end <= now) {
+            log::trace!("Finish overdub");
+            self.finish_overdub();
+        }
+        if let Some(punch_start) = self.punch_start {
+            if punch_start + loop_duration <= now {
+                // The punch-in has replaced the whole loop.
+                self.punch_out(punch_start + loop_duration);
+            }
+        }
+
+        if let Some(retrigger_time) = self.retrigger_time.filter(|&t| t <= now) {
+            log::trace!("Retriggering");
+            self.retrigger_time = None;
+            if !self.is_playing_back() {
+                // Give the loop a schedule to restart.
+                self.playback_schedule = Some(PlaybackSchedule {
+                    anchor: retrigger_time,
+                    duration: loop_duration,
+                    next_index: 0,
+                });
+            }
+            self.reanchor(retrigger_time);
+        }
+
+        if let Some((schedule, queued_playback_time)) = self
+            .playback_schedule
+            .and_then(|schedule| Some((schedule, schedule.next_time()?)))
+        {
+            if queued_playback_time <= now {
+                log::trace!("Starting new playback");
+                self.playback_schedule = None;
+
+                // Catch up to the present, to avoid duplicate note-on events.
+                self.do_loop_events(queued_playback_time);
+
+                if self.global_config.stop_silent_loops
+                    && self.is_silent()
+                    && self.overdub_span.is_none()
+                {
+                    log::warn!("Stopping bloop #{} because its loop is silent", self.id);
+                    self.cancel_all_playbacks(queued_playback_time);
+                    return None;
+                }
+
+                // Make room for the new playback, if there is a limit.
+                let is_full = self
+                    .config
+                    .max_playbacks
+                    .is_some_and(|max| self.playbacks.len() >= max.max(1));
+                let skip_playback = is_full && self.config.playback_limit == PlaybackLimit::SkipNew;
+                if is_full && self.config.playback_limit == PlaybackLimit::DropOldest {
+                    let oldest = self.playbacks.remove(0);
+                    if self.is_playback_active {
+                        self.release_keys(queued_playback_time, oldest.keys_pressed);
+                    }
+                }
+
+                if skip_playback {
+                    log::trace!("Skipping playback because too many are in progress");
+                } else {
+                    // Return to the starting pitch bend, in case the loop
+                    // ends with a bend.
+                    let end_pitch_bend = self.pitch_bend_at(end_time);
+                    if self.is_playback_active
+                        && self.stutter.is_none()
+                        && end_pitch_bend != self.recording_start_pitch_bend
+                    {
+                        self.start_pitch_bend_ramp(queued_playback_time, end_pitch_bend);
+                    }
+                    // Return to the starting patch, in case the loop changes
+                    // it.
+                    if self.is_playback_active
+                        && self.stutter.is_none()
+                        && self.programs_at(end_time) != self.recording_start_programs
+                    {
+                        for (channel, message) in self.recording_start_programs.messages() {
+                            self.send_playback(queued_playback_time, channel, message);
+                        }
+                    }
+
+                    // Press any notes that should be pressed at the start of
+                    // playback and aren't already.
+                    let mut playback = BloopPlayback::new(queued_playback_time - start_time);
+                    for (channel, key, vel) in self.recording_start_state.clone() {
+                        playback.keys_pressed.insert(key);
+                        // A key that is still sounding from the previous pass
+                        // is held continuously rather than retriggered at the
+                        // seam.
+                        if self.is_playback_active
+                            && self.stutter.is_none()
+                            && !self.keys_sounding.contains(key)
+                        {
+                            self.send_playback(
+                                queued_playback_time,
+                                channel,
+                                MidiMessage::NoteOn { key, vel },
+                            );
+                        }
+                    }
+                    // Start the playback.
+                    self.playbacks.push(playback);
+                }
+
+                // Queue the next playback.
+                log::trace!("Queueing next playback");
+                self.playback_schedule = Some(schedule.advance());
+            }
+        }
+
+        let mut wake_time = self.playback_schedule.and_then(PlaybackSchedule::next_time);
+        if let Some(retrigger_time) = self.retrigger_time {
+            wake_time = Some(option_at_most(wake_time, retrigger_time));
+        }
+        if let Some((overdub_start, overdub_end)) = self.overdub_span {
+            let overdub_time = if now < overdub_start {
+                overdub_start
+            } else {
+                overdub_end
+            };
+            wake_time = Some(option_at_most(wake_time, overdub_time));
+        }
+        if let Some(punch_start) = self.punch_start {
+            wake_time = Some(option_at_most(wake_time, punch_start + loop_duration));
+        }
+        let mut queued_events = vec![];
+
+        self.playbacks.retain_mut(|playback| {
+            while let Some(event) = self.recording_buffer.get(playback.index) {
+                let event_time = *playback.next_event_time.get_or_insert_with(|| {
+                    let mut time = event.time + playback.offset;
+                    if let Some((origin, beat)) = self.beat_grid {
+                        let groove = self.config.groove.unwrap_or(self.global_config.groove);
+                        time = groove.apply(time, origin, beat);
+                    }
+                    // Events are played in order, so jittering the time of one
+                    // event can never move it before an earlier one.
+                    let ms = self.rng.jitter(self.config.timing_humanization_ms);
+                    let jitter = Duration::from_millis(ms.unsigned_abs());
+                    match ms < 0 {
+                        true => time.checked_sub(jitter).unwrap_or(time),
+                        false => time + jitter,
+                    }
+                });
+                if event_time > now {
+                    // Wake at the next event.
+                    wake_time = Some(option_at_most(wake_time, event_time));
+                    // Keep this playback.
+                    return true;
+                }
+
+                // Simulate this event. Only releases play during a punch-in,
+                // since everything else is being replaced.
+                let is_replaced = self.punch_start.is_some()
+                    && !matches!(KeyEffect::from(event.message), KeyEffect::Release { .. });
+                if !is_replaced {
+                    playback.keys_pressed.update(event.message);
+                }
+                match event.message.into() {
+                    KeyEffect::Press { key, vel } => {
+                        self.keys[key].last_velocity = vel;
+                        self.keys[key].last_channel = event.channel;
+                    }
+                    KeyEffect::Release {
+                        key,
+                        vel: Some(vel),
+                    } => self.keys[key].last_release_velocity = Some(vel),
+                    _ => (),
+                }
+                // Send this event, unless it is aftertouch for keys that this
+                // playback is not sounding or playback is muted by a stutter.
+                let is_sounding = self.stutter.is_none()
+                    && !is_replaced
+                    && match KeyEffect::from(event.message) {
+                        KeyEffect::Aftertouch { key } => playback.keys_pressed.contains(key),
+                        KeyEffect::ChannelAftertouch => !playback.keys_pressed.is_empty(),
+                        _ => true,
+                    };
+                if self.is_playback_active && is_sounding {
+                    // Position of the event in the loop, which is the same on
+                    // every pass.
+                    let phase =
+                        (event.time - start_time).as_secs_f32() / loop_duration.as_secs_f32();
+                    let mut event = TimedMidiMessage {
+                        time: event_time,
+                        ..*event
+                    };
+                    if let MidiMessage::NoteOn { vel, .. } = &mut event.message {
+                        if vel.as_int() > 0 && !self.gain_curve.is_empty() {
+                            let gain = gain_at(&self.gain_curve, phase);
+                            let scaled = (vel.as_int() as f32 * gain).round();
+                            *vel = (scaled.clamp(1.0, 127.0) as u8).into();
+                        }
+                        let amount = self.config.velocity_humanization as u32;
+                        if vel.as_int() > 0 && amount > 0 {
+                            let jittered = vel.as_int() as i64 + self.rng.jitter(amount);
+                            *vel = (jittered.clamp(1, 127) as u8).into();
+                        }
+                    }
+                    queued_events.push(event);
+                }
+
+                // Play the next event.
+                playback.index += 1;
+                playback.next_event_time = None;
+            }
+            false // End this playback.
+        });
+
+        queued_events.sort_by_key(|event| event.time);
+        for event in queued_events {
+            self.send_playback(event.time, event.channel, event.message);
+        }
+
+        if let Some(stutter_time) = self.do_stutter_events(now) {
+            wake_time = Some(option_at_most(wake_time, stutter_time));
+        }
+
+        wake_time
+    }
+
+    /// Starts repeating a slice of the loop of length `length`, starting from
+    /// the current position. Normal playback continues silently underneath.
+    pub fn start_stutter(&mut self, now: Instant, length: Duration) {
+        if self.stutter.is_some()
+            || self.playbacks.is_empty()
+            || self.punch_start.is_some()
+            || length.is_zero()
+        {
+            return;
+        }
+        let Some(slice_start) = self.loop_time(now) else {
+            return;
+        };
+        if self.is_playback_active {
+            self.release_keys(now, self.playback_keys_pressed());
+        }
+        let start_index = self
+            .recording_buffer
+            .partition_point(|event| event.time < slice_start);
+        self.stutter = Some(Stutter {
+            slice_start,
+            length,
+            window_start: now,
+            start_keys: self.keys_held_at(slice_start),
+            start_index,
+            index: start_index,
+            keys_pressed: KeySet::new(),
+        });
+        self.press_stutter_start_keys(now);
+    }
+    /// Stops repeating a slice of the loop, returning to normal playback.
+    pub fn stop_stutter(&mut self, now: Instant) {
+        let Some(stutter) = self.stutter.take() else {
+            return;
+        };
+        if self.is_playback_active {
+            self.release_keys(now, stutter.keys_pressed);
+            self.press_playback_keys(now);
+        }
+    }
+    /// Presses keys that are held at the start of the stutter slice.
+    fn press_stutter_start_keys(&mut self, time: Instant) {
+        let Some(stutter) = &mut self.stutter else {
+            return;
+        };
+        stutter.keys_pressed = stutter.start_keys;
+        if self.is_playback_active {
+            for key in stutter.start_keys.iter_keys() {
+                let KeyStatus {
+                    last_velocity: vel,
+                    last_channel: channel,
+                    ..
+                } = self.keys[key];
+                self.send_at(time, channel, MidiMessage::NoteOn { key, vel });
+            }
+        }
+    }
+    /// Plays events from the stutter slice, restarting it each time it ends,
+    /// and returns the time of the next event.
+    fn do_stutter_events(&mut self, now: Instant) -> Option<Instant> {
+        loop {
+            let stutter = self.stutter.as_mut()?;
+            let window_end = stutter.window_start + stutter.length;
+            let slice_end = stutter.slice_start + stutter.length;
+            let next_event = self
+                .recording_buffer
+                .get(stutter.index)
+                .filter(|event| event.time < slice_end)
+                .map(|event| {
+                    let time = stutter.window_start + (event.time - stutter.slice_start);
+                    (time, event.channel, event.message)
+                });
+
+            match next_event {
+                Some((time, _, _)) if time > now => return Some(time),
+                Some((time, channel, message)) => {
+                    stutter.index += 1;
+                    let is_sounding = match KeyEffect::from(message) {
+                        KeyEffect::Aftertouch { key } => stutter.keys_pressed.contains(key),
+                        KeyEffect::ChannelAftertouch => !stutter.keys_pressed.is_empty(),
+                        _ => true,
+                    };
+                    stutter.keys_pressed.update(message);
+                    if self.is_playback_active && is_sounding {
+                        self.send_playback(time, channel, message);
+                    }
+                }
+                None if window_end > now => return Some(window_end),
+                None => {
+                    // Release everything from this repetition and start the
+                    // next one.
+                    let keys_to_release = std::mem::take(&mut stutter.keys_pressed);
+                    stutter.window_start = window_end;
+                    stutter.index = stutter.start_index;
+                    if self.is_playback_active {
+                        for key in keys_to_release.iter_keys() {
+                            let channel = self.keys[key].last_channel;
+                            self.send_at(window_end, channel, release(key));
+                        }
+                    }
+                    self.press_stutter_start_keys(window_end);
+                }
+            }
+        }
+    }
+
+    /// Returns the pitch bend of a playback at `time`, measured against the
+    /// original recording.
+    fn pitch_bend_at(&self, time: Instant) -> PitchBend {
+        self.recording_buffer
+            .iter()
+            .take_while(|event| event.time < time)
+            .filter_map(|event| match event.message {
+                MidiMessage::PitchBend { bend } => Some(bend),
+                _ => None,
+            })
+            .last()
+            .unwrap_or(self.recording_start_pitch_bend)
+    }
+    /// Returns the bank and program selected on each channel by the recorded
+    /// loop at `time`, measured against the original recording.
+    fn programs_at(&self, time: Instant) -> ProgramState {
+        let mut programs = self.recording_start_programs;
+        for event in self
+            .recording_buffer
+            .iter()
+            .take_while(|event| event.time < time)
+        {
+            programs.update(event.channel, event.message);
+        }
+        programs
+    }
+    /// Schedules a quick ramp from `from` to the pitch bend at the start of the
+    /// recording, starting at `time`.
+    fn start_pitch_bend_ramp(&mut self, time: Instant, from: PitchBend) {
+        let from = from.as_f32();
+        let to = self.recording_start_pitch_bend.as_f32();
+        // Ramp on the channel that the loop bends on.
+        let channel = self
+            .recording_buffer
+            .iter()
+            .rfind(|event| matches!(event.message, MidiMessage::PitchBend { .. }))
+            .map_or(self.config.output_channel, |event| event.channel);
+        self.pitch_bend_ramp = (1..=PITCH_BEND_RAMP_STEPS)
+            .map(|i| {
+                let t = i as f32 / PITCH_BEND_RAMP_STEPS as f32;
+                // End exactly on the starting value.
+                let bend = match i {
+                    PITCH_BEND_RAMP_STEPS => self.recording_start_pitch_bend,
+                    _ => PitchBend::from_f32(from + (to - from) * t),
+                };
+                TimedMidiMessage {
+                    time: time + PITCH_BEND_RAMP_INTERVAL * (i - 1),
+                    channel,
+                    message: MidiMessage::PitchBend { bend },
+                }
+            })
+            .collect();
+    }
+    /// Sends pitch bend ramp messages that are due, and returns the time of the
+    /// next one.
+    fn do_pitch_bend_ramp(&mut self, now: Instant) -> Option<Instant> {
+        while let Some(event) = self.pitch_bend_ramp.front().copied() {
+            if event.time > now {
+                return Some(event.time);
+            }
+            self.pitch_bend_ramp.pop_front();
+            self.send_at(event.time, event.channel, event.message);
+        }
+        None
+    }
+
+    /// Plays arpeggiator steps that are due, and returns the time of the next
+    /// step.
+    fn do_arpeggiator_events(&mut self, now: Instant) -> Option<Instant> {
+        let arpeggiator = self.config.arpeggiator?;
+        let (grid_origin, beat) = match self.beat_grid {
+            Some((origin, beat)) => (Some(origin), beat),
+            None => (None, self.global_config.beat_duration()),
+        };
+        let step = beat / arpeggiator.steps_per_beat.max(1);
+        if step.is_zero() {
+            return None;
+        }
+        let held = self
+            .keys
+            .iter()
+            .filter(|(_, status)| status.input.any())
+            .map(|(key, _)| key)
+            .collect_vec();
+
+        let Some(step_time) = self.arpeggiator.next_step else {
+            if held.is_empty() {
+                return None;
+            }
+            // Start on the next step of the beat grid, or immediately if there
+            // is no beat grid.
+            let next_step = next_grid_time(now, grid_origin.unwrap_or(now), step);
+            self.arpeggiator.next_step = Some(next_step);
+            return Some(next_step);
+        };
+        if step_time > now {
+            return Some(step_time);
+        }
+
+        self.release_arpeggiator_key(step_time);
+        if held.is_empty() {
+            self.arpeggiator = ArpeggiatorState::default();
+            return None;
+        }
+        let key = self.next_arpeggiator_key(arpeggiator.pattern, &held);
+        self.arpeggiator.last_key = Some(key);
+        // Don't take over a key that a playback is already sounding.
+        if !self.keys_sounding.contains(key) {
+            let KeyStatus {
+                last_velocity: vel,
+                last_channel: channel,
+                ..
+            } = self.keys[key];
+            let channel = self.output_channel(channel);
+            self.keys_sounding.insert(key);
+            self.sounding_channels[key] = channel;
+            self.arpeggiator.sounding = Some(key);
+            let message = scale_velocity(MidiMessage::NoteOn { key, vel }, self.config.live_level);
+            self.send_output(step_time, channel, message);
+        }
+
+        let origin = grid_origin.unwrap_or(step_time);
+        let next_step = next_grid_time(step_time + step / 2, origin, step);
+        self.arpeggiator.next_step = Some(next_step);
+        Some(next_step)
+    }
+    /// Returns the next key for the arpeggiator to play, given the keys held
+    /// in ascending order.
+    fn next_arpeggiator_key(&mut self, pattern: ArpPattern, held: &[u7]) -> u7 {
+        let (lowest, highest) = (held[0], held[held.len() - 1]);
+        let above = |last: Option<u7>| {
+            held.iter()
+                .copied()
+                .find(|&key| last.is_none_or(|last| key > last))
+        };
+        let below = |last: Option<u7>| {
+            held.iter()
+                .rev()
+                .copied()
+                .find(|&key| last.is_none_or(|last| key < last))
+        };
+        let last = self.arpeggiator.last_key;
+        match pattern {
+            ArpPattern::Up => above(last).unwrap_or(lowest),
+            ArpPattern::Down => below(last).unwrap_or(highest),
+            ArpPattern::UpDown => {
+                let next = match self.arpeggiator.is_descending {
+                    true => below(last),
+                    false => above(last),
+                };
+                next.unwrap_or_else(|| {
+                    // Turn around at either end, without repeating the key at
+                    // the end.
+                    self.arpeggiator.is_descending = !self.arpeggiator.is_descending;
+                    match self.arpeggiator.is_descending {
+                        true => below(last).unwrap_or(highest),
+                        false => above(last).unwrap_or(lowest),
+                    }
+                })
+            }
+            ArpPattern::Random => held[(self.rng.next_u64() % held.len() as u64) as usize],
+        }
+    }
+    /// Releases the key that the arpeggiator is sounding, if any.
+    fn release_arpeggiator_key(&mut self, time: Instant) {
+        if let Some(key) = self.arpeggiator.sounding.take() {
+            if self.keys_sounding.remove(key) {
+                let message = self.release_message(key, None);
+                self.send_output(time, self.sounding_channels[key], message);
+            }
+        }
+    }
+    /// Stops the arpeggiator, releasing the key it is sounding. It starts
+    /// again on the next step after a key is held.
+    fn stop_arpeggiator(&mut self, time: Instant) {
+        self.release_arpeggiator_key(time);
+        self.arpeggiator = ArpeggiatorState::default();
+    }
+
+    /// Returns the start and end time of the recorded loop, if there is one.
+    pub fn loop_span(&self, now: Instant) -> Option<(Instant, Instant)> {
+        let start_time = self.recording_start_time.filter(|&t| t <= now)?;
+        let end_time = self.recording_end_time?;
+        (!self.is_recording_in_progress).then_some((start_time, end_time))
+    }
+    /// Returns the keys held by a playback at `time`, measured against the
+    /// original recording.
+    fn keys_held_at(&self, time: Instant) -> KeySet {
+        self.keys_held_before(
+            self.recording_buffer
+                .partition_point(|event| event.time < time),
+        )
+    }
+    /// Returns the keys held by a playback at `time`, measured against the
+    /// original recording, as `(channel, key, velocity)` for the press that
+    /// holds each one.
+    fn presses_held_at(&self, time: Instant) -> Vec<(u4, u7, u7)> {
+        let mut presses = PerKey::<Option<(u4, u7)>>::default();
+        for &(channel, key, vel) in &self.recording_start_state {
+            presses[key] = Some((channel, vel));
+        }
+        for event in self
+            .recording_buffer
+            .iter()
+            .take_while(|event| event.time < time)
+        {
+            match KeyEffect::from(event.message) {
+                KeyEffect::Press { key, vel } => presses[key] = Some((event.channel, vel)),
+                KeyEffect::Release { key, .. } => presses[key] = None,
+                _ => (),
+            }
+        }
+        presses
+            .iter()
+            .filter_map(|(key, &press)| press.map(|(channel, vel)| (channel, key, vel)))
+            .collect()
+    }
+    /// Returns the keys held by a playback just before it plays the event at
+    /// `index` in the recording buffer.
+    fn keys_held_before(&self, index: usize) -> KeySet {
+        let mut keys: KeySet = self
+            .recording_start_state
+            .iter()
+            .map(|&(_, key, _)| key)
+            .collect();
+        for event in &self.recording_buffer[..index.min(self.recording_buffer.len())] {
+            keys.update(event.message);
+        }
+        keys
+    }
+    /// Returns statistics about the content of the loop.
+    fn stats(&self) -> LoopStats {
+        let mut distinct_keys = KeySet::new();
+        // Keys held at the end of the loop are still held when it repeats, so
+        // their releases near the start are matched.
+        let mut keys =
+            self.keys_held_before(0) | self.keys_held_before(self.recording_buffer.len());
+        let mut unmatched_release_count = 0;
+        for event in &self.recording_buffer {
+            match KeyEffect::from(event.message) {
+                KeyEffect::Press { key, .. } => {
+                    distinct_keys.insert(key);
+                    keys.insert(key);
+                }
+                KeyEffect::Release { key, .. } if !keys.remove(key) => {
+                    unmatched_release_count += 1;
+                }
+                _ => (),
+            }
+        }
+        let loop_duration = self
+            .recording_start_time
+            .zip(self.recording_end_time)
+            .map(|(start, end)| end.saturating_duration_since(start));
+        let events_per_beat = self
+            .beat_grid
+            .zip(loop_duration)
+            .map(|((_, beat), loop_duration)| loop_duration.as_secs_f32() / beat.as_secs_f32())
+            .filter(|&beats| beats > 0.0)
+            .map(|beats| self.recording_buffer.len() as f32 / beats);
+        LoopStats {
+            event_count: self.recording_buffer.len(),
+            distinct_key_count: distinct_keys.iter_keys().count(),
+            events_per_beat,
+            unmatched_release_count,
+        }
+    }
+    /// Doubles the length of the loop, repeating its content to fill the second
+    /// half.
+    pub fn double_length(&mut self, now: Instant) {
+        let Some((_, end_time)) = self.loop_span(now) else {
+            return;
+        };
+        let loop_duration = end_time - self.recording_start_time.unwrap_or(end_time);
+
+        let mut buffer = self.recording_buffer.clone();
+        // Press keys that are held at the start of the loop, as a new playback
+        // would.
+        buffer.extend(
+            self.recording_start_state
+                .iter()
+                .map(|&(channel, key, vel)| {
+                    let message = MidiMessage::NoteOn { key, vel };
+                    TimedMidiMessage {
+                        time: end_time,
+                        channel,
+                        message,
+                    }
+                }),
+        );
+        buffer.extend(self.recording_buffer.iter().map(|event| TimedMidiMessage {
+            time: event.time + loop_duration,
+            ..*event
+        }));
+        // This sort is stable, so the original events stay first.
+        buffer.sort_by_key(|event| event.time);
+
+        self.set_loop(buffer, end_time + loop_duration, now);
+    }
+    /// Halves the length of the loop, discarding its second half.
+    pub fn halve_length(&mut self, now: Instant) {
+        let Some((start_time, end_time)) = self.loop_span(now) else {
+            return;
+        };
+        let mid_time = start_time + (end_time - start_time) / 2;
+        if mid_time - start_time < MIN_LOOP_DURATION {
+            return;
+        }
+
+        let mut buffer = self
+            .recording_buffer
+            .iter()
+            .filter(|event| event.time < mid_time)
+            .copied()
+            .collect_vec();
+        // Release keys that would otherwise be left held forever, because the
+        // next playback won't press them again.
+        let start_keys: KeySet = self
+            .recording_start_state
+            .iter()
+            .map(|&(_, key, _)| key)
+            .collect();
+        let keys_to_release = self.keys_held_at(mid_time).iter_keys();
+        buffer.extend(
+            keys_to_release
+                .filter(|&key| !start_keys.contains(key))
+                .map(|key| {
+                    let message = release(key);
+                    TimedMidiMessage {
+                        time: mid_time,
+                        channel: self.keys[key].last_channel,
+                        message,
+                    }
+                }),
+        );
+
+        self.set_loop(buffer, mid_time, now);
+    }
+    /// Replaces the content and end time of the loop, keeping the most recent
+    /// playback at the same position and rescheduling the next one.
+    ///
+    /// Older playbacks, which are still finishing events past the end of the
+    /// previous loop, are stopped.
+    fn set_loop(&mut self, buffer: Vec<TimedMidiMessage>, end_time: Instant, now: Instant) {
+        let Some(start_time) = self.recording_start_time else {
+            return;
+        };
+        self.recording_buffer = buffer;
+        // Overdubs can no longer be separated from the new loop content.
+        self.overdub_layers.clear();
+        self.recording_end_time = Some(end_time);
+        self.recording_end_state = self.keys_held_at(end_time);
+        let loop_duration = end_time - start_time;
+
+        let Some(latest_offset) = self.playbacks.iter().map(|p| p.offset).max() else {
+            return;
+        };
+        let mut keys_to_release = KeySet::new();
+        self.playbacks.retain_mut(|playback| {
+            playback.index = self
+                .recording_buffer
+                .partition_point(|event| event.time + playback.offset <= now);
+            playback.next_event_time = None;
+            let keep =
+                playback.offset == latest_offset && playback.index < self.recording_buffer.len();
+            if !keep {
+                keys_to_release = keys_to_release | playback.keys_pressed;
+            }
+            keep
+        });
+        if self.is_playback_active {
+            self.release_keys(now, keys_to_release);
+        }
+
+        if self.playback_schedule.is_some() {
+            let anchor = start_time + latest_offset;
+            let elapsed = now.saturating_duration_since(anchor);
+            let loops_elapsed = elapsed.as_nanos() / loop_duration.as_nanos().max(1);
+            self.playback_schedule = Some(PlaybackSchedule {
+                anchor,
+                duration: loop_duration,
+                next_index: u64::try_from(loops_elapsed)
+                    .unwrap_or(u64::MAX)
+                    .saturating_add(1),
+            });
+        }
+    }
+
+    /// Sets the user configuration for the bloop.
+    pub fn set_config(&mut self, now: Instant, config: BloopConfig) {
+        if self
+            .global_config
+            .muted_channels
+            .contains(config.output_channel)
+        {
+            // Release on the old channel before moving to the muted one.
+            self.release_all_sounding(now);
+        }
+        let program_changed = config.program != self.config.program;
+        let was_arpeggiating = self.config.arpeggiator.is_some();
+        let was_latching = self.config.latch;
+        if config.velocity_to_volume != self.config.velocity_to_volume {
+            self.last_volume = None;
+        }
+        self.config = config;
+        if program_changed {
+            self.send_program_change(now);
+        }
+        if was_latching && !self.config.latch {
+            self.release_latched_keys(now);
+        }
+        match (was_arpeggiating, self.config.arpeggiator.is_some()) {
+            (true, false) => self.stop_arpeggiator(now),
+            (false, true) => {
+                // Keys held by the user now sound only when the arpeggiator
+                // plays them.
+                let keys_to_release = self
+                    .keys
+                    .iter()
+                    .filter(|(_, status)| status.input.any())
+                    .map(|(key, _)| key)
+                    .collect();
+                self.release_keys(now, keys_to_release);
+            }
+            _ => (),
+        }
+    }
+    /// Sets the configuration shared by all bloops, releasing every sounding
+    /// key if a channel it sounds on is now muted.
+    pub fn set_global_config(&mut self, now: Instant, global_config: GlobalConfig) {
+        self.global_config = global_config;
+        let is_any_muted = self
+            .keys_sounding
+            .iter_keys()
+            .any(|key| self.is_muted(self.sounding_channels[key]));
+        if is_any_muted {
+            self.release_all_sounding(now);
+        }
+    }
+    /// Sends the configured Program Change message, if there is one.
+    pub fn send_program_change(&mut self, now: Instant) {
+        if let Some(program) = self.config.program {
+            let channel = self.config.output_channel;
+            self.send_at(now, channel, MidiMessage::ProgramChange { program });
+        }
+    }
+
+    /// Sets the breakpoints of the gain curve, clamping each one into range.
+    pub fn set_gain_curve(&mut self, mut curve: Vec<(f32, f32)>) {
+        for (phase, gain) in &mut curve {
+            *phase = phase.clamp(0.0, 1.0);
+            *gain = gain.clamp(0.0, MAX_GAIN);
+        }
+        curve.sort_by(|a, b| a.0.total_cmp(&b.0));
+        self.gain_curve = curve;
+    }
+
+    fn ui_state(&self, now: Instant) -> BloopUiState {
+        let time_until_recording = self
+            .recording_start_time
+            .into_iter()
+            .chain(self.overdub_span.map(|(start, _)| start))
+            .filter(|&start_time| start_time > now)
+            .min()
+            .map(|start_time| start_time - now);
+        let is_active = self.is_recording_in_progress
+            || self.recording_start_time.is_some()
+            || self.is_playing_back();
+        let phase = if self.is_paused && is_active {
+            BloopPhase::Paused
+        } else if time_until_recording.is_some() {
+            BloopPhase::WaitingToRecord
+        } else if self.is_overdubbing(now) {
+            BloopPhase::Overdubbing
+        } else if self.punch_start.is_some() {
+            BloopPhase::PunchingIn
+        } else if self.is_recording(now) {
+            BloopPhase::Recording
+        } else if self.is_playing_back() {
+            BloopPhase::Playing
+        } else if self.has_recording() {
+            BloopPhase::Stopped
+        } else {
+            BloopPhase::Idle
+        };
+        BloopUiState {
+            config: self.config.clone(),
+            is_listening: self.passthru.is_listening,
+            is_record_armed: self.is_record_armed,
+            phase,
+            time_until_recording,
+            has_recording: self.has_recording(),
+            loop_span: self
+                .loop_span(now)
+                .filter(|_| self.is_playing_back())
+                .map(|(start, end)| (start + self.phase_shift, end - start)),
+            wrapped_event_count: self.wrapped_event_count,
+            is_playing_back: self.is_playing_back(),
+            is_playback_active: self.is_playback_active,
+            is_bypassed: self.is_bypassed,
+            playback_count: self.playbacks.len(),
+            overdub_layer_count: self.overdub_layers.len(),
+            punch_start: self.punch_start,
+            is_frozen: !self.frozen_keys.is_empty(),
+            stats: self.stats(),
+            gain_curve: self.gain_curve.clone(),
+        }
+    }
+}
+
+/// User configuration for a single bloop.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloopConfig {
+    /// MIDI channel on which to send output.
+    pub output_channel: u4,
+    /// Mapping applied to the channel of each incoming MIDI message before it
+    /// is tracked, passed through, or recorded.
+    pub input_channel_map: InputChannelMap,
+    /// Program Change to send on the output channel whenever a MIDI output
+    /// connection opens.
+    pub program: Option<u7>,
+    /// Maximum number of playbacks of the loop that may be in progress at
+    /// once, or `None` for no limit.
+    pub max_playbacks: Option<usize>,
+    /// What to do when a new playback would exceed `max_playbacks`.
+    pub playback_limit: PlaybackLimit,
+    /// Names of the MIDI input ports to accept messages from, or `None` to
+    /// accept messages from all of them.
+    pub input_ports: Option<BTreeSet<String>>,
+    /// Lowest and highest keys, inclusive, that the bloop passes through and
+    /// records.
+    pub key_range: (u7, u7),
+    /// Swing applied to playback, overriding the global groove.
+    pub groove: Option<Groove>,
+    /// Maximum random change to the velocity of each note played back.
+    pub velocity_humanization: u8,
+    /// Maximum random change to the time of each event played back, in
+    /// milliseconds.
+    pub timing_humanization_ms: u32,
+    /// Number of measures to record, or `None` to record for the length of
+    /// the master loop (or until stopped, if there is no tempo yet).
+    ///
+    /// If there is no tempo yet, recording a fixed length starts the tempo
+    /// at the reference tempo.
+    pub length_measures: Option<u32>,
+    /// Whether to keep recording until stopped even when there is a tempo,
+    /// instead of stopping at the end of the master loop. When stopped, the
+    /// length is rounded to the nearest whole number of master loops. This
+    /// overrides `length_measures`.
+    pub free_length: bool,
+    /// Arpeggiator that plays the keys held on the input one at a time instead
+    /// of passing them through, or `None` to pass them through.
+    pub arpeggiator: Option<Arpeggiator>,
+    /// Fraction of the loop, from 0.0 to 1.0, by which playback is delayed
+    /// relative to the master loop. At 0.5, the middle of the loop plays on
+    /// the master downbeat.
+    ///
+    /// This takes effect the next time the loop starts playing.
+    pub phase_offset: f32,
+    /// Whether to send the velocity of each note played back as a volume
+    /// change (CC 7) and play the note at full velocity, for synths that
+    /// ignore velocity.
+    pub velocity_to_volume: bool,
+    /// Intervals in semitones, including 0 for the key itself, of the chord
+    /// that each key pressed on the input plays and records, or `None` to
+    /// play single keys.
+    pub chord: Option<Vec<i8>>,
+    /// Whether to send output to the bloop's own virtual MIDI output instead
+    /// of the shared outputs, so that each bloop can be recorded separately.
+    /// Only the application's MIDI output handling acts on this.
+    pub track_output: bool,
+    /// Whether to send each message on the channel it arrived on, after
+    /// `input_channel_map`, instead of on `output_channel`, so that a
+    /// multi-channel performance plays back faithfully.
+    ///
+    /// Keys are still tracked by number, so the same key held on two channels
+    /// at once only sounds on one of them.
+    pub keep_input_channels: bool,
+    /// Grid to which retriggering the loop is quantized.
+    pub launch_quantize: LaunchQuantize,
+    /// Scale, from 0.0 to 1.0, applied to the velocity of each note passed
+    /// through from live input. Recordings keep the original velocity.
+    pub live_level: f32,
+    /// Scale, from 0.0 to 1.0, applied to the velocity of each note played
+    /// back from the loop.
+    pub loop_level: f32,
+    /// Kinds of MIDI message that the bloop records.
+    pub record_mask: RecordMask,
+    /// Whether `record_mask` also filters live input passed through.
+    pub mask_passthrough: bool,
+    /// Whether keys pressed on the input keep sounding after they are
+    /// released, until they are pressed again, for recording drones.
+    pub latch: bool,
+}
 
-        if let Some(queued_playback_time) = self.next_queued_playback_time {
-            if queued_playback_time <= now {
-                log::trace!("Starting new playback");
-                self.next_queued_playback_time = None;
+/// Kinds of MIDI message that a bloop records.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RecordMask {
+    /// Note-on and note-off.
+    pub notes: bool,
+    /// Control changes, other than bank select.
+    pub controllers: bool,
+    pub pitch_bend: bool,
+    /// Polyphonic and channel aftertouch.
+    pub aftertouch: bool,
+    /// Program changes and bank select.
+    pub program: bool,
+}
+impl Default for RecordMask {
+    fn default() -> Self {
+        Self {
+            notes: true,
+            controllers: true,
+            pitch_bend: true,
+            aftertouch: true,
+            program: true,
+        }
+    }
+}
+impl RecordMask {
+    /// Returns whether the mask allows a MIDI message.
+    pub fn allows(self, message: MidiMessage) -> bool {
+        match message {
+            MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. } => self.notes,
+            MidiMessage::Controller { controller, .. } => match controller.as_int() {
+                BANK_SELECT_MSB_CONTROLLER | BANK_SELECT_LSB_CONTROLLER => self.program,
+                _ => self.controllers,
+            },
+            MidiMessage::PitchBend { .. } => self.pitch_bend,
+            MidiMessage::Aftertouch { .. } | MidiMessage::ChannelAftertouch { .. } => {
+                self.aftertouch
+            }
+            MidiMessage::ProgramChange { .. } => self.program,
+        }
+    }
+}
 
-                // Catch up to the present, to avoid duplicate note-on events.
-                self.do_events_and_return_wake_time(queued_playback_time);
+/// Pattern and rate of an arpeggiator.
+///
+/// Steps fall on the beat grid of the master loop, or on a grid at the
+/// reference tempo if there is no master loop yet. Each step releases the
+/// previous key and presses the next one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Arpeggiator {
+    /// Order in which to play held keys.
+    pub pattern: ArpPattern,
+    /// Number of steps in each beat.
+    pub steps_per_beat: u32,
+}
+impl Default for Arpeggiator {
+    fn default() -> Self {
+        Self {
+            pattern: ArpPattern::default(),
+            steps_per_beat: 2,
+        }
+    }
+}
 
-                // Press any notes that should be pressed at the start of
-                // playback and aren't already.
-                let mut playback = BloopPlayback::new(queued_playback_time - start_time);
-                for &(key, vel) in &self.recording_start_state {
-                    playback.keys_pressed.insert(key);
-                    if self.is_playback_active {
-                        self.send(MidiMessage::NoteOn { key, vel });
-                    }
-                }
-                // Start the playback.
-                self.playbacks.push(playback);
+/// Order in which an arpeggiator plays held keys.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ArpPattern {
+    /// Lowest to highest.
+    #[default]
+    Up,
+    /// Highest to lowest.
+    Down,
+    /// Lowest to highest and back down.
+    UpDown,
+    /// Random held keys.
+    Random,
+}
 
-                // Queue the next playback.
-                log::trace!("Queueing next playback");
-                self.next_queued_playback_time = Some(queued_playback_time + loop_duration);
+/// Policy for starting a playback when a bloop already has the maximum number
+/// of playbacks in progress.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PlaybackLimit {
+    /// Stop the oldest playback, releasing its keys.
+    #[default]
+    DropOldest,
+    /// Don't start the new playback.
+    SkipNew,
+}
+
+/// When a retriggered loop restarts.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum LaunchQuantize {
+    /// Restart immediately.
+    Free,
+    /// Restart on the next beat of the master loop.
+    Beat,
+    /// Restart on the next measure of the master loop.
+    Bar,
+    /// Restart at the start of the next master loop.
+    #[default]
+    Loop,
+}
+
+/// Grid to which [`Bloop::align()`] snaps a loop.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum AlignGrid {
+    /// Snap to the nearest beat of the master loop.
+    #[default]
+    Beat,
+    /// Snap to the nearest measure of the master loop.
+    Bar,
+}
+
+/// Mapping from the MIDI channel on which a message arrived to the channel a
+/// bloop treats it as having arrived on.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum InputChannelMap {
+    /// Leave channels unchanged.
+    #[default]
+    Identity,
+    /// Treat all input as if it arrived on a single channel.
+    MergeTo(u4),
+    /// Translate each channel according to a table.
+    Table([u4; 16]),
+}
+impl InputChannelMap {
+    /// Returns a table equivalent to the mapping.
+    pub fn to_table(self) -> [u4; 16] {
+        std::array::from_fn(|i| self.map(u4::from(i as u8)))
+    }
+    /// Returns the channel that `channel` maps to.
+    pub fn map(self, channel: u4) -> u4 {
+        match self {
+            InputChannelMap::Identity => channel,
+            InputChannelMap::MergeTo(c) => c,
+            InputChannelMap::Table(table) => table[channel.as_int() as usize],
+        }
+    }
+}
+
+/// Form of MIDI message used to release a key.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum NoteOffStyle {
+    /// Forward note-off messages as they were received, and release keys using
+    /// note-on messages with zero velocity.
+    #[default]
+    AsReceived,
+    /// Always use note-on messages with zero velocity.
+    NoteOnZeroVelocity,
+    /// Always use note-off messages, preserving the release velocity.
+    NoteOff,
+}
+impl NoteOffStyle {
+    /// Returns a message that releases `key` in this form.
+    ///
+    /// `vel` is the release velocity, if known. `default_vel` is used if a
+    /// note-off message is required but the release velocity is unknown.
+    pub fn release_message(self, key: u7, vel: Option<u7>, default_vel: u7) -> MidiMessage {
+        match (self, vel) {
+            (NoteOffStyle::AsReceived, Some(vel)) => MidiMessage::NoteOff { key, vel },
+            (NoteOffStyle::AsReceived | NoteOffStyle::NoteOnZeroVelocity, _) => {
+                MidiMessage::NoteOn { key, vel: 0.into() }
             }
+            (NoteOffStyle::NoteOff, vel) => MidiMessage::NoteOff {
+                key,
+                vel: vel.unwrap_or(default_vel),
+            },
         }
+    }
+}
 
-        let mut wake_time = self.next_queued_playback_time;
-        let mut queued_events = vec![];
+/// Octave numbering convention for note names, which vendors disagree on.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MiddleC {
+    /// Middle C (key 60) is C3, so key 0 is C-2.
+    C3,
+    /// Middle C (key 60) is C4, so key 0 is C-1.
+    #[default]
+    C4,
+}
+impl MiddleC {
+    /// Returns the name of `key`, such as "C4" or "F#3".
+    pub fn key_name(self, key: u7) -> String {
+        const NAMES: [&str; 12] = [
+            "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+        ];
+        let key = key.as_int() as i32;
+        let octave_of_key_0 = match self {
+            MiddleC::C3 => -2,
+            MiddleC::C4 => -1,
+        };
+        let name = NAMES[key as usize % 12];
+        let octave = key / 12 + octave_of_key_0;
+        format!("{name}{octave}")
+    }
+}
 
-        self.playbacks.retain_mut(|playback| {
-            while let Some(event) = self.recording_buffer.get(playback.index) {
-                if event.time + playback.offset > now {
-                    // Wake at the next event.
-                    wake_time = Some(option_at_most(wake_time, event.time + playback.offset));
-                    // Keep this playback.
-                    return true;
-                }
+/// User configuration shared by all bloops.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalConfig {
+    /// Reference tempo, in beats per minute.
+    pub bpm: f32,
+    /// Whether to round the length of the first loop to a whole number of
+    /// beats at the reference tempo.
+    pub quantize_master_length: bool,
+    /// Form in which to send messages that release keys.
+    pub note_off_style: NoteOffStyle,
+    /// Velocity with which to release keys when the release velocity is
+    /// unknown and `note_off_style` is [`NoteOffStyle::NoteOff`].
+    pub release_velocity: u7,
+    /// Whether to send a note-on event for a key that a bloop is already
+    /// sounding.
+    ///
+    /// A bloop only releases a key once nothing (neither the user nor any
+    /// playback) is holding it, so the extra note-on will not have a
+    /// corresponding note-off. This affects passthrough of live input, keys
+    /// pressed at the start of each playback, and keys re-pressed when
+    /// playback is toggled back on. Note-off events are never affected.
+    pub allow_unmatched_note_on: bool,
+    /// What to do when recording on a bloop that already has a loop.
+    pub record_over: RecordOverPolicy,
+    /// What to do with keys that are still held when a recording ends.
+    pub held_notes_at_loop_end: HeldNotePolicy,
+    /// Number of beats in each measure.
+    pub beats_per_measure: u32,
+    /// Number of measures in the master loop.
+    pub measures_per_loop: u32,
+    /// Swing applied to playback, unless a bloop overrides it.
+    pub groove: Groove,
+    /// MIDI channel on which the monitor sends live input.
+    pub monitor_channel: u4,
+    /// MIDI messages that control the transport remotely.
+    pub remote_control: RemoteControl,
+    /// Keys on the MIDI input that control bloops instead of being played.
+    pub shortcuts: ControllerShortcuts,
+    /// Length of the slice repeated by a stutter, in beats.
+    pub stutter_beats: f32,
+    /// Whether clearing all bloops also releases every sounding key,
+    /// including keys the user is holding, and sends All Notes Off on every
+    /// channel.
+    pub clear_all_sends_all_notes_off: bool,
+    /// Minimum time, in milliseconds, that each note played back sounds
+    /// before it is released. Releases that come sooner are delayed, but never
+    /// past the start of the next pass of the loop.
+    pub min_note_length_ms: u32,
+    /// How far, as a percentage of the median, a tap interval may be from the
+    /// median interval before tap tempo ignores it.
+    pub tap_outlier_percent: u32,
+    /// What the key that controls each bloop does.
+    pub do_key_policy: DoKeyPolicy,
+    /// Output channels on which the bloops send nothing. Muting a channel
+    /// releases the notes that bloops are sounding on it.
+    pub muted_channels: ChannelSet,
+    /// Whether to send Song Position Pointer whenever the transport starts,
+    /// is re-anchored, or resumes, so that external sequencers can align to
+    /// the master loop.
+    pub send_song_position: bool,
+    /// Whether live controller changes and controller changes played back
+    /// from a loop each wait for their value to reach the other's before
+    /// taking over, instead of making the value jump.
+    pub soft_takeover: bool,
+    /// Whether to stop playing a loop that makes no sound, such as one that is
+    /// empty, instead of repeating it forever.
+    pub stop_silent_loops: bool,
+    /// Whether recording on a bloop while another records the first loop
+    /// records on both, instead of stopping the other to set the tempo.
+    ///
+    /// The first recording to be stopped then sets the tempo, and the others
+    /// keep recording until stopped, rounded to a whole number of master
+    /// loops as if [`BloopConfig::free_length`] were set.
+    pub record_first_loops_together: bool,
+    /// Whether the tempo is taken from the new tempo master whenever the
+    /// master changes because the old one was cleared or unassigned, instead
+    /// of keeping the tempo. If the new master has no loop, then the tempo is
+    /// forgotten and the next loop it records sets it again.
+    pub recompute_tempo_on_master_change: bool,
+    /// Octave numbering used to show the names of keys.
+    pub middle_c: MiddleC,
+    /// Mapping applied to the velocity of each key pressed on the input,
+    /// before it is passed through or recorded.
+    pub input_velocity: VelocityMap,
+}
 
-                // Simulate this event.
-                playback.keys_pressed.update(event.message);
-                if let KeyEffect::Press { key, vel } = event.message.into() {
-                    self.keys[key].last_velocity = vel;
-                }
-                // Send this event.
-                if self.is_playback_active {
-                    queued_events.push(event);
-                }
+/// Mapping of input velocities into a narrower or wider range, to correct a
+/// controller whose soft notes bottom out or whose loud notes spike.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct VelocityMap {
+    /// Velocity of the softest press, from 1 to 127.
+    pub min: u7,
+    /// Velocity of the hardest press, from 1 to 127.
+    pub max: u7,
+    /// Curve applied before mapping into `min..=max`.
+    pub curve: VelocityCurve,
+}
+impl Default for VelocityMap {
+    fn default() -> Self {
+        Self {
+            min: 1.into(),
+            max: 127.into(),
+            curve: VelocityCurve::default(),
+        }
+    }
+}
+impl VelocityMap {
+    /// Returns a MIDI message with the velocity of a note-on mapped. Other
+    /// messages, including note-ons with velocity 0 (which are releases), are
+    /// returned unchanged.
+    pub fn apply(self, message: MidiMessage) -> MidiMessage {
+        let MidiMessage::NoteOn { key, vel } = message else {
+            return message;
+        };
+        if vel.as_int() == 0 {
+            return message;
+        }
+        let min = self.min.as_int().max(1) as f32;
+        let max = self.max.as_int().max(1) as f32;
+        let x = (vel.as_int() - 1) as f32 / 126.0;
+        let y = match self.curve {
+            VelocityCurve::Linear => x,
+            VelocityCurve::Soft => x.sqrt(),
+            VelocityCurve::Hard => x * x,
+        };
+        let mapped = (min + (max - min) * y).round();
+        let vel = (mapped.clamp(1.0, 127.0) as u8).into();
+        MidiMessage::NoteOn { key, vel }
+    }
+}
 
-                // Play the next event.
-                playback.index += 1;
-            }
-            false // End this playback.
-        });
+/// Curve applied to input velocities by [`VelocityMap`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum VelocityCurve {
+    #[default]
+    Linear,
+    /// Louder soft notes, for a controller that needs a heavy touch.
+    Soft,
+    /// Softer quiet notes, for a controller that is too sensitive.
+    Hard,
+}
 
-        queued_events.sort_by_key(|event| event.time);
-        for event in queued_events {
-            self.send(event.message);
+/// Swing applied to playback timing.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Groove {
+    /// Fraction of each pair of notes taken by the first one, from 0.5
+    /// (straight) to 0.75 (hard swing).
+    pub swing: f32,
+    /// Note value that is swung.
+    pub subdivision: SwingSubdivision,
+}
+impl Default for Groove {
+    fn default() -> Self {
+        Self {
+            swing: 0.5,
+            subdivision: SwingSubdivision::default(),
+        }
+    }
+}
+impl Groove {
+    /// Returns `time` moved according to the groove, on a grid of beats
+    /// starting at `origin` that each last `beat`.
+    ///
+    /// This never changes the order of two times.
+    pub fn apply(&self, time: Instant, origin: Instant, beat: Duration) -> Instant {
+        let pair = match self.subdivision {
+            SwingSubdivision::Eighth => beat,
+            SwingSubdivision::Sixteenth => beat / 2,
+        };
+        let swing = self.swing.clamp(0.5, 0.75) as f64;
+        if pair.is_zero() || swing == 0.5 || time < origin {
+            return time;
         }
+        let phase_nanos = (time - origin).as_nanos() % pair.as_nanos();
+        let pair_start = time - Duration::from_nanos(phase_nanos as u64);
+        let phase = phase_nanos as f64 / pair.as_nanos() as f64;
+        let new_phase = if phase < 0.5 {
+            phase * 2.0 * swing
+        } else {
+            swing + (phase - 0.5) * 2.0 * (1.0 - swing)
+        };
+        pair_start + pair.mul_f64(new_phase)
+    }
+}
 
-        wake_time
+/// Note value that is swung.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SwingSubdivision {
+    /// Swing pairs of eighth notes.
+    #[default]
+    Eighth,
+    /// Swing pairs of sixteenth notes.
+    Sixteenth,
+}
+
+/// Keys on the MIDI input that control bloops instead of being played, each
+/// identified by its channel and key number.
+///
+/// Only the press is intercepted; the release is handled like any other.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControllerShortcuts {
+    /// Key that clears all bloops.
+    pub clear_all: (u4, u7),
+    /// Key that records, plays, or toggles playback of each bloop, by index.
+    pub do_key: Vec<(u4, u7)>,
+    /// Key that toggles listening on each bloop, by index.
+    pub toggle_listening: Vec<(u4, u7)>,
+}
+impl Default for ControllerShortcuts {
+    fn default() -> Self {
+        Self {
+            clear_all: (4.into(), 76.into()),
+            do_key: vec![
+                (5.into(), 77.into()),
+                (5.into(), 79.into()),
+                (5.into(), 81.into()),
+            ],
+            toggle_listening: vec![
+                (4.into(), 78.into()),
+                (4.into(), 80.into()),
+                (4.into(), 82.into()),
+            ],
+        }
+    }
+}
+impl ControllerShortcuts {
+    /// Returns the command for a key pressed on the MIDI input, if it is a
+    /// shortcut.
+    pub fn command_for(&self, channel: u4, key: u7) -> Option<BloopCommand> {
+        let shortcut = (channel, key);
+        if self.clear_all == shortcut {
+            return Some(BloopCommand::ClearAll);
+        }
+        if let Some(i) = self.do_key.iter().position(|&s| s == shortcut) {
+            return Some(BloopCommand::DoKey(i));
+        }
+        if let Some(i) = self.toggle_listening.iter().position(|&s| s == shortcut) {
+            return Some(BloopCommand::ToggleListening(i));
+        }
+        None
     }
+}
 
-    fn ui_state(&self) -> BloopUiState {
-        BloopUiState {
-            is_listening: self.passthru.is_listening,
-            is_waiting_to_record: self
-                .recording_start_time
-                .is_some_and(|start_time| start_time > Instant::now()),
-            is_recording: self.is_recording(),
-            is_playing_back: !self.playbacks.is_empty() || self.next_queued_playback_time.is_some(),
-            is_playback_active: self.is_playback_active,
+/// Action that can be triggered remotely, such as by a foot controller.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RemoteAction {
+    PauseTransport,
+    ResumeTransport,
+    RecordNext,
+    Panic,
+}
+impl RemoteAction {
+    /// All remote actions, in the order they are displayed.
+    pub const ALL: [Self; 4] = [
+        Self::PauseTransport,
+        Self::ResumeTransport,
+        Self::RecordNext,
+        Self::Panic,
+    ];
+
+    /// Returns the command that performs the action.
+    pub fn command(self) -> BloopCommand {
+        match self {
+            Self::PauseTransport => BloopCommand::PauseTransport,
+            Self::ResumeTransport => BloopCommand::ResumeTransport,
+            Self::RecordNext => BloopCommand::RecordNext,
+            Self::Panic => BloopCommand::Panic,
+        }
+    }
+
+    /// Returns the action for a MIDI Machine Control command, given the data
+    /// bytes of a SysEx message (not including `0xF0` or `0xF7`).
+    ///
+    /// Stop and Pause pause the transport, Play and Deferred Play resume it,
+    /// Record Strobe records on the next bloop, and Reset panics. Other SysEx
+    /// messages are ignored.
+    pub fn from_mmc(data: &[u7]) -> Option<Self> {
+        // Universal real-time, any device ID, MMC command.
+        let bytes = data.iter().map(|b| b.as_int()).collect_vec();
+        let [0x7F, _device_id, 0x06, command, ..] = *bytes.as_slice() else {
+            return None;
+        };
+        match command {
+            0x01 | 0x09 => Some(Self::PauseTransport),
+            0x02 | 0x03 => Some(Self::ResumeTransport),
+            0x06 => Some(Self::RecordNext),
+            0x0D => Some(Self::Panic),
+            _ => None,
         }
     }
 }
 
-pub struct BloopConfig {
-    output_channel: u4,
+/// MIDI messages that control the transport remotely.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RemoteControl {
+    /// Whether to respond to MIDI Machine Control SysEx messages.
+    pub mmc: bool,
+    /// Control Change controller number that triggers each action, on any
+    /// channel. A controller mapped here is not passed on to the bloops.
+    pub controllers: BTreeMap<RemoteAction, u7>,
+    /// Control Change controller number of a jog wheel that scrubs the
+    /// transport, on any channel. Each tick moves the playhead by a beat.
+    pub jog_controller: Option<u7>,
+}
+impl RemoteControl {
+    /// Returns whether a MIDI message is a Control Change for a controller
+    /// that is mapped to an action.
+    pub fn is_mapped(&self, message: MidiMessage) -> bool {
+        match message {
+            MidiMessage::Controller { controller, .. } => {
+                self.controllers.values().any(|&c| c == controller)
+                    || self.jog_controller == Some(controller)
+            }
+            _ => false,
+        }
+    }
+    /// Returns the action triggered by a MIDI message, if any.
+    ///
+    /// Actions trigger on any nonzero value, so that a button that sends 127
+    /// when pressed and 0 when released triggers once.
+    pub fn action_for(&self, message: MidiMessage) -> Option<RemoteAction> {
+        let MidiMessage::Controller { controller, value } = message else {
+            return None;
+        };
+        let (&action, _) = self.controllers.iter().find(|&(_, &c)| c == controller)?;
+        (value.as_int() > 0).then_some(action)
+    }
+    /// Returns the number of beats to scrub for a MIDI message from the jog
+    /// wheel, if it is one.
+    ///
+    /// The jog wheel sends relative values: 1 to 63 ticks forward, and 127
+    /// down to 65 for 1 to 63 ticks backward.
+    pub fn jog_beats(&self, message: MidiMessage) -> Option<f32> {
+        let MidiMessage::Controller { controller, value } = message else {
+            return None;
+        };
+        if self.jog_controller != Some(controller) {
+            return None;
+        }
+        match value.as_int() {
+            0 | 64 => None,
+            v @ 1..=63 => Some(v as f32),
+            v => Some(v as f32 - 128.0),
+        }
+    }
+}
+
+/// Behavior for keys that are still held when a recording ends.
+///
+/// Either way, a release during playback only takes effect once nothing else
+/// is holding the key (see `Bloop::is_key_held()`), so a key the user is
+/// still holding, or that another playback is holding, keeps sounding.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HeldNotePolicy {
+    /// Keep the keys held into the next pass of the loop, until their releases
+    /// (which are recorded after the end of the loop and wrapped to its start)
+    /// play.
+    #[default]
+    HoldThrough,
+    /// Release the keys at the end of the loop, so that nothing sounds across
+    /// the boundary except keys that were already held when recording
+    /// started.
+    CutAtBoundary,
+}
+
+/// Behavior of the key that controls a bloop ([`BloopCommand::DoKey`]).
+///
+/// Whatever the policy, the key always stops a recording in progress.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DoKeyPolicy {
+    /// Start recording if the bloop is not playing, and otherwise toggle
+    /// playback.
+    #[default]
+    RecordAndToggle,
+    /// Toggle playback if the bloop is playing, and otherwise do nothing, so
+    /// that the key never starts a recording.
+    ToggleOnly,
+    /// Start recording if the bloop is not playing, and otherwise do nothing.
+    RecordOnly,
+}
+
+/// Behavior when recording on a bloop that already has a loop.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RecordOverPolicy {
+    /// Discard the existing loop and record a new one.
+    #[default]
+    Replace,
+    /// Record for one loop on top of the existing loop. If the loop is not
+    /// playing, this behaves like `Replace`.
+    Overdub,
+    /// Do nothing. The existing loop must be cleared first.
+    Refuse,
+}
+impl Default for GlobalConfig {
+    fn default() -> Self {
+        Self {
+            bpm: 120.0,
+            quantize_master_length: false,
+            note_off_style: NoteOffStyle::default(),
+            release_velocity: 64.into(),
+            allow_unmatched_note_on: true,
+            record_over: RecordOverPolicy::default(),
+            held_notes_at_loop_end: HeldNotePolicy::default(),
+            beats_per_measure: 4,
+            measures_per_loop: 8,
+            groove: Groove::default(),
+            monitor_channel: 0.into(),
+            clear_all_sends_all_notes_off: false,
+            remote_control: RemoteControl::default(),
+            shortcuts: ControllerShortcuts::default(),
+            stutter_beats: 0.25,
+            min_note_length_ms: 0,
+            tap_outlier_percent: 20,
+            do_key_policy: DoKeyPolicy::default(),
+            muted_channels: ChannelSet::default(),
+            send_song_position: false,
+            soft_takeover: false,
+            stop_silent_loops: true,
+            record_first_loops_together: false,
+            recompute_tempo_on_master_change: false,
+            middle_c: MiddleC::default(),
+            input_velocity: VelocityMap::default(),
+        }
+    }
+}
+impl GlobalConfig {
+    /// Returns the number of beats in the master loop.
+    pub fn beats_per_loop(&self) -> u32 {
+        (self.beats_per_measure * self.measures_per_loop).max(1)
+    }
+    /// Returns the duration of one beat at the reference tempo.
+    pub fn beat_duration(&self) -> Duration {
+        Duration::from_secs_f32(60.0 / self.bpm.max(1.0))
+    }
+    /// Returns the length of the stutter slice, given the length of the master
+    /// loop if it is known.
+    pub fn stutter_length(&self, master_duration: Option<Duration>) -> Duration {
+        let beat = match master_duration {
+            Some(duration) => duration / self.beats_per_loop(),
+            None => self.beat_duration(),
+        };
+        beat.mul_f32(self.stutter_beats)
+    }
+    /// Returns the length of the master loop, given the time between the start
+    /// and end of its recording.
+    pub fn master_loop_duration(&self, recorded: Duration) -> Duration {
+        if !self.quantize_master_length {
+            return recorded;
+        }
+        let beat = self.beat_duration();
+        let beats = (recorded.as_secs_f32() / beat.as_secs_f32())
+            .round()
+            .max(1.0);
+        beat * beats as u32
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum BloopCommand {
-    RefreshUi,
-
-    Midi(LiveEvent<'static>),
+    /// MIDI message from the named input port, or from the computer keyboard
+    /// if the port is `None`.
+    Midi(Option<Arc<str>>, LiveEvent<'static>),
 
     DoKey(usize),
     ToggleListening(usize),
+    ToggleRecordArm(usize),
     TogglePlayback(usize),
+    /// Silences a bloop entirely while its playbacks keep running in time, or
+    /// stops silencing it.
+    ToggleBypass(usize),
     CancelPlaying(usize),
+    /// Starts repeating a slice of a bloop's loop with the given length,
+    /// starting from the current position.
+    Stutter(usize, Duration),
+    /// Stops repeating a slice of a bloop's loop.
+    StopStutter(usize),
+    /// Restarts a bloop's loop from its beginning, at the next point allowed
+    /// by [`BloopConfig::launch_quantize`].
+    Retrigger(usize),
+    /// Doubles the length of a bloop's loop, repeating its content.
+    DoubleLength(usize),
+    /// Halves the length of a bloop's loop, discarding its second half.
+    HalveLength(usize),
     StartRecording(usize),
     StartPlaying(usize),
+    /// Removes the most recent overdub from a bloop's loop.
+    UndoLayer(usize),
+    /// Keeps every overdub in a bloop's loop, so that they can no longer be
+    /// undone.
+    CommitOverdub(usize),
+    /// Starts replacing a bloop's loop from the current position with live
+    /// input.
+    PunchIn(usize),
+    /// Stops replacing a bloop's loop with live input.
+    PunchOut(usize),
+    /// Stops a bloop's loop and holds the keys it is sounding.
+    Freeze(usize),
+    /// Releases the keys held by freezing a bloop.
+    Unfreeze(usize),
+    /// Starts recording on the next empty bloop after the one most recently
+    /// recorded, or on the least recently recorded bloop if none are empty.
+    RecordNext,
+    /// Toggles whether live input is forwarded straight to the output,
+    /// independent of any bloop.
+    ToggleMonitor,
+    /// Freezes all loops and recordings in place until the transport is
+    /// resumed.
+    PauseTransport,
+    /// Resumes all loops and recordings from where they were paused.
+    ResumeTransport,
+    /// Releases every sounding key and sends All Notes Off on every channel.
+    Panic,
+    /// Stops every loop, keeping the recordings and the tempo so that each
+    /// loop can be restarted later.
+    StopAll,
+    /// Makes the given time the start of the master loop, restarting every
+    /// playing loop from its beginning without discarding any recordings.
+    ReanchorTransport(Instant),
+    /// Moves the playhead of the transport and every playing loop by a number
+    /// of beats, backward if negative, without playing the events in between.
+    Scrub(f32),
+    /// Shifts a bloop's playback so that its earliest note falls on the
+    /// nearest beat or measure of the master loop.
+    Align(usize, AlignGrid),
+    /// Makes a bloop the tempo master, so that only its first recording sets
+    /// the tempo.
+    SetTempoMaster(usize),
+    /// Lets the first loop to finish recording set the tempo again.
+    ClearTempoMaster,
+    /// Stops a bloop and discards its recording.
+    Clear(usize),
     ClearAll,
+
+    /// Sets the reference tempo from the time between taps, given the time of
+    /// a tap.
+    TapTempo(Instant),
+    /// Transposes the output of every bloop by a number of semitones, from
+    /// -[`MAX_TRANSPOSE`] to [`MAX_TRANSPOSE`].
+    SetMasterTranspose(i8),
+    SetGlobalConfig(GlobalConfig),
+    SetBloopConfig(usize, BloopConfig),
+    /// Sets the breakpoints of a bloop's gain curve, as `(loop_phase, gain)`.
+    SetGainCurve(usize, Vec<(f32, f32)>),
+
+    /// A MIDI output connection has just opened.
+    OutputConnected,
+}
+impl From<(Arc<str>, LiveEvent<'_>)> for BloopCommand {
+    fn from((port, event): (Arc<str>, LiveEvent<'_>)) -> Self {
+        BloopCommand::Midi(Some(port), event.to_static())
+    }
+}
+
+/// Maximum number of MIDI events waiting to be sent to the output.
+///
+/// If the output falls this far behind, new events are dropped (except for
+/// releases) rather than letting latency and memory grow without bound.
+const OUTPUT_QUEUE_CAPACITY: usize = 4096;
+
+/// MIDI event to send to the output.
+#[derive(Debug, Clone)]
+pub struct OutputEvent {
+    /// Time at which the event was intended to be sent.
+    pub time: Instant,
+    /// Index of the bloop that sent the event, or [`MONITOR_SOURCE`] for the
+    /// monitor.
+    pub source: usize,
+    /// MIDI event.
+    pub event: LiveEvent<'static>,
+}
+
+/// Maximum number of semitones by which the output can be transposed in
+/// either direction.
+pub const MAX_TRANSPOSE: i8 = 48;
+
+/// Maximum gain on a bloop's gain curve.
+pub const MAX_GAIN: f32 = 2.0;
+
+/// Scales the velocity of a note-on by `level`, keeping it at least 1 so that
+/// it is not mistaken for a release.
+fn scale_velocity(message: MidiMessage, level: f32) -> MidiMessage {
+    match message {
+        MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 && level != 1.0 => {
+            let scaled = (vel.as_int() as f32 * level).round();
+            let vel = (scaled.clamp(1.0, 127.0) as u8).into();
+            MidiMessage::NoteOn { key, vel }
+        }
+        _ => message,
+    }
+}
+
+/// Returns the gain at `phase` on a gain curve, interpolating linearly between
+/// breakpoints. The gain is constant before the first breakpoint and after the
+/// last one, and is 1.0 everywhere if there are no breakpoints.
+pub fn gain_at(curve: &[(f32, f32)], phase: f32) -> f32 {
+    let i = curve.partition_point(|&(p, _)| p <= phase);
+    match (i.checked_sub(1).map(|j| curve[j]), curve.get(i)) {
+        (Some((p0, g0)), Some(&(p1, g1))) if p1 > p0 => g0 + (g1 - g0) * (phase - p0) / (p1 - p0),
+        (Some((_, g)), _) | (None, Some(&(_, g))) => g,
+        (None, None) => 1.0,
+    }
+}
+
+/// Value of [`OutputEvent::source`] for events sent by the monitor.
+pub const MONITOR_SOURCE: usize = usize::MAX;
+
+/// Forwards live input straight to the output on a single channel,
+/// independent of any bloop's passthrough or recording.
+struct Monitor {
+    midi_out_tx: flume::Sender<OutputEvent>,
+    /// Whether live input is being forwarded.
+    is_enabled: bool,
+    /// Channel on which each key was pressed, if it is held through the
+    /// monitor.
+    keys: PerKey<Option<u4>>,
+    /// User configuration shared by all bloops.
+    global_config: GlobalConfig,
+}
+impl Monitor {
+    fn new(midi_out_tx: flume::Sender<OutputEvent>) -> Self {
+        Self {
+            midi_out_tx,
+            is_enabled: false,
+            keys: PerKey::default(),
+            global_config: GlobalConfig::default(),
+        }
+    }
+
+    /// Toggles the monitor, releasing any keys it is holding when disabled.
+    fn toggle(&mut self, now: Instant) {
+        self.is_enabled = !self.is_enabled;
+        if !self.is_enabled {
+            self.release_all(now);
+        }
+    }
+
+    /// Releases every key the monitor is holding.
+    fn release_all(&mut self, now: Instant) {
+        for key in iter_u7() {
+            if let Some(channel) = self.keys[key].take() {
+                self.send(now, channel, release(key));
+            }
+        }
+    }
+
+    /// Forwards a live MIDI message on `channel`, if the monitor is enabled.
+    ///
+    /// Releases and aftertouch always go to the channel on which the key was
+    /// pressed, even if `channel` has since changed.
+    fn recv_midi(&mut self, event: TimedMidiMessage, channel: u4) {
+        let message = event.message;
+        match KeyEffect::from(message) {
+            KeyEffect::Press { key, .. } if self.is_enabled => {
+                self.keys[key] = Some(channel);
+                self.send(event.time, channel, message);
+            }
+            KeyEffect::Release { key, .. } => {
+                if let Some(channel) = self.keys[key].take() {
+                    self.send(event.time, channel, message);
+                }
+            }
+            KeyEffect::Aftertouch { key } => {
+                if let Some(channel) = self.keys[key] {
+                    self.send(event.time, channel, message);
+                }
+            }
+            KeyEffect::ChannelAftertouch => {
+                if self.keys.iter().any(|(_, channel)| channel.is_some()) {
+                    self.send(event.time, channel, message);
+                }
+            }
+            KeyEffect::Press { .. } => (),
+            KeyEffect::None if self.is_enabled => self.send(event.time, channel, message),
+            KeyEffect::None => (),
+        }
+    }
+
+    /// Sends a MIDI message that is intended to be sent at `time`, converting
+    /// releases to the configured form.
+    fn send(&self, time: Instant, channel: u4, message: MidiMessage) {
+        let message = match KeyEffect::from(message) {
+            KeyEffect::Release { key, vel } => self.global_config.note_off_style.release_message(
+                key,
+                vel,
+                self.global_config.release_velocity,
+            ),
+            _ => message,
+        };
+        let event = OutputEvent {
+            time,
+            source: MONITOR_SOURCE,
+            event: LiveEvent::Midi { channel, message },
+        };
+        try_send_output(&self.midi_out_tx, event);
+    }
+}
+
+/// Tempo estimate from taps, which ignores intervals that are far from the
+/// median so that a single mistimed tap doesn't skew it.
+#[derive(Debug, Default, Clone)]
+struct TapTempo {
+    /// Times of recent taps, oldest first.
+    taps: VecDeque<Instant>,
+    /// How consistent the recent intervals are, from 0.0 to 1.0.
+    stability: Option<f32>,
+}
+impl TapTempo {
+    /// Maximum number of intervals to average.
+    const WINDOW: usize = 8;
+    /// Time after the last tap at which the next tap starts over.
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// Records a tap and returns the estimated beat duration, once there are
+    /// at least two taps. Intervals that differ from the median by more than
+    /// `outlier_percent` are ignored.
+    fn tap(&mut self, time: Instant, outlier_percent: u32) -> Option<Duration> {
+        if !self.is_active(time) {
+            self.taps.clear();
+            self.stability = None;
+        }
+        self.taps.push_back(time);
+        while self.taps.len() > Self::WINDOW + 1 {
+            self.taps.pop_front();
+        }
+
+        let intervals = self
+            .taps
+            .iter()
+            .tuple_windows()
+            .map(|(a, b)| b.saturating_duration_since(*a).as_secs_f32())
+            .sorted_by(f32::total_cmp)
+            .collect_vec();
+        let median = *intervals.get(intervals.len() / 2)?;
+        let tolerance = median * outlier_percent as f32 / 100.0;
+        let kept = intervals
+            .into_iter()
+            .filter(|interval| (interval - median).abs() <= tolerance)
+            .collect_vec();
+        let mean = kept.iter().sum::<f32>() / kept.len() as f32;
+        if mean <= 0.0 {
+            return None;
+        }
+        let variance = kept.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / kept.len() as f32;
+        self.stability = Some((1.0 - variance.sqrt() / mean).clamp(0.0, 1.0));
+        Some(Duration::from_secs_f32(mean))
+    }
+    /// Returns whether a tap at `time` would continue the current sequence of
+    /// taps.
+    fn is_active(&self, time: Instant) -> bool {
+        self.taps
+            .back()
+            .is_some_and(|&last| time.saturating_duration_since(last) < Self::TIMEOUT)
+    }
+    /// Returns how consistent the recent intervals are, from 0.0 to 1.0, if
+    /// tapping is in progress.
+    fn stability(&self, now: Instant) -> Option<f32> {
+        self.stability.filter(|_| self.is_active(now))
+    }
+}
+
+/// Queues an event for output without blocking the bloops thread, logging it
+/// if it is dropped.
+fn try_send_output(midi_out_tx: &flume::Sender<OutputEvent>, event: OutputEvent) {
+    match midi_out_tx.try_send(event) {
+        Ok(()) => (),
+        Err(flume::TrySendError::Full(_)) => {
+            log::error!("Error sending MIDI event: output queue is full; dropping event");
+        }
+        Err(e) => log::error!("Error sending MIDI event: {e}"),
+    }
+}
+
+/// Silences all output: releases every key sounded by the bloops or the
+/// monitor, then sends All Notes Off on every channel to catch anything else.
+fn send_all_notes_off(
+    now: Instant,
+    bloops: &mut [Bloop],
+    monitor: &mut Monitor,
+    midi_out_tx: &flume::Sender<OutputEvent>,
+) {
+    for bloop in bloops {
+        bloop.release_all_sounding(now);
+    }
+    monitor.release_all(now);
+
+    for channel in 0..16 {
+        let message = MidiMessage::Controller {
+            controller: ALL_NOTES_OFF_CONTROLLER.into(),
+            value: 0.into(),
+        };
+        let event = OutputEvent {
+            time: now,
+            source: MONITOR_SOURCE,
+            event: LiveEvent::Midi {
+                channel: channel.into(),
+                message,
+            },
+        };
+        try_send_output(midi_out_tx, event);
+    }
 }
-impl From<LiveEvent<'_>> for BloopCommand {
-    fn from(value: LiveEvent<'_>) -> Self {
-        BloopCommand::Midi(value.to_static())
+
+/// Sends Song Position Pointer for the position of `now` in the master loop,
+/// if the tempo is known.
+fn send_song_position(
+    now: Instant,
+    epoch: Option<Instant>,
+    duration: Option<Duration>,
+    config: &GlobalConfig,
+    midi_out_tx: &flume::Sender<OutputEvent>,
+) {
+    let (Some(epoch), Some(duration)) = (epoch, duration) else {
+        return;
+    };
+    if duration.is_zero() {
+        return;
     }
+    let nanos = now.saturating_duration_since(epoch).as_nanos() % duration.as_nanos();
+    let phase = nanos as f64 / duration.as_nanos() as f64;
+    // Song position is measured in sixteenth notes.
+    let sixteenths = (phase * config.beats_per_loop() as f64 * 4.0) as u16;
+    let event = OutputEvent {
+        time: now,
+        source: MONITOR_SOURCE,
+        event: LiveEvent::Common(SystemCommon::SongPosition(u14::new(
+            sixteenths.min(u14::max_value().as_int()),
+        ))),
+    };
+    try_send_output(midi_out_tx, event);
 }
 
+/// MIDI controller number for channel volume.
+const VOLUME_CONTROLLER: u8 = 7;
+
+/// MIDI controller number for the bank select MSB.
+const BANK_SELECT_MSB_CONTROLLER: u8 = 0;
+/// MIDI controller number for the bank select LSB.
+const BANK_SELECT_LSB_CONTROLLER: u8 = 32;
+
+/// MIDI controller number for the All Notes Off channel mode message.
+const ALL_NOTES_OFF_CONTROLLER: u8 = 123;
+
+/// Interval at which the bloops thread sends its state to the UI.
+const UI_STATE_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
 pub struct UiState {
     pub epoch: Option<Instant>,
     pub duration: Option<Duration>,
+    /// Index of the bloop whose loop sets the tempo.
+    pub tempo_master: Option<usize>,
+    /// Index of the bloop explicitly made the tempo master, if any.
+    pub assigned_tempo_master: Option<usize>,
+    /// Whether live input is forwarded straight to the output.
+    pub is_monitoring: bool,
+    /// Time at which the transport was paused, if it is paused.
+    pub paused_at: Option<Instant>,
+    /// Number of keys pressed on the MIDI input so far.
+    pub key_press_count: u64,
+    /// Most recent key pressed on the MIDI input.
+    pub last_key_pressed: Option<u7>,
+    /// How consistent recent tempo taps are, from 0.0 to 1.0, if tapping is in
+    /// progress.
+    pub tap_stability: Option<f32>,
+    /// Number of semitones by which the output of every bloop is transposed.
+    pub master_transpose: i8,
+    pub config: GlobalConfig,
     pub bloops: Vec<BloopUiState>,
 }
 
+/// Statistics about the content of a bloop's loop.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct LoopStats {
+    /// Number of recorded events.
+    pub event_count: usize,
+    /// Number of distinct keys pressed.
+    pub distinct_key_count: usize,
+    /// Average number of events per beat, if the tempo and the length of the
+    /// loop are known.
+    pub events_per_beat: Option<f32>,
+    /// Number of releases of keys that are not held when they are released,
+    /// which should always be zero.
+    pub unmatched_release_count: usize,
+}
+
+/// What a bloop is doing, for display.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BloopPhase {
+    /// No loop and not recording.
+    Idle,
+    /// Scheduled to start recording or overdubbing.
+    WaitingToRecord,
+    /// Recording a new loop.
+    Recording,
+    /// Playing the loop.
+    Playing,
+    /// Recording on top of the loop while it plays.
+    Overdubbing,
+    /// Replacing part of the loop while it plays.
+    PunchingIn,
+    /// Has a loop that is not playing.
+    Stopped,
+    /// Recording or playing, but the transport is paused.
+    Paused,
+}
+impl BloopPhase {
+    /// Returns whether the bloop is recording or overdubbing.
+    pub fn is_recording(self) -> bool {
+        matches!(
+            self,
+            BloopPhase::Recording | BloopPhase::Overdubbing | BloopPhase::PunchingIn
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct BloopUiState {
+    pub config: BloopConfig,
     pub is_listening: bool,
-    pub is_waiting_to_record: bool,
-    pub is_recording: bool,
+    pub is_record_armed: bool,
+    /// What the bloop is doing.
+    pub phase: BloopPhase,
+    /// Time remaining until the scheduled start of recording, if waiting to
+    /// record.
+    pub time_until_recording: Option<Duration>,
+    /// Whether the bloop has a recorded loop, even if it is not playing.
+    pub has_recording: bool,
+    /// Start time and duration of the loop, if it is playing.
+    pub loop_span: Option<(Instant, Duration)>,
+    /// Number of events recorded past the end of the loop that were wrapped
+    /// back to its start.
+    pub wrapped_event_count: usize,
     pub is_playing_back: bool,
     pub is_playback_active: bool,
+    /// Whether the bloop sends nothing while its playbacks keep running.
+    pub is_bypassed: bool,
+    /// Number of playbacks of the loop in progress.
+    pub playback_count: usize,
+    /// Number of overdubs that can be undone.
+    pub overdub_layer_count: usize,
+    /// Time at which a punch-in started, if one is in progress.
+    pub punch_start: Option<Instant>,
+    /// Whether a freeze is holding any keys.
+    pub is_frozen: bool,
+    /// Statistics about the content of the loop.
+    pub stats: LoopStats,
+    /// Breakpoints of the gain applied to playback velocities, as
+    /// `(loop_phase, gain)` sorted by phase.
+    pub gain_curve: Vec<(f32, f32)>,
 }
 
-pub fn spawn_bloops_thread() -> Result<(
-    flume::Sender<BloopCommand>,
-    flume::Receiver<UiState>,
-    flume::Receiver<LiveEvent<'static>>,
-)> {
-    let (commands_tx, commands_rx) = flume::unbounded();
-    let (ui_state_tx, ui_state_rx) = flume::unbounded();
-    let (midi_out_tx, midi_out_rx) = flume::unbounded();
+/// State of the bloops thread: the bloops, the monitor, and the transport that
+/// they share.
+///
+/// The thread feeds it commands and the current time, so it never reads the
+/// clock to decide what to do.
+struct Engine {
+    /// Sender for commands to the engine, for commands that are carried out by
+    /// sending other commands.
+    commands_tx: flume::Sender<BloopCommand>,
+    midi_out_tx: flume::Sender<OutputEvent>,
 
-    let commands_tx_ref = commands_tx.clone();
-    std::thread::spawn(move || {
-        let commands_tx = commands_tx_ref;
-
-        let mut epoch = None;
-        let mut duration = None;
-        let mut bloops = vec![
-            Bloop::new(midi_out_tx.clone(), 0.into()),
-            Bloop::new(midi_out_tx.clone(), 1.into()),
-            Bloop::new(midi_out_tx.clone(), 2.into()),
+    epoch: Option<Instant>,
+    duration: Option<Duration>,
+    tempo_master: Option<usize>,
+    /// Bloop explicitly made the tempo master, if any. Only it may set the
+    /// tempo by finishing a recording.
+    assigned_tempo_master: Option<usize>,
+    key_press_count: u64,
+    last_key_pressed: Option<u7>,
+    config: GlobalConfig,
+    monitor: Monitor,
+    bloops: Vec<Bloop>,
+    /// Indices of bloops in the order they were last recorded, least recent
+    /// first.
+    record_order: VecDeque<usize>,
+    paused_at: Option<Instant>,
+    tap_tempo: TapTempo,
+    master_transpose: i8,
+    /// Epoch for which Song Position Pointer was last considered.
+    song_position_epoch: Option<Instant>,
+}
+impl Engine {
+    fn new(
+        commands_tx: flume::Sender<BloopCommand>,
+        midi_out_tx: flume::Sender<OutputEvent>,
+    ) -> Self {
+        let bloops = vec![
+            Bloop::new(midi_out_tx.clone(), 0),
+            Bloop::new(midi_out_tx.clone(), 1),
+            Bloop::new(midi_out_tx.clone(), 2),
         ];
+        Self {
+            commands_tx,
+            monitor: Monitor::new(midi_out_tx.clone()),
+            midi_out_tx,
 
-        loop {
-            let next_event_time = bloops
+            epoch: None,
+            duration: None,
+            tempo_master: None,
+            assigned_tempo_master: None,
+            key_press_count: 0,
+            last_key_pressed: None,
+            config: GlobalConfig::default(),
+            record_order: (0..bloops.len()).collect(),
+            bloops,
+            paused_at: None,
+            tap_tempo: TapTempo::default(),
+            master_transpose: 0,
+            song_position_epoch: None,
+        }
+    }
+
+    /// Plays events that are due at `now`, and returns the time of the next
+    /// one.
+    fn do_events(&mut self, now: Instant) -> Option<Instant> {
+        if self.epoch != self.song_position_epoch {
+            self.song_position_epoch = self.epoch;
+            if self.config.send_song_position {
+                send_song_position(
+                    now,
+                    self.epoch,
+                    self.duration,
+                    &self.config,
+                    &self.midi_out_tx,
+                );
+            }
+        }
+        let beat_grid = self
+            .epoch
+            .zip(self.duration.map(|d| d / self.config.beats_per_loop()));
+        for bloop in &mut self.bloops {
+            bloop.beat_grid = beat_grid;
+        }
+        match self.paused_at {
+            Some(_) => None,
+            None => self
+                .bloops
                 .iter_mut()
-                .filter_map(|b| b.do_events_and_return_wake_time(Instant::now()))
-                .min();
+                .filter_map(|b| b.do_events_and_return_wake_time(now))
+                .min(),
+        }
+    }
+
+    fn ui_state(&self, now: Instant) -> UiState {
+        UiState {
+            epoch: self.epoch,
+            duration: self.duration,
+            tempo_master: self.tempo_master,
+            assigned_tempo_master: self.assigned_tempo_master,
+            is_monitoring: self.monitor.is_enabled,
+            paused_at: self.paused_at,
+            key_press_count: self.key_press_count,
+            last_key_pressed: self.last_key_pressed,
+            tap_stability: self.tap_tempo.stability(now),
+            master_transpose: self.master_transpose,
+            config: self.config.clone(),
+            bloops: self
+                .bloops
+                .iter()
+                .map(|bloop| bloop.ui_state(now))
+                .collect_vec(),
+        }
+    }
 
-            let command = if let Some(deadline) = next_event_time {
-                match commands_rx.recv_deadline(deadline) {
-                    Ok(command) => command,
-                    Err(flume::RecvTimeoutError::Disconnected) => return,
-                    Err(flume::RecvTimeoutError::Timeout) => continue,
+    /// Carries out a command at `now`.
+    fn handle_command(&mut self, command: BloopCommand, now: Instant) {
+        match command {
+            BloopCommand::Midi(_, LiveEvent::Midi { message, .. })
+                if self.config.remote_control.is_mapped(message) =>
+            {
+                if let Some(action) = self.config.remote_control.action_for(message) {
+                    self.commands_tx.send(action.command()).unwrap();
                 }
-            } else {
-                match commands_rx.recv() {
-                    Ok(command) => command,
-                    Err(flume::RecvError::Disconnected) => return,
+                if let Some(beats) = self.config.remote_control.jog_beats(message) {
+                    self.commands_tx.send(BloopCommand::Scrub(beats)).unwrap();
                 }
-            };
-
-            match command {
-                BloopCommand::RefreshUi => {
-                    let ui_state = UiState {
-                        epoch,
-                        duration,
-                        bloops: bloops.iter().map(|bloop| bloop.ui_state()).collect_vec(),
-                    };
-                    if ui_state_tx.send(ui_state).is_err() {
-                        return;
-                    }
+            }
+            BloopCommand::Midi(_, LiveEvent::Common(SystemCommon::SysEx(data)))
+                if self.config.remote_control.mmc =>
+            {
+                if let Some(action) = RemoteAction::from_mmc(data) {
+                    self.commands_tx.send(action.command()).unwrap();
                 }
-
-                BloopCommand::Midi(LiveEvent::Midi { channel, message }) => {
-                    let time = Instant::now();
-                    let message = TimedMidiMessage { time, message };
-                    if let KeyEffect::Press { key, vel: _ } = KeyEffect::from(message.message) {
-                        match (channel.as_int(), key.as_int()) {
-                            (4, 76) => commands_tx.send(BloopCommand::ClearAll).unwrap(),
-                            (5, 77) => commands_tx.send(BloopCommand::DoKey(0)).unwrap(),
-                            (4, 78) => bloops[0].toggle_listening(),
-                            (5, 79) => commands_tx.send(BloopCommand::DoKey(1)).unwrap(),
-                            (4, 80) => bloops[1].toggle_listening(),
-                            (5, 81) => commands_tx.send(BloopCommand::DoKey(2)).unwrap(),
-                            (4, 82) => bloops[2].toggle_listening(),
-                            _ => {
-                                for bloop in &mut bloops {
-                                    bloop.recv_midi(channel, message);
+            }
+            BloopCommand::Midi(port, LiveEvent::Midi { channel, message }) => {
+                let port = port.as_deref();
+                let message = TimedMidiMessage {
+                    time: now,
+                    channel,
+                    message: self.config.input_velocity.apply(message),
+                };
+                if let KeyEffect::Press { key, vel: _ } = KeyEffect::from(message.message) {
+                    self.key_press_count += 1;
+                    self.last_key_pressed = Some(key);
+                    match self.config.shortcuts.command_for(channel, key) {
+                        Some(command) => self.commands_tx.send(command).unwrap(),
+                        None => {
+                            self.monitor.recv_midi(message, self.config.monitor_channel);
+                            for bloop in &mut self.bloops {
+                                if bloop.accepts_input_from(port) {
+                                    bloop.recv_midi(message);
                                 }
                             }
                         }
-                    } else {
-                        for bloop in &mut bloops {
-                            bloop.recv_midi(channel, message);
+                    }
+                } else {
+                    self.monitor.recv_midi(message, self.config.monitor_channel);
+                    for bloop in &mut self.bloops {
+                        if bloop.accepts_input_from(port) {
+                            bloop.recv_midi(message);
                         }
                     }
                 }
-                BloopCommand::Midi(_) => (), // Ignore other MIDI events
+            }
+            BloopCommand::Midi(_, _) => (), // Ignore other MIDI events
 
-                BloopCommand::DoKey(i) => {
-                    if bloops[i].is_recording() {
-                        commands_tx.send(BloopCommand::StartPlaying(i)).unwrap();
-                    } else if !bloops[i].playbacks.is_empty()
-                        || bloops[i].next_queued_playback_time.is_some()
-                    {
-                        commands_tx.send(BloopCommand::TogglePlayback(i)).unwrap();
-                    } else {
-                        commands_tx.send(BloopCommand::StartRecording(i)).unwrap();
+            BloopCommand::ToggleMonitor => self.monitor.toggle(now),
+            BloopCommand::PauseTransport => {
+                if self.paused_at.is_none() {
+                    self.paused_at = Some(now);
+                    for bloop in &mut self.bloops {
+                        bloop.pause(now);
                     }
                 }
-                BloopCommand::ToggleListening(i) => bloops[i].toggle_listening(),
-                BloopCommand::TogglePlayback(i) => bloops[i].toggle_playing(),
-                BloopCommand::CancelPlaying(i) => bloops[i].cancel_all_playbacks(),
-                BloopCommand::StartRecording(i) => {
-                    if epoch.is_none() || duration.is_none() {
-                        // If we don't know the tempo, then stop recording on
-                        // another bloop and use that to infer the tempo.
-                        if let Some(recording_bloop) =
-                            bloops.iter_mut().find(|bloop| bloop.recorder.is_listening)
-                        {
-                            if let Some(start) = recording_bloop.recording_start_time {
-                                let end = Instant::now();
-                                epoch = Some(start);
-                                duration = Some(end - start);
-                                recording_bloop.start_playing(end - start);
+            }
+            BloopCommand::ResumeTransport => {
+                if let Some(t) = self.paused_at.take() {
+                    let paused_duration = now - t;
+                    self.epoch = self.epoch.map(|epoch| epoch + paused_duration);
+                    for bloop in &mut self.bloops {
+                        bloop.resume(now, paused_duration);
+                    }
+                }
+            }
+            BloopCommand::Panic => {
+                send_all_notes_off(now, &mut self.bloops, &mut self.monitor, &self.midi_out_tx)
+            }
+            BloopCommand::StopAll => {
+                for bloop in &mut self.bloops {
+                    bloop.cancel_all_playbacks(now);
+                }
+            }
+            BloopCommand::ReanchorTransport(t) => {
+                if self.epoch.is_some() && self.paused_at.is_none() {
+                    self.epoch = Some(t);
+                    for bloop in &mut self.bloops {
+                        bloop.reanchor(t);
+                    }
+                }
+            }
+
+            BloopCommand::Scrub(beats) => {
+                if !beats.is_finite() {
+                    return;
+                }
+                if let (Some(e), Some(d), None) = (self.epoch, self.duration, self.paused_at) {
+                    let amount = (d / self.config.beats_per_loop()).mul_f32(beats.abs());
+                    let is_backward = beats < 0.0;
+                    // Moving the epoch earlier moves the playhead forward.
+                    let loop_nanos = d.as_nanos().max(1);
+                    let nanos = amount.as_nanos() % loop_nanos;
+                    let earlier = match is_backward {
+                        true => loop_nanos - nanos,
+                        false => nanos,
+                    };
+                    self.epoch = e
+                        .checked_sub(Duration::from_nanos(earlier as u64))
+                        .or(self.epoch);
+                    for bloop in &mut self.bloops {
+                        bloop.scrub(now, amount, is_backward);
+                    }
+                }
+            }
+
+            BloopCommand::DoKey(i) => {
+                let policy = self.config.do_key_policy;
+                if self.bloops[i].is_recording(now) {
+                    self.commands_tx
+                        .send(BloopCommand::StartPlaying(i))
+                        .unwrap();
+                } else if self.bloops[i].is_playing_back() {
+                    if policy != DoKeyPolicy::RecordOnly {
+                        self.commands_tx
+                            .send(BloopCommand::TogglePlayback(i))
+                            .unwrap();
+                    }
+                } else if policy != DoKeyPolicy::ToggleOnly {
+                    self.commands_tx
+                        .send(BloopCommand::StartRecording(i))
+                        .unwrap();
+                }
+            }
+            BloopCommand::ToggleListening(i) => self.bloops[i].toggle_listening(),
+            BloopCommand::ToggleRecordArm(i) => self.bloops[i].toggle_record_arm(),
+            BloopCommand::TogglePlayback(i) => self.bloops[i].toggle_playing(now),
+            BloopCommand::ToggleBypass(i) => self.bloops[i].toggle_bypass(now),
+            BloopCommand::CancelPlaying(i) => self.bloops[i].cancel_all_playbacks(now),
+            BloopCommand::Stutter(i, length) => self.bloops[i].start_stutter(now, length),
+            BloopCommand::StopStutter(i) => self.bloops[i].stop_stutter(now),
+            BloopCommand::Align(i, grid) => self.bloops[i].align(now, grid),
+            BloopCommand::Retrigger(i) => {
+                let step = match self.bloops[i].config.launch_quantize {
+                    LaunchQuantize::Free => None,
+                    LaunchQuantize::Beat => self.duration.map(|d| d / self.config.beats_per_loop()),
+                    LaunchQuantize::Bar => self
+                        .duration
+                        .map(|d| d / self.config.measures_per_loop.max(1)),
+                    LaunchQuantize::Loop => self.duration,
+                };
+                // Without a tempo, there is no grid to wait for.
+                let time = next_loop_time(now, self.epoch, step).map_or(now, |(start, _)| start);
+                self.bloops[i].retrigger(time);
+            }
+            BloopCommand::DoubleLength(i) => self.bloops[i].double_length(now),
+            BloopCommand::HalveLength(i) => self.bloops[i].halve_length(now),
+            BloopCommand::UndoLayer(i) => self.bloops[i].undo_layer(now),
+            BloopCommand::CommitOverdub(i) => self.bloops[i].commit_overdubs(),
+            BloopCommand::PunchIn(i) => self.bloops[i].punch_in(now),
+            BloopCommand::PunchOut(i) => self.bloops[i].punch_out(now),
+            BloopCommand::Freeze(i) => self.bloops[i].freeze(now),
+            BloopCommand::Unfreeze(i) => self.bloops[i].unfreeze(now),
+            BloopCommand::RecordNext => {
+                let last = self
+                    .record_order
+                    .back()
+                    .copied()
+                    .unwrap_or(self.bloops.len() - 1);
+                let next = (last + 1..self.bloops.len())
+                    .chain(0..=last)
+                    .find(|&i| self.bloops[i].is_empty())
+                    .or(self.record_order.front().copied());
+                if let Some(i) = next {
+                    self.commands_tx
+                        .send(BloopCommand::StartRecording(i))
+                        .unwrap();
+                }
+            }
+            BloopCommand::StartRecording(i) => {
+                self.record_order.retain(|&j| j != i);
+                self.record_order.push_back(i);
+
+                if self.bloops[i].has_recording() {
+                    match self.config.record_over {
+                        RecordOverPolicy::Replace => (),
+                        RecordOverPolicy::Overdub if self.bloops[i].is_playing_back() => {
+                            if let Some((start, end)) =
+                                next_loop_time(now, self.epoch, self.duration)
+                            {
+                                log::trace!("Schedule overdub on #{i}");
+                                self.bloops[i].start_overdub(start, end);
                             }
+                            return;
+                        }
+                        RecordOverPolicy::Overdub => (),
+                        RecordOverPolicy::Refuse => {
+                            log::warn!("Bloop #{i} already has a loop; clear it first");
+                            return;
+                        }
+                    }
+                }
+
+                if (self.epoch.is_none() || self.duration.is_none())
+                    && !self.config.record_first_loops_together
+                {
+                    // If we don't know the tempo, then stop recording on
+                    // another bloop (the tempo master, if one is assigned)
+                    // and use that to infer the tempo.
+                    let recording_master = match self.assigned_tempo_master {
+                        Some(m) => Some(m).filter(|&m| self.bloops[m].is_recording_in_progress()),
+                        None => self
+                            .bloops
+                            .iter()
+                            .position(|b| b.is_recording_in_progress()),
+                    };
+                    if let Some(j) = recording_master {
+                        if let Some(start) = self.bloops[j].recording_start_time {
+                            let loop_duration = self
+                                .config
+                                .master_loop_duration(now - start)
+                                .max(MIN_LOOP_DURATION);
+                            self.epoch = Some(start);
+                            self.duration = Some(loop_duration);
+                            self.tempo_master = Some(j);
+                            self.bloops[j].start_playing(loop_duration);
                         }
                     }
+                }
+
+                let free_length = self.bloops[i].config.free_length;
+                let length_measures = self.bloops[i]
+                    .config
+                    .length_measures
+                    .filter(|_| !free_length);
+                if length_measures.is_some() && (self.epoch.is_none() || self.duration.is_none()) {
+                    // Start the tempo now, at the reference tempo.
+                    self.epoch = Some(now);
+                    self.duration =
+                        Some(self.config.beat_duration() * self.config.beats_per_loop());
+                    self.tempo_master = Some(self.assigned_tempo_master.unwrap_or(i));
+                }
 
-                    if let Some((next_start, next_end)) = next_loop_time(epoch, duration) {
-                        log::trace!(
-                            "Schedule recording start on #{i} in {:?}",
-                            next_start - Instant::now(),
-                        );
-                        bloops[i].start_recording(next_start, Some(next_end));
-                    } else {
-                        log::trace!("Schedule recording start on #{i}");
-                        bloops[i].start_recording(Instant::now(), None);
+                if let Some(measures) = length_measures {
+                    // Record for exactly that many measures, starting at
+                    // the next measure.
+                    let measure_duration = self
+                        .duration
+                        .map(|d| d / self.config.measures_per_loop.max(1));
+                    if let Some((next_start, _)) = next_loop_time(now, self.epoch, measure_duration)
+                    {
+                        let next_end = next_start + measure_duration.unwrap_or_default() * measures;
+                        log::trace!("Schedule {measures}-measure recording on #{i}");
+                        self.bloops[i].start_recording(next_start, Some(next_end));
                     }
+                } else if let Some((next_start, next_end)) =
+                    next_loop_time(now, self.epoch, self.duration)
+                {
+                    log::trace!(
+                        "Schedule recording start on #{i} in {:?}",
+                        next_start.saturating_duration_since(now),
+                    );
+                    // A free-length recording runs until stopped.
+                    let end = (!free_length).then_some(next_end);
+                    self.bloops[i].start_recording(next_start, end);
+                } else {
+                    log::trace!("Schedule recording start on #{i}");
+                    self.bloops[i].start_recording(now, None);
                 }
-                BloopCommand::StartPlaying(i) => {
-                    if epoch.is_some() || duration.is_some() {
-                        continue; // We already know the tempo, so ignore this request.
+            }
+            BloopCommand::StartPlaying(i) => {
+                if let (Some(_), Some(loop_duration)) = (self.epoch, self.duration) {
+                    // We already know the tempo, so only a free-length
+                    // recording can be stopped.
+                    self.bloops[i].end_open_recording(now, loop_duration);
+                    return;
+                }
+                if self.epoch.is_some() || self.duration.is_some() {
+                    return; // We already know the tempo, so ignore this request.
+                }
+                if self.assigned_tempo_master.is_some_and(|m| m != i) {
+                    log::warn!("Ignoring stop on bloop #{i}; waiting for the tempo master");
+                    return;
+                }
+                if let Some(start) = self.bloops[i].recording_start_time {
+                    let loop_duration = self.config.master_loop_duration(now - start);
+                    if loop_duration < MIN_LOOP_DURATION {
+                        log::warn!("Ignoring stop on bloop #{i}; the loop would be too short");
+                        return;
                     }
-                    if let Some(start) = bloops[i].recording_start_time {
-                        let end = Instant::now();
-                        epoch = Some(start);
-                        duration = Some(end - start);
-                        bloops[i].start_playing(end - start);
+                    self.epoch = Some(start);
+                    self.duration = Some(loop_duration);
+                    self.tempo_master = Some(i);
+                    self.bloops[i].start_playing(loop_duration);
+                }
+            }
+            BloopCommand::Clear(i) => {
+                self.bloops[i].clear(now);
+                // Pass master status on to another loop that is still
+                // playing, if there is one.
+                if self.tempo_master == Some(i) {
+                    self.tempo_master = self
+                        .assigned_tempo_master
+                        .or_else(|| self.bloops.iter().position(|b| b.is_playing_back()));
+                    if self.config.recompute_tempo_on_master_change {
+                        (self.epoch, self.duration) =
+                            tempo_of(&self.bloops, self.tempo_master, now);
                     }
                 }
-                BloopCommand::ClearAll => {
-                    for bloop in &mut bloops {
-                        bloop.cancel_recording();
-                        bloop.cancel_all_playbacks();
+            }
+            BloopCommand::SetTempoMaster(i) => {
+                self.assigned_tempo_master = Some(i);
+                if self.tempo_master != Some(i)
+                    && (self.epoch.is_none() || self.config.recompute_tempo_on_master_change)
+                {
+                    (self.epoch, self.duration) = tempo_of(&self.bloops, Some(i), now);
+                }
+                self.tempo_master = Some(i);
+            }
+            BloopCommand::ClearTempoMaster => {
+                self.assigned_tempo_master = None;
+                if self.tempo_master.is_some_and(|m| self.bloops[m].is_empty()) {
+                    self.tempo_master = self.bloops.iter().position(|b| b.is_playing_back());
+                    if self.config.recompute_tempo_on_master_change {
+                        (self.epoch, self.duration) =
+                            tempo_of(&self.bloops, self.tempo_master, now);
                     }
-                    epoch = None;
-                    duration = None;
                 }
             }
+            BloopCommand::ClearAll => {
+                for bloop in &mut self.bloops {
+                    bloop.clear(now);
+                }
+                if self.config.clear_all_sends_all_notes_off {
+                    send_all_notes_off(now, &mut self.bloops, &mut self.monitor, &self.midi_out_tx);
+                }
+                self.epoch = None;
+                self.duration = None;
+                self.tempo_master = None;
+            }
+
+            BloopCommand::TapTempo(t) => {
+                if let Some(beat) = self.tap_tempo.tap(t, self.config.tap_outlier_percent) {
+                    let bpm = (60.0 / beat.as_secs_f32()).clamp(20.0, 300.0);
+                    let new_config = GlobalConfig {
+                        bpm,
+                        ..self.config.clone()
+                    };
+                    self.commands_tx
+                        .send(BloopCommand::SetGlobalConfig(new_config))
+                        .unwrap();
+                }
+            }
+            BloopCommand::SetMasterTranspose(transpose) => {
+                self.master_transpose = transpose.clamp(-MAX_TRANSPOSE, MAX_TRANSPOSE);
+                for bloop in &mut self.bloops {
+                    bloop.set_transpose(now, self.master_transpose);
+                }
+            }
+            BloopCommand::SetGlobalConfig(new_config) => {
+                for bloop in &mut self.bloops {
+                    bloop.set_global_config(now, new_config.clone());
+                }
+                self.monitor.global_config = new_config.clone();
+                self.config = new_config;
+            }
+            BloopCommand::SetBloopConfig(i, new_config) => {
+                self.bloops[i].set_config(now, new_config)
+            }
+            BloopCommand::SetGainCurve(i, curve) => self.bloops[i].set_gain_curve(curve),
+
+            BloopCommand::OutputConnected => {
+                for bloop in &mut self.bloops {
+                    bloop.send_program_change(now);
+                }
+            }
+        }
+    }
+}
+
+pub fn spawn_bloops_thread() -> Result<(
+    flume::Sender<BloopCommand>,
+    flume::Receiver<UiState>,
+    flume::Receiver<OutputEvent>,
+)> {
+    let (commands_tx, commands_rx) = flume::unbounded();
+    let (ui_state_tx, ui_state_rx) = flume::bounded(1);
+    let (midi_out_tx, midi_out_rx) = flume::bounded(OUTPUT_QUEUE_CAPACITY);
+
+    let mut engine = Engine::new(commands_tx.clone(), midi_out_tx);
+    let ui_state_rx_ref = ui_state_rx.clone();
+    std::thread::spawn(move || {
+        let stale_ui_state_rx = ui_state_rx_ref;
+        let mut next_ui_state_time = Instant::now();
+
+        loop {
+            let now = Instant::now();
+            let next_event_time = engine.do_events(now);
+
+            if now >= next_ui_state_time {
+                // Replace any state that the UI hasn't read yet, so that it
+                // never has to wait for us.
+                _ = stale_ui_state_rx.try_recv();
+                _ = ui_state_tx.try_send(engine.ui_state(now));
+                next_ui_state_time = now + UI_STATE_INTERVAL;
+            }
+
+            let deadline = option_at_most(next_event_time, next_ui_state_time);
+            let command = match commands_rx.recv_deadline(deadline) {
+                Ok(command) => command,
+                Err(flume::RecvTimeoutError::Disconnected) => return,
+                Err(flume::RecvTimeoutError::Timeout) => continue,
+            };
+
+            // Read the time once, so that the whole command sees the same time.
+            let now = Instant::now();
+            // Show the effects of the command as soon as possible.
+            next_ui_state_time = now;
+
+            engine.handle_command(command, now);
         }
     });
 
     Ok((commands_tx, ui_state_rx, midi_out_rx))
 }
 
+/// Number of pitch bend messages used to return to the starting pitch bend at
+/// a loop boundary.
+const PITCH_BEND_RAMP_STEPS: u32 = 4;
+/// Time between pitch bend messages when returning to the starting pitch bend
+/// at a loop boundary.
+const PITCH_BEND_RAMP_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Shortest loop that can be played, so that pressing a key twice in quick
+/// succession can't make a loop with no length.
+const MIN_LOOP_DURATION: Duration = Duration::from_millis(50);
+
+/// How far past a loop boundary a time may be and still be considered on the
+/// boundary, so that a request made right at the boundary doesn't wait a whole
+/// extra loop.
+const LOOP_BOUNDARY_TOLERANCE: Duration = Duration::from_millis(1);
+
+/// Returns the first time at or after `time` on a grid of steps of length
+/// `step` starting at `origin`.
+fn next_grid_time(time: Instant, origin: Instant, step: Duration) -> Instant {
+    if time <= origin {
+        return origin;
+    }
+    let phase_nanos = (time - origin).as_nanos() % step.as_nanos();
+    match phase_nanos {
+        0 => time,
+        _ => time + (step - Duration::from_nanos(phase_nanos as u64)),
+    }
+}
+
+/// Returns the epoch and duration of the tempo set by the loop of `master`,
+/// or `None` for both if it has no loop.
+fn tempo_of(
+    bloops: &[Bloop],
+    master: Option<usize>,
+    now: Instant,
+) -> (Option<Instant>, Option<Duration>) {
+    match master.and_then(|i| bloops[i].loop_span(now)) {
+        Some((start, end)) => (Some(start), Some(end - start)),
+        None => (None, None),
+    }
+}
+
+/// Returns the start and end time of the next loop that starts at or after
+/// `now`.
 fn next_loop_time(
+    now: Instant,
     epoch: Option<Instant>,
     duration: Option<Duration>,
 ) -> Option<(Instant, Instant)> {
-    let loops_elapsed = (Instant::now() - epoch?).as_secs_f32() / duration?.as_secs_f32();
-    let next_start = epoch? + duration? * loops_elapsed.ceil() as u32;
-    let next_end = next_start + duration?;
+    let (epoch, duration) = (epoch?, duration?);
+    if duration.is_zero() {
+        return None;
+    }
+    let elapsed = now.saturating_duration_since(epoch);
+    let mut loops_elapsed = u64::try_from(elapsed.as_nanos() / duration.as_nanos()).ok()?;
+    if elapsed.saturating_sub(duration_times(duration, loops_elapsed)?) > LOOP_BOUNDARY_TOLERANCE {
+        loops_elapsed = loops_elapsed.checked_add(1)?;
+    }
+    let next_start = epoch.checked_add(duration_times(duration, loops_elapsed)?)?;
+    let next_end = next_start.checked_add(duration)?;
     Some((next_start, next_end))
 }
 
+/// Returns `duration * n`, or `None` if it overflows.
+///
+/// Unlike multiplying a `Duration` by a `u32`, this accepts loop counts that
+/// may grow large over a long session.
+fn duration_times(duration: Duration, n: u64) -> Option<Duration> {
+    let nanos = duration.as_nanos().checked_mul(u128::from(n))?;
+    let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+    Some(Duration::new(secs, (nanos % 1_000_000_000) as u32))
+}
+
 pub fn option_at_most<T: PartialOrd>(a: Option<T>, b: T) -> T {
     match a {
         Some(a) if a < b => a,
         _ => b,
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Engine driven by a fake clock, which collects everything that it sends
+    /// to the output.
+    ///
+    /// Commands go through the same channel as in the bloops thread, so
+    /// commands that send other commands behave the same way.
+    pub(crate) struct Harness {
+        engine: Engine,
+        commands_tx: flume::Sender<BloopCommand>,
+        commands_rx: flume::Receiver<BloopCommand>,
+        output_rx: flume::Receiver<OutputEvent>,
+        /// Time at which the test started, which all other times are measured
+        /// from.
+        pub(crate) start: Instant,
+        /// Current time of the fake clock.
+        pub(crate) now: Instant,
+    }
+    impl Harness {
+        pub(crate) fn new() -> Self {
+            let (commands_tx, commands_rx) = flume::unbounded();
+            let (midi_out_tx, output_rx) = flume::unbounded();
+            let start = Instant::now();
+            Self {
+                engine: Engine::new(commands_tx.clone(), midi_out_tx),
+                commands_tx,
+                commands_rx,
+                output_rx,
+                start,
+                now: start,
+            }
+        }
+        /// Returns the time `ms` milliseconds after the start of the test.
+        pub(crate) fn at(&self, ms: u64) -> Instant {
+            self.start + Duration::from_millis(ms)
+        }
+
+        /// Sends a command at the current time, along with any commands that
+        /// it sends, processing events in between as the bloops thread does.
+        pub(crate) fn command(&mut self, command: BloopCommand) {
+            self.commands_tx.send(command).unwrap();
+            while let Ok(command) = self.commands_rx.try_recv() {
+                self.engine.handle_command(command, self.now);
+                self.engine.do_events(self.now);
+            }
+        }
+        /// Sets the global configuration.
+        pub(crate) fn set_config(&mut self, f: impl FnOnce(&mut GlobalConfig)) {
+            let mut config = self.engine.config.clone();
+            f(&mut config);
+            self.command(BloopCommand::SetGlobalConfig(config));
+        }
+        /// Sends a MIDI message from the computer keyboard on `channel`.
+        pub(crate) fn midi(&mut self, channel: u8, message: MidiMessage) {
+            let event = LiveEvent::Midi {
+                channel: channel.into(),
+                message,
+            };
+            self.command(BloopCommand::Midi(None, event));
+        }
+        /// Presses a key on channel 0.
+        pub(crate) fn press(&mut self, key: u8, vel: u8) {
+            self.midi(0, note_on(key, vel));
+        }
+        /// Releases a key on channel 0.
+        pub(crate) fn release(&mut self, key: u8) {
+            self.midi(0, note_on(key, 0));
+        }
+
+        /// Advances the fake clock to `ms` milliseconds after the start of the
+        /// test, processing every event that is due on the way at the time it
+        /// is due.
+        pub(crate) fn wait_until(&mut self, ms: u64) {
+            let target = self.at(ms);
+            assert!(target >= self.now, "the fake clock can't go backward");
+            while let Some(wake_time) = self.engine.do_events(self.now) {
+                if wake_time <= self.now || wake_time > target {
+                    break;
+                }
+                self.now = wake_time;
+            }
+            self.now = target;
+            self.engine.do_events(self.now);
+        }
+
+        /// Returns every event sent to the output since the last call.
+        pub(crate) fn take_output(&mut self) -> Vec<OutputEvent> {
+            self.output_rx.try_iter().collect()
+        }
+        /// Returns every MIDI message sent to the output since the last call,
+        /// as `(ms, channel, message)` where `ms` is the intended time in
+        /// milliseconds after the start of the test.
+        pub(crate) fn take_midi(&mut self) -> Vec<(f64, u8, MidiMessage)> {
+            let start = self.start;
+            self.take_output()
+                .into_iter()
+                .filter_map(|event| match event.event {
+                    LiveEvent::Midi { channel, message } => {
+                        let ms = event.time.saturating_duration_since(start).as_secs_f64() * 1000.0;
+                        Some((ms, channel.as_int(), message))
+                    }
+                    _ => None,
+                })
+                .collect()
+        }
+        /// Returns the note-on messages with nonzero velocity sent to the
+        /// output since the last call, as `(ms, channel, key)`.
+        pub(crate) fn take_presses(&mut self) -> Vec<(f64, u8, u8)> {
+            self.take_midi()
+                .into_iter()
+                .filter_map(|(ms, channel, message)| match KeyEffect::from(message) {
+                    KeyEffect::Press { key, .. } => Some((ms, channel, key.as_int())),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        /// Records a loop on bloop `i` from 0 ms to `length_ms`, pressing each
+        /// `(key, press_ms, release_ms)` in between, and starts playing it.
+        /// Any output is discarded.
+        pub(crate) fn record_loop(&mut self, i: usize, length_ms: u64, notes: &[(u8, u64, u64)]) {
+            let mut events = notes
+                .iter()
+                .flat_map(|&(key, press, release)| [(press, key, 100), (release, key, 0)])
+                .collect_vec();
+            events.sort_by_key(|&(ms, _, _)| ms);
+            self.command(BloopCommand::StartRecording(i));
+            for (ms, key, vel) in events {
+                self.wait_until(ms);
+                self.midi(0, note_on(key, vel));
+            }
+            self.wait_until(length_ms);
+            self.command(BloopCommand::StartPlaying(i));
+            self.take_output();
+        }
+    }
+
+    pub(crate) fn note_on(key: u8, vel: u8) -> MidiMessage {
+        MidiMessage::NoteOn {
+            key: key.into(),
+            vel: vel.into(),
+        }
+    }
+    pub(crate) fn aftertouch(key: u8, vel: u8) -> MidiMessage {
+        MidiMessage::Aftertouch {
+            key: key.into(),
+            vel: vel.into(),
+        }
+    }
+    #[test]
+    fn test_record_and_loop() {
+        let mut h = Harness::new();
+        h.command(BloopCommand::StartRecording(0));
+        h.wait_until(100);
+        h.press(60, 100);
+        h.wait_until(300);
+        h.release(60);
+        h.wait_until(1000);
+        h.command(BloopCommand::StartPlaying(0));
+        h.wait_until(2500);
+
+        // Live input passes through, then each pass of the loop plays it back.
+        assert_eq!(
+            h.take_midi(),
+            [
+                (100.0, 0, note_on(60, 100)),
+                (300.0, 0, note_on(60, 0)),
+                (1100.0, 0, note_on(60, 100)),
+                (1300.0, 0, note_on(60, 0)),
+                (2100.0, 0, note_on(60, 100)),
+                (2300.0, 0, note_on(60, 0)),
+            ],
+        );
+        let state = h.engine.ui_state(h.now);
+        assert_eq!(state.epoch, Some(h.at(0)));
+        assert_eq!(state.duration, Some(Duration::from_millis(1000)));
+        assert_eq!(state.bloops[0].phase, BloopPhase::Playing);
+    }
+
+    #[test]
+    fn test_fake_clock_wakes_at_each_event() {
+        let mut h = Harness::new();
+        h.record_loop(0, 1000, &[(60, 100, 300), (62, 500, 700)]);
+        // Jumping straight past several events still plays each one at the
+        // time it was due, in order.
+        h.wait_until(1999);
+        assert_eq!(h.take_presses(), [(1100.0, 0, 60), (1500.0, 0, 62)]);
+        h.wait_until(2100);
+        assert_eq!(h.take_presses(), [(2100.0, 0, 60)]);
+    }
+
+    #[test]
+    fn test_aftertouch_after_release_is_not_played_back() {
+        let mut h = Harness::new();
+        h.set_config(|c| c.record_over = RecordOverPolicy::Overdub);
+        h.command(BloopCommand::StartRecording(0));
+        h.wait_until(100);
+        h.press(60, 100);
+        h.wait_until(200);
+        h.midi(0, aftertouch(60, 50));
+        h.wait_until(300);
+        h.release(60);
+        h.wait_until(1000);
+        h.command(BloopCommand::StartPlaying(0));
+        // Overdub aftertouch after the key is released, which passthrough
+        // drops but the overdub records.
+        h.command(BloopCommand::StartRecording(0));
+        h.wait_until(1400);
+        h.midi(0, aftertouch(60, 70));
+        h.midi(0, MidiMessage::ChannelAftertouch { vel: 70.into() });
+        h.wait_until(2000);
+        h.take_output();
+
+        // Only the aftertouch recorded while the key was held plays back.
+        h.wait_until(2999);
+        let aftertouch = h
+            .take_midi()
+            .into_iter()
+            .filter(|&(_, _, message)| {
+                matches!(
+                    KeyEffect::from(message),
+                    KeyEffect::Aftertouch { .. } | KeyEffect::ChannelAftertouch
+                )
+            })
+            .collect_vec();
+        assert_eq!(aftertouch, [(2200.0, 0, self::aftertouch(60, 50))]);
+    }
+
+    #[test]
+    fn test_double_length_repeats_loop() {
+        let mut h = Harness::new();
+        h.record_loop(0, 1000, &[(60, 100, 200), (64, 600, 700)]);
+        h.wait_until(1050);
+        h.command(BloopCommand::DoubleLength(0));
+        h.wait_until(4999);
+        assert_eq!(
+            h.take_presses(),
+            [
+                (1100.0, 0, 60),
+                (1600.0, 0, 64),
+                (2100.0, 0, 60),
+                (2600.0, 0, 64),
+                (3100.0, 0, 60),
+                (3600.0, 0, 64),
+                (4100.0, 0, 60),
+                (4600.0, 0, 64),
+            ],
+        );
+        let loop_span = h.engine.ui_state(h.now).bloops[0].loop_span;
+        assert_eq!(loop_span.map(|(_, d)| d), Some(Duration::from_millis(2000)));
+    }
+
+    #[test]
+    fn test_halve_length_truncates_loop() {
+        let mut h = Harness::new();
+        h.record_loop(0, 1000, &[(60, 100, 200), (64, 600, 700)]);
+        h.wait_until(1050);
+        h.command(BloopCommand::HalveLength(0));
+        h.wait_until(2499);
+        assert_eq!(
+            h.take_presses(),
+            [(1100.0, 0, 60), (1600.0, 0, 60), (2100.0, 0, 60)],
+        );
+        let loop_span = h.engine.ui_state(h.now).bloops[0].loop_span;
+        assert_eq!(loop_span.map(|(_, d)| d), Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_next_loop_time_at_boundary() {
+        let epoch = Instant::now();
+        let duration = Duration::from_millis(1000);
+        let next_start = |after: Duration| {
+            let (start, end) = next_loop_time(epoch + after, Some(epoch), Some(duration)).unwrap();
+            assert_eq!(end - start, duration);
+            start - epoch
+        };
+        let ms = Duration::from_millis;
+        assert_eq!(next_start(ms(2000)), ms(2000));
+        assert_eq!(next_start(ms(2000) - Duration::from_micros(1)), ms(2000));
+        // Jitter just past the boundary does not delay the start a whole loop.
+        assert_eq!(next_start(ms(2000) + Duration::from_micros(500)), ms(2000));
+        assert_eq!(next_start(ms(2000) + LOOP_BOUNDARY_TOLERANCE), ms(2000));
+        assert_eq!(next_start(ms(2002)), ms(3000));
+    }
+
+    #[test]
+    fn test_record_armed_at_loop_boundary() {
+        let mut h = Harness::new();
+        h.record_loop(0, 1000, &[(60, 100, 200)]);
+        h.wait_until(2000);
+        h.command(BloopCommand::StartRecording(1));
+        let state = &h.engine.ui_state(h.now).bloops[1];
+        assert_eq!(state.phase, BloopPhase::Recording);
+        assert_eq!(state.time_until_recording, None);
+        h.wait_until(2300);
+        h.press(64, 100);
+        h.wait_until(2400);
+        h.release(64);
+        h.wait_until(3000);
+        h.take_output();
+
+        // The recording covered exactly one loop and plays back in the next.
+        h.wait_until(3999);
+        assert_eq!(h.take_presses(), [(3100.0, 0, 60), (3300.0, 1, 64)]);
+        let loop_span = h.engine.ui_state(h.now).bloops[1].loop_span;
+        let expected = (
+            h.start + Duration::from_millis(2000),
+            Duration::from_millis(1000),
+        );
+        assert_eq!(loop_span, Some(expected));
+    }
+
+    #[test]
+    fn test_events_past_loop_end_wrap_to_start() {
+        let mut h = Harness::new();
+        h.record_loop(0, 1000, &[(60, 100, 200)]);
+        let mut config = h.engine.bloops[1].config.clone();
+        config.free_length = true;
+        h.command(BloopCommand::SetBloopConfig(1, config));
+        h.command(BloopCommand::StartRecording(1));
+        h.wait_until(1100);
+        h.press(62, 100);
+        h.wait_until(1200);
+        h.release(62);
+        // Stopping late ends the recording at 2000 ms, so these are recorded
+        // past the end of the loop.
+        h.wait_until(2050);
+        h.press(64, 100);
+        h.wait_until(2150);
+        h.release(64);
+        h.wait_until(2200);
+        h.command(BloopCommand::StartPlaying(1));
+        assert_eq!(h.engine.ui_state(h.now).bloops[1].wrapped_event_count, 2);
+        h.wait_until(3000);
+        h.take_output();
+
+        h.wait_until(3999);
+        let presses = h.take_presses();
+        assert_eq!(presses, [(3050.0, 1, 64), (3100.0, 0, 60), (3100.0, 1, 62)]);
+    }
+
+    #[test]
+    fn test_swing_moves_offbeat_eighths() {
+        let mut h = Harness::new();
+        h.set_config(|c| c.measures_per_loop = 1);
+        h.record_loop(0, 1000, &[(60, 250, 300), (62, 375, 400)]);
+
+        // Straight timing plays back as recorded.
+        h.wait_until(1999);
+        assert_eq!(h.take_presses(), [(1250.0, 0, 60), (1375.0, 0, 62)]);
+
+        // Hard swing moves the offbeat eighth three quarters of the way
+        // through the beat, and leaves the downbeat alone.
+        h.set_config(|c| c.groove.swing = 0.75);
+        h.wait_until(2999);
+        assert_eq!(h.take_presses(), [(2250.0, 0, 60), (2437.5, 0, 62)]);
+    }
+
+    #[test]
+    fn test_record_and_loop_key_127() {
+        let mut h = Harness::new();
+        h.record_loop(0, 1000, &[(127, 100, 300)]);
+        h.wait_until(1999);
+        assert_eq!(
+            h.take_midi(),
+            [(1100.0, 0, note_on(127, 100)), (1300.0, 0, note_on(127, 0))],
+        );
+    }
+
+    #[test]
+    fn test_all_notes_off_does_not_block_on_full_queue() {
+        let (midi_out_tx, midi_out_rx) = flume::bounded(4);
+        let mut monitor = Monitor::new(midi_out_tx.clone());
+        // This would block forever if it waited for room in the queue.
+        send_all_notes_off(Instant::now(), &mut [], &mut monitor, &midi_out_tx);
+        assert_eq!(midi_out_rx.len(), 4);
+    }
+
+    #[test]
+    fn test_clear_all_sends_all_notes_off() {
+        let mut h = Harness::new();
+        h.set_config(|c| c.clear_all_sends_all_notes_off = true);
+        h.record_loop(0, 1000, &[(60, 100, 900)]);
+        h.wait_until(1500);
+        h.press(64, 100);
+        h.take_output();
+        assert!(!h.engine.bloops[0].keys_sounding.is_empty());
+
+        h.command(BloopCommand::ClearAll);
+        assert!(h.engine.bloops.iter().all(|b| b.keys_sounding.is_empty()));
+        let all_notes_off = MidiMessage::Controller {
+            controller: ALL_NOTES_OFF_CONTROLLER.into(),
+            value: 0.into(),
+        };
+        let mut expected = vec![(1500.0, 0, note_on(60, 0)), (1500.0, 0, note_on(64, 0))];
+        expected.extend((0..16).map(|channel| (1500.0, channel, all_notes_off)));
+        assert_eq!(h.take_midi(), expected);
+    }
+
+    #[test]
+    fn test_gain_curve_on_later_passes() {
+        let mut h = Harness::new();
+        h.record_loop(0, 1000, &[(60, 100, 200), (64, 600, 700)]);
+        h.command(BloopCommand::SetGainCurve(0, vec![(0.0, 1.0), (1.0, 0.5)]));
+        h.wait_until(3999);
+        let velocities = h
+            .take_midi()
+            .into_iter()
+            .filter_map(|(ms, _, message)| match KeyEffect::from(message) {
+                KeyEffect::Press { vel, .. } => Some((ms, vel.as_int())),
+                _ => None,
+            })
+            .collect_vec();
+        // The gain depends only on the position in the loop, so every pass is
+        // the same.
+        assert_eq!(
+            velocities,
+            [
+                (1100.0, 95),
+                (1600.0, 70),
+                (2100.0, 95),
+                (2600.0, 70),
+                (3100.0, 95),
+                (3600.0, 70),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_passthrough_only_on_listening_bloop() {
+        let mut h = Harness::new();
+        h.press(60, 100);
+        h.wait_until(100);
+        h.release(60);
+        assert_eq!(
+            h.take_midi(),
+            [(0.0, 0, note_on(60, 100)), (100.0, 0, note_on(60, 0))],
+        );
+
+        // Hand passthrough over to the second bloop.
+        h.command(BloopCommand::ToggleListening(0));
+        h.command(BloopCommand::ToggleListening(1));
+        h.wait_until(200);
+        h.press(62, 100);
+        h.wait_until(300);
+        h.release(62);
+        assert_eq!(
+            h.take_midi(),
+            [(200.0, 1, note_on(62, 100)), (300.0, 1, note_on(62, 0))],
+        );
+    }
+
+    #[test]
+    fn test_pad_spanning_loop_is_pressed_once() {
+        let mut h = Harness::new();
+        // Hold a key from before recording starts until after it ends.
+        h.press(60, 100);
+        h.command(BloopCommand::StartRecording(0));
+        h.wait_until(1000);
+        h.command(BloopCommand::StartPlaying(0));
+        h.wait_until(4500);
+        assert_eq!(h.take_midi(), [(0.0, 0, note_on(60, 100))]);
+    }
+
+    #[test]
+    fn test_note_off_and_zero_velocity_note_on_are_equivalent() {
+        let note_off = |key: u8, vel: u8| MidiMessage::NoteOff {
+            key: key.into(),
+            vel: vel.into(),
+        };
+        for (style, expected) in [
+            (NoteOffStyle::AsReceived, [note_off(60, 40), note_on(62, 0)]),
+            (
+                NoteOffStyle::NoteOnZeroVelocity,
+                [note_on(60, 0), note_on(62, 0)],
+            ),
+            (NoteOffStyle::NoteOff, [note_off(60, 40), note_off(62, 64)]),
+        ] {
+            let mut h = Harness::new();
+            h.set_config(|c| c.note_off_style = style);
+            h.command(BloopCommand::StartRecording(0));
+            h.wait_until(100);
+            h.press(60, 100);
+            h.press(62, 100);
+            h.wait_until(200);
+            h.midi(0, note_off(60, 40));
+            h.wait_until(300);
+            h.release(62);
+            h.wait_until(1000);
+            h.command(BloopCommand::StartPlaying(0));
+            h.take_output();
+
+            // Either kind of release ends the note, and is played back in the
+            // configured form.
+            h.wait_until(1999);
+            let releases = h
+                .take_midi()
+                .into_iter()
+                .filter(|&(_, _, message)| {
+                    matches!(KeyEffect::from(message), KeyEffect::Release { .. })
+                })
+                .map(|(_, _, message)| message)
+                .collect_vec();
+            assert_eq!(releases, expected, "{style:?}");
+            assert!(h.engine.bloops[0].keys_sounding.is_empty(), "{style:?}");
+        }
+    }
+
+    #[test]
+    fn test_playback_schedule_long_run() {
+        let anchor = Instant::now();
+        let duration = Duration::from_nanos(1_234_567_891);
+        let mut schedule = PlaybackSchedule {
+            anchor,
+            duration,
+            next_index: 0,
+        };
+        for i in 0..10_000 {
+            assert_eq!(
+                schedule.next_time(),
+                Some(anchor + duration_times(duration, i).unwrap())
+            );
+            assert!(schedule.next_index <= PlaybackSchedule::REBASE_INTERVAL);
+            schedule = schedule.advance();
+        }
+
+        // A loop count too large to represent gives up instead of panicking.
+        let schedule = PlaybackSchedule {
+            next_index: u64::MAX,
+            ..schedule
+        };
+        assert_eq!(schedule.next_time(), None);
+        assert_eq!(schedule.advance().next_index, u64::MAX);
+        assert_eq!(duration_times(Duration::from_secs(2), u64::MAX), None);
+        assert_eq!(
+            duration_times(Duration::from_secs(1), u64::MAX),
+            Some(Duration::from_secs(u64::MAX)),
+        );
+    }
+
+    #[test]
+    fn test_next_loop_time_after_many_loops() {
+        let epoch = Instant::now();
+        let duration = Duration::from_millis(1000);
+        let loops = 10_000_000;
+        let now = epoch + duration_times(duration, loops).unwrap() + Duration::from_millis(500);
+        let (start, end) = next_loop_time(now, Some(epoch), Some(duration)).unwrap();
+        assert_eq!(start, epoch + duration_times(duration, loops + 1).unwrap());
+        assert_eq!(end, start + duration);
+    }
+
+    #[test]
+    fn test_next_loop_time_at_one_loop() {
+        let epoch = Instant::now();
+        let duration = Duration::from_millis(1000);
+        let next_start = |after: Duration| {
+            let (start, _) = next_loop_time(epoch + after, Some(epoch), Some(duration)).unwrap();
+            start - epoch
+        };
+        assert_eq!(next_start(Duration::ZERO), Duration::ZERO);
+        assert_eq!(next_start(duration), duration);
+        assert_eq!(next_start(duration + LOOP_BOUNDARY_TOLERANCE), duration);
+        assert_eq!(
+            next_start(duration + LOOP_BOUNDARY_TOLERANCE + Duration::from_nanos(1)),
+            duration * 2,
+        );
+    }
+
+    #[test]
+    fn test_unreleased_pitch_bend_returns_at_seam() {
+        let mut h = Harness::new();
+        h.command(BloopCommand::StartRecording(0));
+        h.wait_until(100);
+        h.press(60, 100);
+        h.wait_until(300);
+        h.midi(
+            0,
+            MidiMessage::PitchBend {
+                bend: PitchBend::from_int(4000),
+            },
+        );
+        h.wait_until(900);
+        h.release(60);
+        h.wait_until(1000);
+        h.command(BloopCommand::StartPlaying(0));
+        h.wait_until(1999);
+        h.take_output();
+
+        // The bend never returns to center, so it ramps back at the seam before
+        // the next pass bends again.
+        h.wait_until(2299);
+        let bends = h
+            .take_midi()
+            .into_iter()
+            .filter_map(|(ms, _, message)| match message {
+                MidiMessage::PitchBend { bend } => Some((ms, bend.as_int())),
+                _ => None,
+            })
+            .collect_vec();
+        assert!(bends.len() > 1, "{bends:?}");
+        assert!(bends.iter().all(|&(ms, _)| ms >= 2000.0), "{bends:?}");
+        assert!(bends.windows(2).all(|w| w[0].1 > w[1].1), "{bends:?}");
+        assert_eq!(bends.last().map(|&(_, bend)| bend), Some(0));
+    }
+
+    #[test]
+    fn test_recorder_listening_across_phases() {
+        let mut h = Harness::new();
+        h.set_config(|c| c.record_over = RecordOverPolicy::Overdub);
+        // Returns the phase of the first bloop, and whether its recorder and
+        // passthrough are listening.
+        let state = |h: &Harness| {
+            let bloop = &h.engine.bloops[0];
+            let phase = bloop.ui_state(h.now).phase;
+            (
+                phase,
+                bloop.recorder.is_listening,
+                bloop.passthru.is_listening,
+            )
+        };
+        // Toggling passthrough never affects the recorder.
+        let toggle_listening = |h: &mut Harness| {
+            let (phase, recorder, passthru) = state(h);
+            h.command(BloopCommand::ToggleListening(0));
+            assert_eq!(state(h), (phase, recorder, !passthru));
+            h.command(BloopCommand::ToggleListening(0));
+            assert_eq!(state(h), (phase, recorder, passthru));
+        };
+
+        assert_eq!(state(&h), (BloopPhase::Idle, false, true));
+        toggle_listening(&mut h);
+
+        h.command(BloopCommand::StartRecording(0));
+        assert_eq!(state(&h), (BloopPhase::Recording, true, true));
+        toggle_listening(&mut h);
+        h.command(BloopCommand::ToggleRecordArm(0));
+        assert_eq!(state(&h), (BloopPhase::Recording, false, true));
+        h.command(BloopCommand::ToggleRecordArm(0));
+        assert_eq!(state(&h), (BloopPhase::Recording, true, true));
+        h.wait_until(100);
+        h.press(60, 100);
+        h.wait_until(300);
+        h.release(60);
+
+        h.wait_until(1000);
+        h.command(BloopCommand::StartPlaying(0));
+        assert_eq!(state(&h), (BloopPhase::Playing, false, true));
+        toggle_listening(&mut h);
+
+        // Overdubs record separately from the recorder.
+        h.wait_until(1500);
+        h.command(BloopCommand::StartRecording(0));
+        assert_eq!(state(&h), (BloopPhase::WaitingToRecord, false, true));
+        h.wait_until(2100);
+        assert_eq!(state(&h), (BloopPhase::Overdubbing, false, true));
+        toggle_listening(&mut h);
+        h.wait_until(3100);
+        assert_eq!(state(&h), (BloopPhase::Playing, false, true));
+
+        // Passthrough can still be turned off while the loop plays.
+        h.take_output();
+        h.command(BloopCommand::ToggleListening(0));
+        h.press(60, 100);
+        h.release(60);
+        assert_eq!(h.take_midi(), []);
+    }
+
+    #[test]
+    fn test_key_names_at_range_ends() {
+        let name = |middle_c: MiddleC, key: u8| middle_c.key_name(key.into());
+        assert_eq!(name(MiddleC::C4, 0), "C-1");
+        assert_eq!(name(MiddleC::C4, 60), "C4");
+        assert_eq!(name(MiddleC::C4, 127), "G9");
+        assert_eq!(name(MiddleC::C3, 0), "C-2");
+        assert_eq!(name(MiddleC::C3, 60), "C3");
+        assert_eq!(name(MiddleC::C3, 127), "G8");
+    }
+
+    #[test]
+    fn test_record_first_loops_together() {
+        let mut h = Harness::new();
+        h.set_config(|c| c.record_first_loops_together = true);
+        h.command(BloopCommand::StartRecording(0));
+        h.wait_until(100);
+        h.press(60, 100);
+        h.wait_until(200);
+        h.release(60);
+        h.wait_until(300);
+        h.command(BloopCommand::StartRecording(1));
+        h.wait_until(400);
+        h.press(62, 100);
+        h.wait_until(500);
+        h.release(62);
+
+        // Stopping the first bloop sets the tempo without stopping the other.
+        h.wait_until(1000);
+        h.command(BloopCommand::StartPlaying(0));
+        let state = h.engine.ui_state(h.now);
+        assert_eq!(state.duration, Some(Duration::from_millis(1000)));
+        assert_eq!(state.bloops[0].phase, BloopPhase::Playing);
+        assert_eq!(state.bloops[1].phase, BloopPhase::Recording);
+        h.wait_until(1500);
+        h.press(64, 100);
+        h.wait_until(1600);
+        h.release(64);
+
+        // The other bloop is rounded to a whole number of master loops.
+        h.wait_until(2400);
+        h.command(BloopCommand::StartPlaying(1));
+        let state = h.engine.ui_state(h.now);
+        assert_eq!(state.bloops[1].phase, BloopPhase::Playing);
+        assert_eq!(
+            state.bloops[1].loop_span,
+            Some((h.at(300), Duration::from_millis(2000))),
+        );
+        h.take_output();
+        h.wait_until(4499);
+        assert_eq!(
+            h.take_presses(),
+            [
+                (3100.0, 0, 60),
+                (3400.0, 0, 62),
+                (3500.0, 1, 64),
+                (4100.0, 0, 60),
+                (4400.0, 0, 62),
+                (4400.0, 1, 62),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_empty_loop_stops_playing() {
+        let mut h = Harness::new();
+        h.command(BloopCommand::StartRecording(0));
+        h.wait_until(1000);
+        h.command(BloopCommand::StartPlaying(0));
+        h.wait_until(3500);
+        let bloop = &h.engine.ui_state(h.now).bloops[0];
+        assert_eq!(bloop.phase, BloopPhase::Idle);
+        assert!(!bloop.is_playing_back);
+        assert_eq!(h.engine.do_events(h.now), None);
+        assert_eq!(h.take_midi(), []);
+
+        // The loop keeps going if silent loops aren't stopped.
+        let mut h = Harness::new();
+        h.set_config(|c| c.stop_silent_loops = false);
+        h.command(BloopCommand::StartRecording(0));
+        h.wait_until(1000);
+        h.command(BloopCommand::StartPlaying(0));
+        h.wait_until(3500);
+        let bloop = &h.engine.ui_state(h.now).bloops[0];
+        assert_eq!(bloop.phase, BloopPhase::Playing);
+        assert_eq!(h.engine.do_events(h.now), Some(h.at(4000)));
+        assert_eq!(h.take_midi(), []);
+    }
+
+    #[test]
+    fn test_record_on_two_channels() {
+        let mut h = Harness::new();
+        let mut config = h.engine.bloops[0].config.clone();
+        config.keep_input_channels = true;
+        h.command(BloopCommand::SetBloopConfig(0, config));
+        h.command(BloopCommand::StartRecording(0));
+        h.wait_until(100);
+        h.midi(0, note_on(60, 100));
+        h.midi(5, note_on(48, 90));
+        h.wait_until(300);
+        h.midi(0, note_on(60, 0));
+        h.midi(5, note_on(48, 0));
+        h.wait_until(1000);
+        h.command(BloopCommand::StartPlaying(0));
+        h.take_output();
+
+        // Each note is played back on the channel it was played on.
+        h.wait_until(1999);
+        assert_eq!(
+            h.take_midi(),
+            [
+                (1100.0, 0, note_on(60, 100)),
+                (1100.0, 5, note_on(48, 90)),
+                (1300.0, 0, note_on(60, 0)),
+                (1300.0, 5, note_on(48, 0)),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_zero_duration_loop_is_rejected() {
+        let mut h = Harness::new();
+        h.command(BloopCommand::StartRecording(0));
+        h.command(BloopCommand::StartPlaying(0));
+        h.wait_until(20);
+        h.command(BloopCommand::StartPlaying(0));
+        let state = h.engine.ui_state(h.now);
+        assert_eq!(state.epoch, None);
+        assert_eq!(state.duration, None);
+        assert_eq!(state.bloops[0].phase, BloopPhase::Recording);
+        assert_eq!(
+            next_loop_time(h.now, Some(h.start), Some(Duration::ZERO)),
+            None
+        );
+
+        // Recording carries on and can still be stopped normally.
+        h.wait_until(100);
+        h.press(60, 100);
+        h.wait_until(200);
+        h.release(60);
+        h.wait_until(1000);
+        h.command(BloopCommand::StartPlaying(0));
+        h.wait_until(1999);
+        assert_eq!(h.take_presses(), [(100.0, 0, 60), (1100.0, 0, 60)]);
+        let state = h.engine.ui_state(h.now);
+        assert_eq!(state.duration, Some(Duration::from_millis(1000)));
+        assert_eq!(state.bloops[0].phase, BloopPhase::Playing);
+    }
+
+    #[test]
+    fn test_scrub_keeps_loop() {
+        let mut h = Harness::new();
+        h.set_config(|c| c.measures_per_loop = 1);
+        h.record_loop(0, 1000, &[(60, 100, 200), (64, 600, 700)]);
+        h.wait_until(1050);
+        h.command(BloopCommand::Scrub(1.0));
+        h.wait_until(2999);
+        assert_eq!(
+            h.take_presses(),
+            [
+                (1350.0, 0, 64),
+                (1850.0, 0, 60),
+                (2350.0, 0, 64),
+                (2850.0, 0, 60),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_clear_all_allows_recording_again() {
+        let mut h = Harness::new();
+        h.set_config(|c| c.record_over = RecordOverPolicy::Refuse);
+        h.record_loop(0, 1000, &[(60, 100, 200)]);
+        h.wait_until(1500);
+        h.command(BloopCommand::ClearAll);
+        assert!(h.engine.bloops.iter().all(|b| !b.has_recording()));
+
+        h.command(BloopCommand::StartRecording(0));
+        h.wait_until(1600);
+        h.press(62, 100);
+        h.wait_until(1700);
+        h.release(62);
+        h.wait_until(2500);
+        h.command(BloopCommand::StartPlaying(0));
+        h.take_output();
+        h.wait_until(3499);
+        assert_eq!(h.take_presses(), [(2600.0, 0, 62)]);
+    }
+
+    #[test]
+    fn test_monitor_sends_at_engine_time() {
+        let mut h = Harness::new();
+        h.command(BloopCommand::ToggleMonitor);
+        h.wait_until(100);
+        h.press(60, 100);
+        // Turning the monitor off releases the key it is holding.
+        h.wait_until(300);
+        h.command(BloopCommand::ToggleMonitor);
+        let times = h
+            .take_output()
+            .into_iter()
+            .filter(|event| event.source == MONITOR_SOURCE)
+            .map(|event| event.time)
+            .collect_vec();
+        assert_eq!(times, [h.at(100), h.at(300)]);
+    }
+
+    #[test]
+    fn test_cancel_playing_releases_at_engine_time() {
+        let mut h = Harness::new();
+        h.record_loop(0, 1000, &[(60, 100, 900)]);
+        h.wait_until(1500);
+        h.take_output();
+        h.command(BloopCommand::CancelPlaying(0));
+        assert_eq!(h.take_midi(), [(1500.0, 0, note_on(60, 0))]);
+    }
+
+    #[test]
+    fn test_pause_releases_playback_keys() {
+        let mut h = Harness::new();
+        h.record_loop(0, 1000, &[(60, 100, 900)]);
+        h.wait_until(1500);
+        h.take_output();
+        h.command(BloopCommand::PauseTransport);
+        assert_eq!(h.take_midi(), [(1500.0, 0, note_on(60, 0))]);
+        h.wait_until(2000);
+        h.command(BloopCommand::ResumeTransport);
+        assert_eq!(h.take_midi(), [(2000.0, 0, note_on(60, 100))]);
+    }
+
+    #[test]
+    fn test_transpose_moves_sounding_keys_at_engine_time() {
+        let mut h = Harness::new();
+        h.record_loop(0, 1000, &[(60, 100, 900)]);
+        h.wait_until(1500);
+        h.take_output();
+        h.command(BloopCommand::SetMasterTranspose(2));
+        assert_eq!(
+            h.take_midi(),
+            [(1500.0, 0, note_on(60, 0)), (1500.0, 0, note_on(62, 100))],
+        );
+    }
+
+    #[test]
+    fn test_toggle_playback_at_engine_time() {
+        let mut h = Harness::new();
+        h.record_loop(0, 1000, &[(60, 100, 900)]);
+        h.wait_until(1500);
+        h.take_output();
+        h.command(BloopCommand::TogglePlayback(0));
+        assert_eq!(h.take_midi(), [(1500.0, 0, note_on(60, 0))]);
+        h.wait_until(1600);
+        h.command(BloopCommand::TogglePlayback(0));
+        assert_eq!(h.take_midi(), [(1600.0, 0, note_on(60, 100))]);
+    }
+}
@@ -115,7 +115,7 @@ pub trait IndexNewtype:
 
     /// Increments the index, or returns an error if it does not fit.
     fn next(self) -> Result<Self, IndexOutOfRange> {
-        Self::try_from_usize(self.to_usize().checked_add(1).unwrap_or(usize::MAX))
+        Self::try_from_usize(self.to_usize().saturating_add(1))
     }
 }
 
@@ -263,20 +263,20 @@ impl<I: IndexNewtype, E> GenericVec<I, E> {
         }
     }
     /// Returns an iterator over the values in the collection.
-    pub fn iter_values(&self) -> impl Iterator<Item = &E> + DoubleEndedIterator {
+    pub fn iter_values(&self) -> impl DoubleEndedIterator<Item = &E> {
         self.values.iter()
     }
     /// Returns a mutating iterator over the values in the collections.
-    pub fn iter_values_mut(&mut self) -> impl Iterator<Item = &mut E> + DoubleEndedIterator {
+    pub fn iter_values_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut E> {
         self.values.iter_mut()
     }
     /// Returns an iterator over the index-value pairs in the collection.
-    pub fn iter(&self) -> impl Iterator<Item = (I, &E)> + DoubleEndedIterator {
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (I, &E)> {
         self.iter_keys().zip(&self.values)
     }
     /// Returns a mutating iterator over the index-value pairs in the
     /// collection.
-    pub fn iter_mut(&mut self) -> impl Iterator<Item = (I, &mut E)> + DoubleEndedIterator {
+    pub fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = (I, &mut E)> {
         self.iter_keys().zip(&mut self.values)
     }
 
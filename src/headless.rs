@@ -0,0 +1,184 @@
+//! Runs the looper without a window, taking commands from stdin.
+//!
+//! The bloops are still controlled by the MIDI shortcuts handled in the bloops
+//! thread. Features that only exist in the GUI are unavailable: the time
+//! display and gain curve editing, the computer keyboard as a MIDI
+//! controller, MIDI-learn for key ranges, and all settings, which keep their
+//! defaults. Every MIDI input is enabled. On Unix the virtual output is
+//! enabled; hardware outputs can be enabled with `--output <name>`. With
+//! `--capture <path>`, output is written to a file instead of sent to MIDI.
+
+use std::fs::File;
+use std::io::{BufRead, LineWriter};
+use std::time::Instant;
+
+use blooprs::bloop::{spawn_bloops_thread, AlignGrid, BloopCommand, BloopPhase, UiState};
+use blooprs::output_sink::CaptureSink;
+use eyre::{bail, eyre, Context, Result};
+
+use crate::midi_io::AppMidiIO;
+
+const HELP: &str = "\
+Commands:
+  <n>            do key for bloop <n> (record, play, etc.)
+  next           record on the next empty bloop
+  listen <n>     toggle listening for bloop <n>
+  arm <n>        toggle record arm for bloop <n>
+  play <n>       toggle playback for bloop <n>
+  bypass <n>     toggle silencing bloop <n> while it keeps running
+  clear <n>      stop bloop <n> and discard its recording
+  punch <n>      replace bloop <n> with live input from now
+  punchout <n>   stop replacing bloop <n> with live input
+  restart <n>    restart bloop <n> from its beginning
+  align <n>      shift bloop <n> so its first note is on the beat
+  freeze <n>     stop bloop <n> and hold the notes it is playing
+  unfreeze <n>   release the notes held by freezing bloop <n>
+  master <n>     make bloop <n> the tempo master
+  master         let the first loop recorded set the tempo
+  clear          clear all bloops
+  monitor        toggle the monitor
+  pause          pause the transport
+  resume         resume the transport
+  downbeat       restart every loop from its beginning now
+  stop           stop every loop, keeping recordings and the tempo
+  scrub <n>      move the playhead by <n> beats (backward if negative)
+  tap            tap the reference tempo
+  transpose <n>  transpose every bloop by <n> semitones
+  panic          release every key and send All Notes Off
+  status         print the state of each bloop
+  help           print this message
+  quit           exit";
+
+/// Runs the looper without a window until `quit` is entered. If stdin is
+/// closed, keeps running until the process is killed.
+///
+/// If `capture_path` is given, output events are written to that file instead
+/// of being sent to MIDI outputs. See [`CaptureSink`] for the format.
+pub fn run(output_port_names: &[String], capture_path: Option<&str>) -> Result<()> {
+    let (bloop_commands_tx, ui_state_rx, midi_out_rx) = spawn_bloops_thread()?;
+    let output_connected_event = BloopCommand::OutputConnected;
+    let mut midi_io = match capture_path {
+        Some(path) => {
+            let file = File::create(path).wrap_err("error creating capture file")?;
+            let sink = Box::new(CaptureSink::new(LineWriter::new(file)));
+            AppMidiIO::with_output_sink(
+                bloop_commands_tx.clone(),
+                midi_out_rx,
+                output_connected_event,
+                sink,
+            )
+        }
+        None => AppMidiIO::new(
+            bloop_commands_tx.clone(),
+            midi_out_rx,
+            output_connected_event,
+        ),
+    };
+    for port_name in output_port_names {
+        midi_io.open_output_connection(port_name);
+    }
+
+    println!("Running headless. Type `help` for a list of commands.");
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        let state = ui_state_rx.recv()?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match parse_command(&words, &state) {
+            Ok(Some(command)) => command,
+            Ok(None) => match words.as_slice() {
+                [] => continue,
+                ["status"] => {
+                    print_status(&state);
+                    continue;
+                }
+                ["help"] => {
+                    println!("{HELP}");
+                    continue;
+                }
+                ["quit" | "exit"] => return Ok(()),
+                _ => {
+                    println!("Unknown command. Type `help` for a list of commands.");
+                    continue;
+                }
+            },
+            Err(e) => {
+                println!("{e}");
+                continue;
+            }
+        };
+        bloop_commands_tx.send(command).map_err(|e| eyre!("{e}"))?;
+    }
+
+    log::info!("stdin closed; running until killed");
+    loop {
+        std::thread::park();
+    }
+}
+
+/// Parses a command for the bloops thread, or returns `None` if `words` is
+/// not one.
+fn parse_command(words: &[&str], state: &UiState) -> Result<Option<BloopCommand>> {
+    let bloop_index = |s: &str| -> Result<usize> {
+        match s.parse::<usize>() {
+            Ok(n) if (1..=state.bloops.len()).contains(&n) => Ok(n - 1),
+            _ => bail!("Expected a bloop number from 1 to {}", state.bloops.len()),
+        }
+    };
+    Ok(Some(match words {
+        [n] if n.parse::<usize>().is_ok() => BloopCommand::DoKey(bloop_index(n)?),
+        ["listen", n] => BloopCommand::ToggleListening(bloop_index(n)?),
+        ["arm", n] => BloopCommand::ToggleRecordArm(bloop_index(n)?),
+        ["play", n] => BloopCommand::TogglePlayback(bloop_index(n)?),
+        ["bypass", n] => BloopCommand::ToggleBypass(bloop_index(n)?),
+        ["clear", n] => BloopCommand::Clear(bloop_index(n)?),
+        ["punch", n] => BloopCommand::PunchIn(bloop_index(n)?),
+        ["punchout", n] => BloopCommand::PunchOut(bloop_index(n)?),
+        ["restart", n] => BloopCommand::Retrigger(bloop_index(n)?),
+        ["align", n] => BloopCommand::Align(bloop_index(n)?, AlignGrid::Beat),
+        ["freeze", n] => BloopCommand::Freeze(bloop_index(n)?),
+        ["unfreeze", n] => BloopCommand::Unfreeze(bloop_index(n)?),
+        ["master", n] => BloopCommand::SetTempoMaster(bloop_index(n)?),
+        ["master"] => BloopCommand::ClearTempoMaster,
+        ["next"] => BloopCommand::RecordNext,
+        ["clear"] => BloopCommand::ClearAll,
+        ["monitor"] => BloopCommand::ToggleMonitor,
+        ["pause"] => BloopCommand::PauseTransport,
+        ["resume"] => BloopCommand::ResumeTransport,
+        ["stop"] => BloopCommand::StopAll,
+        ["downbeat"] => BloopCommand::ReanchorTransport(Instant::now()),
+        ["scrub", n] => match n.parse() {
+            Ok(beats) => BloopCommand::Scrub(beats),
+            Err(_) => bail!("Expected a number of beats"),
+        },
+        ["tap"] => BloopCommand::TapTempo(Instant::now()),
+        ["transpose", n] => match n.parse() {
+            Ok(semitones) => BloopCommand::SetMasterTranspose(semitones),
+            Err(_) => bail!("Expected a number of semitones"),
+        },
+        ["panic"] => BloopCommand::Panic,
+        _ => return Ok(None),
+    }))
+}
+
+fn print_status(state: &UiState) {
+    for (i, bloop) in state.bloops.iter().enumerate() {
+        let status = match bloop.phase {
+            BloopPhase::Idle => "empty",
+            BloopPhase::WaitingToRecord => "waiting to record",
+            BloopPhase::Recording => "recording",
+            BloopPhase::Playing => "playing",
+            BloopPhase::Overdubbing => "overdubbing",
+            BloopPhase::PunchingIn => "punching in",
+            BloopPhase::Stopped => "stopped",
+            BloopPhase::Paused => "paused",
+        };
+        let listening = match bloop.is_listening {
+            true => ", listening",
+            false => "",
+        };
+        println!("Bloop {}: {status}{listening}", i + 1);
+    }
+    if state.is_monitoring {
+        println!("Monitor: on");
+    }
+}
@@ -3,18 +3,33 @@ use midly::MidiMessage;
 
 pub enum KeyEffect {
     Press { key: u7, vel: u7 },
-    Release { key: u7 },
+    Release { key: u7, vel: Option<u7> },
     Aftertouch { key: u7 },
+    ChannelAftertouch,
     None,
 }
 impl From<MidiMessage> for KeyEffect {
     fn from(message: MidiMessage) -> Self {
         match message {
-            MidiMessage::NoteOff { key, vel: _ } => KeyEffect::Release { key },
-            MidiMessage::NoteOn { key, vel } if vel == 0 => KeyEffect::Release { key },
+            MidiMessage::NoteOff { key, vel } => KeyEffect::Release {
+                key,
+                vel: Some(vel),
+            },
+            MidiMessage::NoteOn { key, vel } if vel == 0 => KeyEffect::Release { key, vel: None },
             MidiMessage::NoteOn { key, vel } => KeyEffect::Press { key, vel },
             MidiMessage::Aftertouch { key, vel: _ } => KeyEffect::Aftertouch { key },
+            MidiMessage::ChannelAftertouch { vel: _ } => KeyEffect::ChannelAftertouch,
             _ => KeyEffect::None,
         }
     }
 }
+
+/// Returns a message that releases `key`, with unknown release velocity.
+///
+/// Internally, both note-off messages and note-on messages with zero velocity
+/// are releases (see [`KeyEffect`]), and this is the form used for releases
+/// that don't come from the MIDI input. Every release is converted to the
+/// configured wire format when it is sent.
+pub fn release(key: u7) -> MidiMessage {
+    MidiMessage::NoteOn { key, vel: 0.into() }
+}
@@ -14,9 +14,17 @@ impl ChannelSet {
     pub fn set_off(&mut self, channel: u4) {
         self.0 &= !(1 << channel.as_int())
     }
+    pub fn contains(self, channel: u4) -> bool {
+        self.0 & (1 << channel.as_int()) != 0
+    }
     pub fn any(self) -> bool {
         self.0 != 0
     }
+    pub fn iter_channels(self) -> impl Iterator<Item = u4> {
+        (0..16_u8)
+            .filter(move |&i| self.0 & (1 << i) != 0)
+            .map(u4::from)
+    }
 }
 
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
@@ -31,6 +39,10 @@ pub struct KeyStatus {
     pub recording: ChannelSet,
     /// Most recent velocity with which the key was pressed (for resumption).
     pub last_velocity: u7,
+    /// Channel on which the key was most recently pressed (for resumption).
+    pub last_channel: u4,
+    /// Most recent velocity with which the key was released, if known.
+    pub last_release_velocity: Option<u7>,
 }
 
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
@@ -53,7 +65,7 @@ impl KeySet {
             KeyEffect::Press { key, vel: _ } => {
                 self.insert(key);
             }
-            KeyEffect::Release { key } => {
+            KeyEffect::Release { key, .. } => {
                 self.remove(key);
             }
             _ => (),
@@ -71,6 +83,9 @@ impl KeySet {
         self.0[array_index] &= !bitmask;
         ret
     }
+    pub fn is_empty(self) -> bool {
+        self.0 == [0, 0]
+    }
     pub fn iter_keys(self) -> impl Iterator<Item = u7> {
         iter_u7().filter(move |&i| self.contains(i))
     }
@@ -162,7 +177,33 @@ impl<T> IndexMut<u7> for PerKey<T> {
     }
 }
 
-pub type IterU7 = std::iter::Map<std::ops::Range<u8>, fn(u8) -> u7>;
+pub type IterU7 = std::iter::Map<std::ops::RangeInclusive<u8>, fn(u8) -> u7>;
+/// Returns an iterator over all 128 MIDI keys, including 127.
 pub fn iter_u7() -> IterU7 {
-    (0..u7::max_value().as_int()).map(u7::from)
+    (0..=u7::max_value().as_int()).map(u7::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_127_is_included() {
+        let top = u7::max_value();
+        assert_eq!(iter_u7().count(), 128);
+        assert_eq!(iter_u7().next_back(), Some(top));
+
+        let mut keys = KeySet::new();
+        assert!(keys.insert(top));
+        assert!(keys.contains(top));
+        assert_eq!(keys.iter_keys().collect_vec(), [top]);
+        assert!(keys.remove(top));
+        assert!(keys.is_empty());
+
+        let per_key = PerKey::from_fn(|key| key.as_int());
+        assert_eq!(per_key[top], 127);
+        assert_eq!(per_key.iter().last(), Some((top, &127)));
+        let held: KeySet = per_key.iter().map(|(_, &key)| key == 127).collect();
+        assert_eq!(held.iter_keys().collect_vec(), [top]);
+    }
 }
@@ -0,0 +1,14 @@
+//! Engine of an opinionated MIDI looper.
+//!
+//! [`bloop::spawn_bloops_thread()`] starts the engine on its own thread and
+//! returns its channels: [`bloop::BloopCommand`]s go in, and
+//! [`bloop::UiState`] snapshots and [`bloop::OutputEvent`]s for the MIDI
+//! output come out. The engine has no dependency on any particular MIDI or GUI
+//! library; MIDI input is sent to it as [`bloop::BloopCommand::Midi`], and its
+//! output can be sent anywhere, such as to an [`output_sink::OutputSink`].
+
+pub mod bloop;
+pub mod key_effect;
+pub mod key_tracker;
+pub mod output_sink;
+mod rng;
@@ -0,0 +1,136 @@
+//! Log panel, which shows recent log messages in the window so that they can
+//! be seen without a terminal.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Instant;
+
+use eframe::egui;
+use itertools::Itertools;
+use parking_lot::Mutex;
+
+/// Maximum number of messages kept for the log panel. Older messages are
+/// discarded.
+const LOG_CAPACITY: usize = 500;
+
+/// Least severe level that is always kept for the log panel, even if
+/// `RUST_LOG` doesn't print it.
+const MIN_CAPTURED_LEVEL: log::Level = log::Level::Info;
+
+/// Log message kept for the log panel.
+struct LogLine {
+    /// Time since the logger was installed.
+    seconds: f32,
+    level: log::Level,
+    target: String,
+    message: String,
+}
+impl std::fmt::Display for LogLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            seconds,
+            level,
+            target,
+            message,
+        } = self;
+        write!(f, "{seconds:9.3} {level:5} {target}: {message}")
+    }
+}
+
+/// Recent log messages, shared between the logger and the UI.
+#[derive(Default)]
+pub struct LogBuffer {
+    lines: Mutex<VecDeque<LogLine>>,
+}
+impl LogBuffer {
+    /// Draws the recent messages, with buttons to copy and clear them.
+    pub fn ui(&self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui.button("Copy logs").clicked() {
+                let text = self.lines.lock().iter().join("\n");
+                ui.ctx().copy_text(text);
+            }
+            if ui.button("Clear").clicked() {
+                self.lines.lock().clear();
+            }
+        });
+        egui::ScrollArea::vertical()
+            .max_height(200.0)
+            .stick_to_bottom(true)
+            .auto_shrink([false, true])
+            .show(ui, |ui| {
+                for line in &*self.lines.lock() {
+                    let color = match line.level {
+                        log::Level::Error => egui::Color32::RED,
+                        log::Level::Warn => egui::Color32::YELLOW,
+                        log::Level::Info => ui.visuals().text_color(),
+                        log::Level::Debug | log::Level::Trace => egui::Color32::GRAY,
+                    };
+                    ui.label(
+                        egui::RichText::new(line.to_string())
+                            .monospace()
+                            .color(color),
+                    );
+                }
+            });
+    }
+}
+
+/// Logger that prints messages as `env_logger` would, and also keeps recent
+/// messages for the log panel.
+struct AppLogger {
+    env_logger: env_logger::Logger,
+    buffer: Arc<LogBuffer>,
+    start: Instant,
+}
+impl log::Log for AppLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= MIN_CAPTURED_LEVEL || self.env_logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.env_logger.matches(record) {
+            self.env_logger.log(record);
+        }
+        if record.level() <= MIN_CAPTURED_LEVEL || self.env_logger.matches(record) {
+            let mut message = String::new();
+            _ = write!(message, "{}", record.args());
+            let line = LogLine {
+                seconds: self.start.elapsed().as_secs_f32(),
+                level: record.level(),
+                target: record.target().to_owned(),
+                message,
+            };
+            let mut lines = self.buffer.lines.lock();
+            if lines.len() >= LOG_CAPACITY {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+    }
+
+    fn flush(&self) {
+        self.env_logger.flush();
+    }
+}
+
+/// Installs the logger, which is configured by `RUST_LOG` like `env_logger`,
+/// and returns the buffer of recent messages for the log panel.
+pub fn init() -> Arc<LogBuffer> {
+    let env_logger = env_logger::Builder::from_default_env().build();
+    let max_level = env_logger
+        .filter()
+        .max(MIN_CAPTURED_LEVEL.to_level_filter());
+    let buffer = Arc::new(LogBuffer::default());
+    let logger = AppLogger {
+        env_logger,
+        buffer: Arc::clone(&buffer),
+        start: Instant::now(),
+    };
+    match log::set_boxed_logger(Box::new(logger)) {
+        Ok(()) => log::set_max_level(max_level),
+        Err(e) => eprintln!("error installing logger: {e}"),
+    }
+    buffer
+}
@@ -1,47 +1,80 @@
 //! Opinionated MIDI looper.
 
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use bloop::{BloopCommand, UiState};
+use blooprs::bloop::{
+    gain_at, AlignGrid, ArpPattern, Arpeggiator, BloopCommand, BloopConfig, BloopPhase,
+    BloopUiState, ControllerShortcuts, DoKeyPolicy, Groove, HeldNotePolicy, InputChannelMap,
+    LaunchQuantize, MiddleC, NoteOffStyle, PlaybackLimit, RecordOverPolicy, RemoteAction,
+    RemoteControl, SwingSubdivision, UiState, VelocityCurve, VelocityMap, MAX_GAIN, MAX_TRANSPOSE,
+};
 use eframe::egui;
-use eframe::emath::NumExt;
-use eyre::{eyre, Context, Result};
+use eyre::{eyre, Context, OptionExt, Result};
+use log_panel::LogBuffer;
 use midi_io::AppMidiIO;
+use midly::live::LiveEvent;
+use midly::num::{u4, u7};
 
 #[macro_use]
 mod generic_vec;
-mod bloop;
-mod key_effect;
-mod key_tracker;
+mod headless;
+mod log_panel;
 mod midi_io;
+mod midi_monitor;
 
 /// Precision of the OS that can be trusted.
 pub const SLEEP_PRECISION: Duration = Duration::from_millis(100);
 
 pub const APP_NAME: &str = "Bloop.rs";
 
-/// Whether to send note-on events whenever a key is pressed, even if the
-/// corresponding note-off event might not be sent.
-pub const ALLOW_UNMATCHED_NOTE_ON: bool = true;
-
-/// Name for the application's virtual MIDI output.
+/// Default name for the application's virtual MIDI output.
 #[cfg(unix)]
 const BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME: &str = "Bloop.rs Virtual Output";
+/// Environment variable that overrides the name of the application's virtual
+/// MIDI output.
+#[cfg(unix)]
+const BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME_ENV_VAR: &str = "BLOOPRS_VIRTUAL_OUTPUT_NAME";
+
+/// Explanation shown on controls that are disabled because there is no MIDI
+/// output.
+const NO_OUTPUT_HINT: &str = "No MIDI output is connected, so nothing would be heard";
+
+/// Time within which Escape must be pressed twice to clear all bloops.
+const CLEAR_ALL_DOUBLE_PRESS_WINDOW: Duration = Duration::from_millis(500);
 
 fn main() -> Result<()> {
     // Initialize logging.
-    env_logger::builder().init();
+    let log_buffer = log_panel::init();
 
     // Initialize panic handler.
     // #[cfg(debug_assertions)]
     // color_eyre::install()?;
 
+    let mut args = std::env::args().skip(1);
+    let mut headless = false;
+    let mut output_port_names = vec![];
+    let mut capture_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--headless" => headless = true,
+            "--output" => output_port_names.push(args.next().ok_or_eyre("expected port name")?),
+            "--capture" => capture_path = Some(args.next().ok_or_eyre("expected file path")?),
+            _ => return Err(eyre!("unknown argument {arg:?}")),
+        }
+    }
+    if headless {
+        return headless::run(&output_port_names, capture_path.as_deref());
+    } else if capture_path.is_some() {
+        return Err(eyre!("--capture requires --headless"));
+    }
+
     // Run the GUI.
     let native_options = eframe::NativeOptions::default();
     eframe::run_native(
         "Bloop.rs",
         native_options,
-        Box::new(|cc| Ok(Box::new(App::new(cc).unwrap()))),
+        Box::new(|cc| Ok(Box::new(App::new(cc, log_buffer).unwrap()))),
     )
     .map_err(|e| eyre!("{e}"))
 }
@@ -51,13 +84,135 @@ struct App {
     bloop_commands_tx: flume::Sender<BloopCommand>,
 
     ui_state_rx: flume::Receiver<UiState>,
+    /// Most recent state received from the bloops thread.
+    ui_state: Option<UiState>,
+
+    /// Key range bound that is waiting for a key to be pressed, if any.
+    key_range_learn: Option<KeyRangeLearn>,
+    /// Horizontal zoom factor of the time display, where 1.0 fits one master
+    /// loop in the available width.
+    time_display_zoom: f32,
+    /// Computer keyboard used as a MIDI controller.
+    computer_keyboard: ComputerKeyboard,
+    /// Time at which Escape was last pressed, if a second press would clear
+    /// all bloops.
+    last_escape_press: Option<Instant>,
+    /// Recent log messages.
+    log_buffer: Arc<LogBuffer>,
+    /// Whether the log panel is shown.
+    show_log: bool,
+    /// Whether the MIDI monitor window is shown.
+    show_midi_monitor: bool,
+}
+
+/// Computer keyboard used as a MIDI controller, with one row of keys mapped
+/// to a chromatic octave like a piano.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ComputerKeyboard {
+    /// Number of octaves to shift notes up (or down, if negative).
+    octave: i8,
+    /// Velocity of notes played.
+    velocity: u7,
+    /// MIDI channel on which notes are played.
+    channel: u4,
+    /// Note sounded by each key that is held, so that it is released
+    /// correctly even if the octave changes in the meantime.
+    keys_held: Vec<(egui::Key, u7)>,
+}
+impl Default for ComputerKeyboard {
+    fn default() -> Self {
+        Self {
+            octave: 0,
+            velocity: 95.into(),
+            channel: 0.into(),
+            keys_held: vec![],
+        }
+    }
+}
+impl ComputerKeyboard {
+    /// Lowest and highest octave shift.
+    const OCTAVE_RANGE: std::ops::RangeInclusive<i8> = -4..=4;
+
+    /// Returns the key number played by a computer key with no octave shift.
+    fn base_key(key: egui::Key) -> Option<u8> {
+        Some(match key {
+            egui::Key::A => 57, // A
+            egui::Key::W => 58, // A#
+            egui::Key::S => 59, // B
+            // egui::Key::E => (),
+            egui::Key::D => 60, // C
+            egui::Key::R => 61, // C#
+            egui::Key::F => 62, // D
+            egui::Key::T => 63, // D#
+            egui::Key::G => 64, // E
+            // egui::Key::Y => (),
+            egui::Key::H => 65, // F
+            egui::Key::U => 66, // F#
+            egui::Key::J => 67, // G
+            egui::Key::I => 68, // G#
+            egui::Key::K => 69, // A
+            egui::Key::O => 70, // A#
+            egui::Key::L => 71, // B
+            // egui::Key::P => (),
+            egui::Key::Semicolon => 72, // C
+            _ => return None,
+        })
+    }
+
+    /// Handles a computer key being pressed or released, returning the MIDI
+    /// event to send, if any.
+    ///
+    /// `Z` and `X` shift the octave down and up.
+    fn key_event(&mut self, key: egui::Key, pressed: bool) -> Option<LiveEvent<'static>> {
+        let message = if pressed {
+            match key {
+                egui::Key::Z => self.octave = (self.octave - 1).max(*Self::OCTAVE_RANGE.start()),
+                egui::Key::X => self.octave = (self.octave + 1).min(*Self::OCTAVE_RANGE.end()),
+                _ => (),
+            }
+            let note = Self::base_key(key)? as i16 + 12 * self.octave as i16;
+            let note = u7::try_from(u8::try_from(note).ok()?)?;
+            self.keys_held.retain(|&(k, _)| k != key);
+            self.keys_held.push((key, note));
+            midly::MidiMessage::NoteOn {
+                key: note,
+                vel: self.velocity,
+            }
+        } else {
+            let i = self.keys_held.iter().position(|&(k, _)| k == key)?;
+            let (_, note) = self.keys_held.remove(i);
+            midly::MidiMessage::NoteOff {
+                key: note,
+                vel: self.velocity,
+            }
+        };
+        Some(LiveEvent::Midi {
+            channel: self.channel,
+            message,
+        })
+    }
+}
+
+/// Key range bound that is waiting for a key to be pressed on the MIDI input.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct KeyRangeLearn {
+    /// Index of the bloop.
+    bloop: usize,
+    /// Whether to set the highest key instead of the lowest.
+    is_high: bool,
+    /// Number of keys that had been pressed when learning started.
+    key_press_count: u64,
 }
 
 impl App {
-    fn new(_cc: &eframe::CreationContext<'_>) -> Result<Self> {
-        let (bloop_commands_tx, ui_state_rx, midi_out_rx) = crate::bloop::spawn_bloops_thread()?;
+    fn new(_cc: &eframe::CreationContext<'_>, log_buffer: Arc<LogBuffer>) -> Result<Self> {
+        let (bloop_commands_tx, ui_state_rx, midi_out_rx) = blooprs::bloop::spawn_bloops_thread()?;
 
-        let midi_io = AppMidiIO::new(bloop_commands_tx.clone(), midi_out_rx);
+        let midi_io = AppMidiIO::new(
+            bloop_commands_tx.clone(),
+            midi_out_rx,
+            BloopCommand::OutputConnected,
+        );
 
         Ok(App {
             bloop_commands_tx,
@@ -65,6 +220,15 @@ impl App {
             midi_io,
 
             ui_state_rx,
+            ui_state: None,
+
+            key_range_learn: None,
+            time_display_zoom: 1.0,
+            computer_keyboard: ComputerKeyboard::default(),
+            last_escape_press: None,
+            log_buffer,
+            show_log: false,
+            show_midi_monitor: false,
         })
     }
 
@@ -75,22 +239,59 @@ impl App {
     }
 
     fn do_bloop_key(&self, mods: egui::Modifiers, i: usize, state: &UiState) {
-        if let Some(bloop_state) = state.bloops.get(i) {
+        if i < state.bloops.len() {
             if mods.shift {
                 self.send(BloopCommand::ToggleListening(i));
-            } else {
+            } else if mods.alt {
+                self.send(BloopCommand::ToggleBypass(i));
+            } else if self.midi_io.has_output() {
                 self.send(BloopCommand::DoKey(i));
             }
         }
     }
 
-    fn latest_ui_state(&self) -> Result<UiState> {
-        if self.ui_state_rx.is_empty() {
-            self.send(BloopCommand::RefreshUi);
+    /// Starts or stops stuttering every bloop that is playing.
+    fn stutter(&self, start: bool, state: &UiState) {
+        let length = state.config.stutter_length(state.duration);
+        for (i, bloop) in state.bloops.iter().enumerate() {
+            if !start {
+                self.send(BloopCommand::StopStutter(i));
+            } else if bloop.is_playing_back {
+                self.send(BloopCommand::Stutter(i, length));
+            }
+        }
+    }
+
+    fn computer_keyboard_ui(&mut self, ui: &mut egui::Ui) {
+        let kb = &mut self.computer_keyboard;
+        ui.horizontal(|ui| {
+            ui.label("Computer keyboard:")
+                .on_hover_text("A–; play notes. Z and X shift the octave.");
+            ui.label("octave");
+            ui.add(egui::DragValue::new(&mut kb.octave).range(ComputerKeyboard::OCTAVE_RANGE));
+            ui.label("velocity");
+            let mut vel = kb.velocity.as_int();
+            ui.add(egui::DragValue::new(&mut vel).range(1..=127));
+            kb.velocity = vel.into();
+            ui.label("channel");
+            channel_ui(ui, &mut kb.channel);
+        });
+    }
+
+    fn latest_ui_state(&mut self) -> Result<UiState> {
+        let new_state = match &self.ui_state {
+            Some(_) => self.ui_state_rx.try_recv().ok(),
+            // Wait for the first state, so that the UI doesn't start empty.
+            None => Some(
+                self.ui_state_rx
+                    .recv_timeout(std::time::Duration::from_millis(100))
+                    .wrap_err("error fetching UI state")?,
+            ),
+        };
+        if let Some(state) = new_state {
+            self.ui_state = Some(state);
         }
-        self.ui_state_rx
-            .recv_timeout(std::time::Duration::from_millis(100))
-            .wrap_err("error fetching UI state")
+        self.ui_state.clone().ok_or_eyre("no UI state")
     }
 }
 
@@ -99,6 +300,19 @@ impl eframe::App for App {
         // Always refresh the UI.
         ctx.request_repaint();
 
+        if self.show_log {
+            egui::TopBottomPanel::bottom("log")
+                .resizable(true)
+                .show(ctx, |ui| self.log_buffer.ui(ui));
+        }
+
+        let midi_monitor = self.midi_io.midi_monitor();
+        midi_monitor.set_enabled(self.show_midi_monitor);
+        egui::Window::new("MIDI monitor")
+            .open(&mut self.show_midi_monitor)
+            .default_height(300.0)
+            .show(ctx, |ui| midi_monitor.ui(ui));
+
         egui::CentralPanel::default().show(ctx, |ui| {
             let state = match self.latest_ui_state() {
                 Ok(s) => s,
@@ -109,12 +323,29 @@ impl eframe::App for App {
                 }
             };
 
-            ui.heading("Bloop.rs");
+            for (i, bloop) in state.bloops.iter().enumerate() {
+                self.midi_io.set_track_output(i, bloop.config.track_output);
+            }
+
+            ui.horizontal(|ui| {
+                ui.heading("Bloop.rs");
+                ui.toggle_value(&mut self.show_log, "Log")
+                    .on_hover_text("Show recent log messages");
+                ui.toggle_value(&mut self.show_midi_monitor, "MIDI monitor")
+                    .on_hover_text("Show recent incoming and outgoing MIDI events");
+            });
 
             ui.group(|ui| self.midi_io.ui(ui));
+            // The engine keeps running so that settings can be changed, but
+            // recording and playback are disabled until there is somewhere
+            // for notes to go.
+            let has_output = self.midi_io.has_output();
 
-            draw_time_display(ui, &state);
+            if let Some(command) = draw_time_display(ui, &state, &mut self.time_display_zoom) {
+                self.send(command);
+            }
 
+            self.computer_keyboard_ui(ui);
             ui.input(|input| {
                 for ev in &input.events {
                     if let egui::Event::Key {
@@ -124,105 +355,588 @@ impl eframe::App for App {
                         ..
                     } = ev
                     {
-                        let key = match k {
-                            egui::Key::A => 57, // A
-                            egui::Key::W => 58, // A#
-                            egui::Key::S => 59, // B
-                            // egui::Key::E => (),
-                            egui::Key::D => 60, // C
-                            egui::Key::R => 61, // C#
-                            egui::Key::F => 62, // D
-                            egui::Key::T => 63, // D#
-                            egui::Key::G => 64, // E
-                            // egui::Key::Y => (),
-                            egui::Key::H => 65, // F
-                            egui::Key::U => 66, // F#
-                            egui::Key::J => 67, // G
-                            egui::Key::I => 68, // G#
-                            egui::Key::K => 69, // A
-                            egui::Key::O => 70, // A#
-                            egui::Key::L => 71, // B
-                            // egui::Key::P => (),
-                            egui::Key::Semicolon => 72, // C
-                            _ => continue,
+                        if *k == egui::Key::Q {
+                            self.stutter(*pressed, &state);
+                            continue;
                         }
-                        .into();
-
-                        let vel = 95.into();
+                        if let Some(event) = self.computer_keyboard.key_event(*k, *pressed) {
+                            self.send(BloopCommand::Midi(None, event));
+                        }
+                    }
+                }
+            });
 
-                        self.send(BloopCommand::Midi(midly::live::LiveEvent::Midi {
-                            channel: 0.into(),
-                            message: match pressed {
-                                true => midly::MidiMessage::NoteOn { key, vel },
-                                false => midly::MidiMessage::NoteOff { key, vel },
-                            },
-                        }));
+            let mut config = state.config.clone();
+            ui.horizontal(|ui| {
+                ui.label("Reference tempo:");
+                ui.add(
+                    egui::DragValue::new(&mut config.bpm)
+                        .range(20.0..=300.0)
+                        .suffix(" BPM"),
+                );
+                if ui.button("Tap").clicked() {
+                    self.send(BloopCommand::TapTempo(Instant::now()));
+                }
+                if let Some(stability) = state.tap_stability {
+                    ui.label(format!("{:.0}% steady", stability * 100.0))
+                        .on_hover_text("How consistent the time between recent taps is");
+                }
+                ui.add(
+                    egui::DragValue::new(&mut config.tap_outlier_percent)
+                        .range(5..=50)
+                        .prefix("ignore taps ±")
+                        .suffix("% off"),
+                )
+                .on_hover_text("Ignore taps this far from the typical time between taps");
+                ui.checkbox(&mut config.quantize_master_length, "Quantize master length");
+            });
+            ui.horizontal(|ui| {
+                let r = ui.selectable_label(state.is_monitoring, "Monitor");
+                if r.on_hover_text("Send live input straight to the output")
+                    .clicked()
+                {
+                    self.send(BloopCommand::ToggleMonitor);
+                }
+                ui.label("on channel");
+                channel_ui(ui, &mut config.monitor_channel);
+            });
+            ui.horizontal(|ui| {
+                ui.label("Transpose:");
+                let mut transpose = state.master_transpose;
+                ui.add(
+                    egui::DragValue::new(&mut transpose)
+                        .range(-MAX_TRANSPOSE..=MAX_TRANSPOSE)
+                        .suffix(" semitones"),
+                )
+                .on_hover_text("Transpose every bloop. Page Up and Page Down change the octave.");
+                if ui.button("−12").clicked() {
+                    transpose = transpose.saturating_sub(12);
+                }
+                if ui.button("+12").clicked() {
+                    transpose = transpose.saturating_add(12);
+                }
+                if transpose != state.master_transpose {
+                    self.send(BloopCommand::SetMasterTranspose(transpose));
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Mute channels:");
+                for i in 0..16_u8 {
+                    let channel = u4::from(i);
+                    let is_muted = config.muted_channels.contains(channel);
+                    let r = ui.selectable_label(is_muted, (i + 1).to_string());
+                    if r.on_hover_text("Silence every bloop that outputs on this channel")
+                        .clicked()
+                    {
+                        match is_muted {
+                            true => config.muted_channels.set_off(channel),
+                            false => config.muted_channels.set_on(channel),
+                        }
                     }
                 }
             });
+            ui.horizontal(|ui| {
+                ui.label("Time signature:");
+                ui.add(egui::DragValue::new(&mut config.beats_per_measure).range(1..=16));
+                ui.label("beats per measure,");
+                ui.add(egui::DragValue::new(&mut config.measures_per_loop).range(1..=64));
+                ui.label("measures per loop");
+            });
+            ui.horizontal(|ui| {
+                ui.label("Swing:");
+                groove_ui(ui, &mut config.groove);
+            });
+            ui.horizontal(|ui| {
+                ui.label("Stutter length:")
+                    .on_hover_text("Hold Q to repeat a slice of every playing loop");
+                for (beats, label) in [(1.0, "1"), (0.5, "1/2"), (0.25, "1/4"), (0.125, "1/8")] {
+                    ui.selectable_value(&mut config.stutter_beats, beats, label);
+                }
+                ui.label("beat");
+            });
+            ui.horizontal(|ui| {
+                ui.label("Release keys using:");
+                for (style, label) in [
+                    (NoteOffStyle::AsReceived, "As received"),
+                    (NoteOffStyle::NoteOnZeroVelocity, "Note-on (vel 0)"),
+                    (NoteOffStyle::NoteOff, "Note-off"),
+                ] {
+                    ui.selectable_value(&mut config.note_off_style, style, label);
+                }
+                if config.note_off_style == NoteOffStyle::NoteOff {
+                    let mut vel = config.release_velocity.as_int();
+                    ui.label("Default release velocity:");
+                    ui.add(egui::DragValue::new(&mut vel).range(0..=127));
+                    config.release_velocity = vel.into();
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Middle C is:");
+                for (middle_c, label) in [(MiddleC::C3, "C3"), (MiddleC::C4, "C4")] {
+                    ui.selectable_value(&mut config.middle_c, middle_c, label);
+                }
+            });
+            ui.checkbox(
+                &mut config.allow_unmatched_note_on,
+                "Retrigger keys that are already sounding",
+            )
+            .on_hover_text(
+                "Send note-on events for keys that are already held, \
+                 even though only one note-off event will be sent",
+            );
+            ui.horizontal(|ui| {
+                ui.label("Minimum note length:").on_hover_text(
+                    "Delay the release of notes played back so that none are too short",
+                );
+                ui.add(egui::Slider::new(&mut config.min_note_length_ms, 0..=100).suffix(" ms"));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Bloop keys:");
+                for (policy, label, hover_text) in [
+                    (
+                        DoKeyPolicy::RecordAndToggle,
+                        "Record and toggle",
+                        "Record on a bloop that isn't playing, or toggle playback",
+                    ),
+                    (
+                        DoKeyPolicy::ToggleOnly,
+                        "Toggle only",
+                        "Toggle playback, but never start recording",
+                    ),
+                    (
+                        DoKeyPolicy::RecordOnly,
+                        "Record only",
+                        "Record on a bloop that isn't playing, but never toggle playback",
+                    ),
+                ] {
+                    ui.selectable_value(&mut config.do_key_policy, policy, label)
+                        .on_hover_text(hover_text);
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Record over existing loop:");
+                for (policy, label) in [
+                    (RecordOverPolicy::Replace, "Replace"),
+                    (RecordOverPolicy::Overdub, "Overdub"),
+                    (RecordOverPolicy::Refuse, "Refuse"),
+                ] {
+                    ui.selectable_value(&mut config.record_over, policy, label);
+                }
+            });
+            ui.checkbox(&mut config.stop_silent_loops, "Stop silent loops")
+                .on_hover_text("Stop playing a loop that has no notes instead of repeating it");
+            ui.checkbox(
+                &mut config.record_first_loops_together,
+                "Record first loops together",
+            )
+            .on_hover_text(
+                "Before the tempo is set, recording on another bloop doesn't stop the first \
+                 recording. The first recording stopped sets the tempo.",
+            );
+            ui.checkbox(
+                &mut config.recompute_tempo_on_master_change,
+                "Recompute tempo on master change",
+            )
+            .on_hover_text(
+                "When the tempo master is cleared or changed, take the tempo from the new \
+                 master's loop instead of keeping it",
+            );
+            ui.horizontal(|ui| {
+                ui.label("Keys held when recording ends:");
+                for (policy, label, hover_text) in [
+                    (
+                        HeldNotePolicy::HoldThrough,
+                        "Hold through",
+                        "Keep sounding into the next pass until released",
+                    ),
+                    (
+                        HeldNotePolicy::CutAtBoundary,
+                        "Cut",
+                        "Release at the end of the loop",
+                    ),
+                ] {
+                    ui.selectable_value(&mut config.held_notes_at_loop_end, policy, label)
+                        .on_hover_text(hover_text);
+                }
+            });
+            ui.checkbox(
+                &mut config.clear_all_sends_all_notes_off,
+                "Send All Notes Off when clearing all loops",
+            )
+            .on_hover_text("Also release keys that are held down and notes from the monitor");
+            ui.checkbox(&mut config.soft_takeover, "Soft takeover for controllers")
+                .on_hover_text(
+                    "Ignore a knob until it reaches the value being played back, \
+                     and vice versa, so that the value never jumps",
+                );
+            ui.checkbox(&mut config.send_song_position, "Send Song Position Pointer")
+                .on_hover_text(
+                    "Tell external sequencers where the master loop is when the transport \
+                     starts, resumes, or is re-anchored",
+                );
+            ui.collapsing("Input velocity", |ui| {
+                velocity_map_ui(ui, &mut config.input_velocity);
+            });
+            ui.collapsing("Remote control", |ui| {
+                remote_control_ui(ui, &mut config.remote_control);
+            });
+            ui.collapsing("Controller shortcuts", |ui| {
+                shortcuts_ui(ui, &mut config.shortcuts, config.middle_c);
+            });
+            if config != state.config {
+                self.send(BloopCommand::SetGlobalConfig(config));
+            }
 
             ui.horizontal(|ui| {
                 ui.allocate_space(egui::Vec2::new(0.0, 30.0));
+                let is_paused = state.paused_at.is_some();
+                if ui.selectable_label(is_paused, "⏸ Pause").clicked() {
+                    self.send(match is_paused {
+                        true => BloopCommand::ResumeTransport,
+                        false => BloopCommand::PauseTransport,
+                    });
+                }
+                let can_reanchor = state.duration.is_some() && !is_paused;
+                let r = ui.add_enabled(can_reanchor, egui::Button::new("Downbeat").small());
+                if r.on_hover_text("Restart every loop from its beginning now, keeping recordings")
+                    .clicked()
+                {
+                    self.send(BloopCommand::ReanchorTransport(Instant::now()));
+                }
+                let r = ui.small_button("Panic");
+                if r.on_hover_text("Release every key and send All Notes Off on every channel")
+                    .clicked()
+                {
+                    self.send(BloopCommand::Panic);
+                }
+                let r = ui.small_button("Stop all");
+                if r.on_hover_text("Stop every loop, keeping recordings and the tempo (or press End)")
+                    .clicked()
+                {
+                    self.send(BloopCommand::StopAll);
+                }
+                let r = ui.small_button("Clear");
+                if r.on_hover_text("Clear all bloops (or press Escape twice)")
+                    .clicked()
+                {
+                    self.send(BloopCommand::ClearAll);
+                }
                 if let Some(duration) = state.duration {
-                    if ui.small_button("Clear").clicked() {
-                        self.send(BloopCommand::ClearAll);
-                    }
                     ui.label(format!("Loop duration: {duration:?}"));
+                    if state.config.quantize_master_length {
+                        let beat_duration = state.config.beat_duration();
+                        let beats = duration.as_secs_f32() / beat_duration.as_secs_f32();
+                        ui.label(format!(
+                            "({} beats at {} BPM)",
+                            beats.round(),
+                            state.config.bpm,
+                        ));
+                    }
                 }
             });
+            let input_port_names = self.midi_io.input_port_names();
+            let mut key_range_learn = self.key_range_learn;
             for (i, bloop) in state.bloops.iter().enumerate() {
                 ui.horizontal(|ui| {
                     let (_, max_button_rect) = ui.allocate_space(egui::vec2(150.0, 1.0));
 
+                    let countdown = recording_countdown(&state, bloop);
+
+                    let mut frame = egui::Frame::group(ui.style());
+                    if let Some((beats, pulse)) = countdown {
+                        if beats <= state.config.beats_per_measure {
+                            frame.stroke = egui::Stroke::new(
+                                1.0 + pulse,
+                                egui::Color32::RED.gamma_multiply(pulse),
+                            );
+                        }
+                    }
+
                     ui.vertical(|ui| {
-                        ui.group(|ui| {
-                            ui.strong(format!("Bloop #{i}"));
+                        frame.show(ui, |ui| {
+                            ui.horizontal(|ui| {
+                                ui.strong(format!("Bloop #{i}"));
+                                if state.tempo_master == Some(i) {
+                                    ui.label("⚓").on_hover_text("This loop set the tempo");
+                                }
+                                let is_assigned = state.assigned_tempo_master == Some(i);
+                                let r = ui
+                                    .selectable_label(is_assigned, "Master")
+                                    .on_hover_text("Only this bloop's recording sets the tempo");
+                                if r.clicked() {
+                                    self.send(if is_assigned {
+                                        BloopCommand::ClearTempoMaster
+                                    } else {
+                                        BloopCommand::SetTempoMaster(i)
+                                    });
+                                }
+                            });
                             ui.horizontal(|ui| {
                                 let r = ui.selectable_label(bloop.is_listening, "Listen");
                                 if r.clicked() {
                                     self.send(BloopCommand::ToggleListening(i));
                                 }
 
-                                let r = ui.selectable_label(bloop.is_playback_active, "Playback");
+                                let r = ui.selectable_label(bloop.is_record_armed, "Arm");
                                 if r.clicked() {
+                                    self.send(BloopCommand::ToggleRecordArm(i));
+                                }
+
+                                let r = ui.add_enabled(
+                                    has_output,
+                                    egui::SelectableLabel::new(
+                                        bloop.is_playback_active,
+                                        "Playback",
+                                    ),
+                                );
+                                if r.on_disabled_hover_text(NO_OUTPUT_HINT).clicked() {
                                     self.send(BloopCommand::TogglePlayback(i));
                                 }
+
+                                let r = ui.selectable_label(bloop.is_bypassed, "Bypass");
+                                if r.on_hover_text(
+                                    "Silence this bloop while it keeps running in time (Alt+number)",
+                                )
+                                .clicked()
+                                {
+                                    self.send(BloopCommand::ToggleBypass(i));
+                                }
+
+                                let is_enabled = has_output
+                                    && matches!(
+                                        bloop.phase,
+                                        BloopPhase::Playing | BloopPhase::Stopped
+                                    );
+                                let r = ui.add_enabled(is_enabled, egui::Button::new("Restart"));
+                                if r.on_hover_text("Play the loop from its beginning")
+                                    .clicked()
+                                {
+                                    self.send(BloopCommand::Retrigger(i));
+                                }
+
+                                let r =
+                                    ui.add_enabled(bloop.is_playing_back, egui::Button::new("×2"));
+                                if r.on_hover_text("Double loop length").clicked() {
+                                    self.send(BloopCommand::DoubleLength(i));
+                                }
+                                let r =
+                                    ui.add_enabled(bloop.is_playing_back, egui::Button::new("÷2"));
+                                if r.on_hover_text("Halve loop length").clicked() {
+                                    self.send(BloopCommand::HalveLength(i));
+                                }
+                                ui.add_enabled_ui(bloop.is_playing_back, |ui| {
+                                    ui.menu_button("Align", |ui| {
+                                        for (grid, label) in [
+                                            (AlignGrid::Beat, "To nearest beat"),
+                                            (AlignGrid::Bar, "To nearest bar"),
+                                        ] {
+                                            if ui.button(label).clicked() {
+                                                self.send(BloopCommand::Align(i, grid));
+                                                ui.close_menu();
+                                            }
+                                        }
+                                    })
+                                    .response
+                                    .on_hover_text(
+                                        "Shift the loop so that its first note falls on the grid",
+                                    );
+                                });
+                                if bloop.is_frozen {
+                                    let r = ui.button("Unfreeze");
+                                    if r.on_hover_text("Release the frozen chord").clicked() {
+                                        self.send(BloopCommand::Unfreeze(i));
+                                    }
+                                } else {
+                                    let is_enabled =
+                                        bloop.is_playing_back && bloop.is_playback_active;
+                                    let r = ui.add_enabled(is_enabled, egui::Button::new("Freeze"));
+                                    if r.on_hover_text(
+                                        "Stop the loop and hold the notes it is playing",
+                                    )
+                                    .clicked()
+                                    {
+                                        self.send(BloopCommand::Freeze(i));
+                                    }
+                                }
+
+                                let record_label = match state.config.record_over {
+                                    RecordOverPolicy::Replace => Some("Re-record"),
+                                    RecordOverPolicy::Overdub => Some("Overdub"),
+                                    RecordOverPolicy::Refuse => None,
+                                };
+                                if let Some(label) = record_label {
+                                    let is_enabled =
+                                        has_output && bloop.phase == BloopPhase::Playing;
+                                    let r = ui.add_enabled(is_enabled, egui::Button::new(label));
+                                    if r.clicked() {
+                                        self.send(BloopCommand::StartRecording(i));
+                                    }
+                                }
+
+                                if bloop.phase == BloopPhase::PunchingIn {
+                                    let r = ui.button("Punch out");
+                                    if r.on_hover_text("Go back to playing the loop").clicked() {
+                                        self.send(BloopCommand::PunchOut(i));
+                                    }
+                                } else {
+                                    let is_enabled = has_output
+                                        && bloop.is_record_armed
+                                        && bloop.phase == BloopPhase::Playing;
+                                    let r =
+                                        ui.add_enabled(is_enabled, egui::Button::new("Punch in"));
+                                    if r.on_hover_text(
+                                        "Replace the loop from here with what you play, \
+                                         until punching out",
+                                    )
+                                    .clicked()
+                                    {
+                                        self.send(BloopCommand::PunchIn(i));
+                                    }
+                                }
+
+                                if bloop.overdub_layer_count > 0 {
+                                    let n = bloop.overdub_layer_count;
+                                    let r = ui.button(format!("Undo layer ({n})"));
+                                    if r.on_hover_text("Remove the most recent overdub").clicked() {
+                                        self.send(BloopCommand::UndoLayer(i));
+                                    }
+                                    let r = ui.button("Commit");
+                                    if r.on_hover_text(
+                                        "Keep every overdub, so they can no longer be undone",
+                                    )
+                                    .clicked()
+                                    {
+                                        self.send(BloopCommand::CommitOverdub(i));
+                                    }
+                                }
+
+                                let is_active =
+                                    !matches!(bloop.phase, BloopPhase::Idle | BloopPhase::Stopped);
+                                let r = ui.add_enabled(is_active, egui::Button::new("Clear"));
+                                if r.on_hover_text("Discard this loop").clicked() {
+                                    self.send(BloopCommand::Clear(i));
+                                }
                             });
 
-                            let button = |ui: &mut egui::Ui, label| {
+                            let button = |ui: &mut egui::Ui, label, is_enabled| {
                                 let x_range = max_button_rect.x_range().shrink(10.0);
                                 let y_range = ui.min_rect().y_range().shrink(10.0);
                                 let rect = egui::Rect::from_x_y_ranges(x_range, y_range);
-                                ui.put(rect, egui::Button::new(label))
+                                ui.put(rect, |ui: &mut egui::Ui| {
+                                    ui.add_enabled(is_enabled, egui::Button::new(label))
+                                })
+                                .on_disabled_hover_text(NO_OUTPUT_HINT)
                             };
 
-                            if bloop.is_waiting_to_record {
-                                ui.label("Waiting until start of loop ...");
-                                ui.scope_builder(egui::UiBuilder::new().invisible(), |ui| {
-                                    button(ui, "")
-                                });
-                            } else if bloop.is_recording {
-                                ui.label("Recording ...");
-                                if state.duration.is_none() {
-                                    if button(ui, "Stop recording").clicked() {
+                            match bloop.phase {
+                                BloopPhase::WaitingToRecord => {
+                                    match countdown {
+                                        Some((1, _)) => ui.label("Recording in 1 beat"),
+                                        Some((beats, _)) => {
+                                            ui.label(format!("Recording in {beats} beats"))
+                                        }
+                                        None => ui.label("Waiting until start of loop ..."),
+                                    };
+                                    ui.scope_builder(egui::UiBuilder::new().invisible(), |ui| {
+                                        button(ui, "", true)
+                                    });
+                                }
+                                BloopPhase::Overdubbing => {
+                                    ui.label("Overdubbing ...");
+                                }
+                                BloopPhase::PunchingIn => {
+                                    ui.label("Punching in ...");
+                                }
+                                BloopPhase::Recording => {
+                                    ui.label("Recording ...");
+                                    if button(ui, "Stop recording", true).clicked() {
                                         self.send(BloopCommand::StartPlaying(i));
                                     }
                                 }
-                            } else if bloop.is_playing_back {
-                                ui.label("Playing");
-                                if button(ui, "Cancel playback").clicked() {
-                                    self.send(BloopCommand::CancelPlaying(i));
+                                BloopPhase::Paused => {
+                                    ui.label("Paused");
+                                }
+                                BloopPhase::Playing => {
+                                    ui.label(match bloop.playback_count {
+                                        0 | 1 => "Playing".to_owned(),
+                                        n => format!("Playing ({n} playbacks)"),
+                                    });
+                                    if bloop.wrapped_event_count > 0 {
+                                        let n = bloop.wrapped_event_count;
+                                        ui.label(format!("↺ {n}")).on_hover_text(format!(
+                                            "{n} events recorded past the end of the loop \
+                                             were moved to its start",
+                                        ));
+                                    }
+                                    if button(ui, "Cancel playback", true).clicked() {
+                                        self.send(BloopCommand::CancelPlaying(i));
+                                    }
+                                }
+                                BloopPhase::Idle => {
+                                    ui.label("Idle");
+                                    if button(ui, "Record", has_output).clicked() {
+                                        self.send(BloopCommand::StartRecording(i));
+                                    }
+                                }
+                                BloopPhase::Stopped
+                                    if state.config.record_over == RecordOverPolicy::Refuse =>
+                                {
+                                    ui.label("Stopped (clear to record again)");
+                                }
+                                BloopPhase::Stopped => {
+                                    ui.label("Stopped");
+                                    if button(ui, "Re-record", has_output).clicked() {
+                                        self.send(BloopCommand::StartRecording(i));
+                                    }
+                                }
+                            }
+
+                            if bloop.has_recording {
+                                let stats = bloop.stats;
+                                let mut text = format!(
+                                    "{} events, {} notes",
+                                    stats.event_count, stats.distinct_key_count,
+                                );
+                                if let Some(density) = stats.events_per_beat {
+                                    text += &format!(", {density:.1} per beat");
+                                }
+                                ui.weak(text);
+                                if stats.unmatched_release_count > 0 {
+                                    let n = stats.unmatched_release_count;
+                                    ui.colored_label(
+                                        egui::Color32::YELLOW,
+                                        format!("⚠ {n} unmatched note-offs"),
+                                    )
+                                    .on_hover_text("Releases of keys that the loop never pressed");
                                 }
-                            } else {
-                                ui.label("Idle");
-                                if button(ui, "Record").clicked() {
-                                    self.send(BloopCommand::StartRecording(i));
+                            }
+
+                            let mut config = bloop.config.clone();
+                            ui.push_id(i, |ui| {
+                                ui.collapsing("Settings", |ui| {
+                                    bloop_config_ui(ui, &mut config, &input_port_names);
+                                    key_range_ui(ui, i, &mut config, &state, &mut key_range_learn);
+                                })
+                            });
+                            if let Some(learn) = key_range_learn.filter(|l| l.bloop == i) {
+                                if state.key_press_count > learn.key_press_count {
+                                    if let Some(key) = state.last_key_pressed {
+                                        match learn.is_high {
+                                            true => config.key_range.1 = key,
+                                            false => config.key_range.0 = key,
+                                        }
+                                    }
+                                    key_range_learn = None;
                                 }
                             }
+                            if config != bloop.config {
+                                self.send(BloopCommand::SetBloopConfig(i, config));
+                            }
                         });
                     })
                 });
             }
+            self.key_range_learn = key_range_learn;
 
             ui.input(|input| {
                 if input.key_pressed(egui::Key::Num1) {
@@ -250,33 +964,287 @@ impl eframe::App for App {
                     self.do_bloop_key(input.modifiers, 7, &state);
                 }
 
+                if input.key_pressed(egui::Key::Num0) && has_output {
+                    self.send(BloopCommand::RecordNext);
+                }
+
+                if input.key_pressed(egui::Key::ArrowRight) {
+                    self.send(BloopCommand::Scrub(1.0));
+                }
+                if input.key_pressed(egui::Key::ArrowLeft) {
+                    self.send(BloopCommand::Scrub(-1.0));
+                }
+
+                if input.key_pressed(egui::Key::End) {
+                    self.send(BloopCommand::StopAll);
+                }
+
+                if input.key_pressed(egui::Key::PageUp) {
+                    let transpose = state.master_transpose.saturating_add(12);
+                    self.send(BloopCommand::SetMasterTranspose(transpose));
+                }
+                if input.key_pressed(egui::Key::PageDown) {
+                    let transpose = state.master_transpose.saturating_sub(12);
+                    self.send(BloopCommand::SetMasterTranspose(transpose));
+                }
+
+                // A single stray press of Escape shouldn't discard every
+                // recording.
                 if input.key_pressed(egui::Key::Escape) {
-                    self.send(BloopCommand::ClearAll);
+                    let now = Instant::now();
+                    match self.last_escape_press.take() {
+                        Some(t) if now - t <= CLEAR_ALL_DOUBLE_PRESS_WINDOW => {
+                            self.send(BloopCommand::ClearAll);
+                        }
+                        _ => self.last_escape_press = Some(now),
+                    }
                 }
             });
+            if self
+                .last_escape_press
+                .is_some_and(|t| t.elapsed() <= CLEAR_ALL_DOUBLE_PRESS_WINDOW)
+            {
+                ui.label("Press Escape again to clear all bloops");
+            }
+        });
+    }
+}
+
+/// Draws the time display, returning a command to send if a gain curve was
+/// edited.
+fn draw_time_display(ui: &mut egui::Ui, state: &UiState, zoom: &mut f32) -> Option<BloopCommand> {
+    const MASTER_LANE_HEIGHT: f32 = 60.0;
+    const BLOOP_LANE_HEIGHT: f32 = 24.0;
+    const MAX_HEIGHT: f32 = 200.0;
+
+    ui.horizontal(|ui| {
+        ui.label("Zoom:");
+        ui.add(
+            egui::Slider::new(zoom, 1.0..=16.0)
+                .logarithmic(true)
+                .suffix("×"),
+        );
+    });
 
-            self.send(BloopCommand::RefreshUi);
+    // Show enough master loops to fit the longest bloop.
+    let master_duration = state.duration.map(|d| d.as_secs_f32()).filter(|&d| d > 0.0);
+    let loop_count = state
+        .bloops
+        .iter()
+        .filter_map(|bloop| Some(bloop.loop_span?.1.as_secs_f32() / master_duration?))
+        .fold(1.0, f32::max)
+        .ceil() as u32;
+
+    // Freeze the playheads while the transport is paused.
+    let now = state.paused_at.unwrap_or_else(Instant::now);
+    // Returns the position of a playhead, measured in master loops.
+    let playhead = |start: Instant, loops: f32| {
+        let elapsed = now.saturating_duration_since(start).as_secs_f32() / master_duration?;
+        Some(elapsed % loops.max(f32::EPSILON))
+    };
+
+    let mut command = None;
+    let width = ui.available_width() * *zoom * loop_count as f32;
+    egui::ScrollArea::both()
+        .max_height(MAX_HEIGHT)
+        .auto_shrink([false, true])
+        .show(ui, |ui| {
+            ui.spacing_mut().item_spacing.y = 2.0;
+
+            let (rect, _) =
+                ui.allocate_exact_size(egui::vec2(width, MASTER_LANE_HEIGHT), egui::Sense::hover());
+            let master_playhead = state
+                .epoch
+                .and_then(|epoch| playhead(epoch, loop_count as f32))
+                .map(|x| (x, egui::Color32::LIGHT_BLUE));
+            draw_time_lane(
+                &ui.painter_at(rect),
+                rect,
+                state,
+                loop_count,
+                master_playhead,
+            );
+
+            for (i, bloop) in state.bloops.iter().enumerate() {
+                let (rect, r) = ui.allocate_exact_size(
+                    egui::vec2(width, BLOOP_LANE_HEIGHT),
+                    egui::Sense::click(),
+                );
+                let color = match bloop.phase.is_recording() {
+                    true => egui::Color32::RED,
+                    false => egui::Color32::LIGHT_GREEN,
+                };
+                let bloop_playhead = bloop
+                    .loop_span
+                    .and_then(|(start, duration)| {
+                        playhead(start, duration.as_secs_f32() / master_duration?)
+                    })
+                    .map(|x| (x, color));
+                draw_time_lane(
+                    &ui.painter_at(rect),
+                    rect,
+                    state,
+                    loop_count,
+                    bloop_playhead,
+                );
+
+                // Width of one pass of the loop, as a fraction of the lane.
+                let Some(pass_width) = bloop.loop_span.and_then(|(_, duration)| {
+                    Some(duration.as_secs_f32() / master_duration? / loop_count as f32)
+                }) else {
+                    continue;
+                };
+                if let Some((punch_start, (start, duration))) =
+                    bloop.punch_start.zip(bloop.loop_span)
+                {
+                    let pass_secs = duration.as_secs_f32().max(f32::EPSILON);
+                    let from =
+                        punch_start.saturating_duration_since(start).as_secs_f32() / pass_secs;
+                    let length =
+                        now.saturating_duration_since(punch_start).as_secs_f32() / pass_secs;
+                    draw_punch_region(
+                        &ui.painter_at(rect),
+                        rect,
+                        from.fract(),
+                        length.min(1.0),
+                        pass_width,
+                    );
+                }
+                draw_gain_curve(&ui.painter_at(rect), rect, &bloop.gain_curve, pass_width);
+                let r = r.on_hover_text(
+                    "Click to add a gain breakpoint; right-click to remove the nearest one",
+                );
+                if let Some(curve) = edit_gain_curve(&r, rect, &bloop.gain_curve, pass_width) {
+                    command = Some(BloopCommand::SetGainCurve(i, curve));
+                }
+            }
         });
+    command
+}
+
+/// Draws the region being replaced by a punch-in over each pass of a loop in a
+/// time lane, where `from` and `length` are fractions of one pass and
+/// `pass_width` is the width of one pass as a fraction of `rect`.
+fn draw_punch_region(
+    painter: &egui::Painter,
+    rect: egui::Rect,
+    from: f32,
+    length: f32,
+    pass_width: f32,
+) {
+    const COLOR: egui::Color32 = egui::Color32::from_rgba_premultiplied(96, 0, 0, 96);
+
+    if pass_width <= 0.0 {
+        return;
+    }
+    // Start one pass early, in case the region wraps around the end of the
+    // loop.
+    let mut pass_start = -pass_width;
+    while pass_start < 1.0 {
+        let x_range = egui::Rangef::new(
+            rect.lerp_inside(egui::vec2(pass_start + from * pass_width, 0.0))
+                .x,
+            rect.lerp_inside(egui::vec2(pass_start + (from + length) * pass_width, 0.0))
+                .x,
+        );
+        painter.rect_filled(
+            egui::Rect::from_x_y_ranges(x_range, rect.y_range()),
+            0.0,
+            COLOR,
+        );
+        pass_start += pass_width;
     }
 }
 
-fn draw_time_display(ui: &mut egui::Ui, state: &UiState) {
-    const MARGIN: f32 = 5.0;
+/// Draws a gain curve over each pass of a loop in a time lane, where
+/// `pass_width` is the width of one pass as a fraction of `rect`.
+fn draw_gain_curve(
+    painter: &egui::Painter,
+    rect: egui::Rect,
+    curve: &[(f32, f32)],
+    pass_width: f32,
+) {
+    const COLOR: egui::Color32 = egui::Color32::GOLD;
 
-    let measures_per_loop = 8;
-    let beats_per_measure = 4;
+    if curve.is_empty() || pass_width <= 0.0 {
+        return;
+    }
+    let point = |x: f32, gain: f32| rect.lerp_inside(egui::vec2(x, 1.0 - gain / MAX_GAIN));
+    let mut pass_start = 0.0;
+    while pass_start < 1.0 {
+        let mut points = vec![point(pass_start, gain_at(curve, 0.0))];
+        points.extend(
+            curve
+                .iter()
+                .map(|&(p, g)| point(pass_start + p * pass_width, g)),
+        );
+        points.push(point(pass_start + pass_width, gain_at(curve, 1.0)));
+        for &(p, g) in curve {
+            painter.circle_filled(point(pass_start + p * pass_width, g), 2.5, COLOR);
+        }
+        painter.add(egui::Shape::line(points, egui::Stroke::new(1.5, COLOR)));
+        pass_start += pass_width;
+    }
+}
 
-    let beat_count = measures_per_loop * beats_per_measure;
-    let beat_width = (ui.available_width().at_most(500.0) / beat_count as f32).floor();
-    let measure_width = beat_width * beats_per_measure as f32;
-    let total_width = measure_width * measures_per_loop as f32;
+/// Handles clicks on a time lane that edit a gain curve, returning the new
+/// curve if it changed. `pass_width` is the width of one pass of the loop as a
+/// fraction of `rect`.
+fn edit_gain_curve(
+    r: &egui::Response,
+    rect: egui::Rect,
+    curve: &[(f32, f32)],
+    pass_width: f32,
+) -> Option<Vec<(f32, f32)>> {
+    /// Maximum distance from the pointer to a breakpoint to remove it.
+    const REMOVE_RADIUS: f32 = 8.0;
+
+    if pass_width <= 0.0 {
+        return None;
+    }
+    let pos = r.interact_pointer_pos()?;
+    let x = (pos.x - rect.left()) / rect.width();
+    let phase = (x / pass_width).fract();
+    if r.clicked() {
+        let gain = (1.0 - (pos.y - rect.top()) / rect.height()) * MAX_GAIN;
+        let mut curve = curve.to_vec();
+        curve.push((phase, gain.clamp(0.0, MAX_GAIN)));
+        Some(curve)
+    } else if r.secondary_clicked() {
+        // Breakpoints repeat every pass, so measure distance within one pass.
+        let pass_px = pass_width * rect.width();
+        let (i, distance) = curve
+            .iter()
+            .map(|&(p, _)| {
+                let d = (p - phase).abs() * pass_px;
+                d.min(pass_px - d)
+            })
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(&b.1))?;
+        let mut curve = curve.to_vec();
+        (distance <= REMOVE_RADIUS).then(|| {
+            curve.remove(i);
+            curve
+        })
+    } else {
+        None
+    }
+}
 
-    let loop_display_size = egui::vec2(total_width + MARGIN * 2.0, 130.0);
+/// Draws beat and measure gridlines spanning `loop_count` master loops in
+/// `rect`, along with a playhead whose position is measured in master loops.
+fn draw_time_lane(
+    painter: &egui::Painter,
+    rect: egui::Rect,
+    state: &UiState,
+    loop_count: u32,
+    playhead: Option<(f32, egui::Color32)>,
+) {
+    /// Minimum distance between beat gridlines, below which they are hidden.
+    const MIN_BEAT_SPACING: f32 = 4.0;
 
-    let (r, painter) = ui.allocate_painter(loop_display_size, egui::Sense::click());
-    let rect = r.rect.expand(-MARGIN);
-    let vline = |p: &egui::Painter, x: f32, h: f32, color: egui::Color32| {
-        p.line_segment(
+    let vline = |x: f32, h: f32, color: egui::Color32| {
+        painter.line_segment(
             [
                 rect.lerp_inside(egui::vec2(x, 0.5 - 0.5 * h)),
                 rect.lerp_inside(egui::vec2(x, 0.5 + 0.5 * h)),
@@ -285,25 +1253,422 @@ fn draw_time_display(ui: &mut egui::Ui, state: &UiState) {
         )
     };
 
-    let measure_width = 1.0 / measures_per_loop as f32;
+    let measures_per_loop = state.config.measures_per_loop.max(1);
+    let beats_per_measure = state.config.beats_per_measure.max(1);
+    let measure_count = measures_per_loop * loop_count.max(1);
+    let measure_width = 1.0 / measure_count as f32;
     let beat_width = measure_width / beats_per_measure as f32;
+    let show_beats = beat_width * rect.width() >= MIN_BEAT_SPACING;
 
-    for i in 0..measures_per_loop {
+    for i in 0..measure_count {
         let measure_x = i as f32 * measure_width;
-        vline(&painter, measure_x, 1.0, egui::Color32::GRAY);
-        for j in 1..beats_per_measure {
-            vline(
-                &painter,
-                j as f32 * beat_width + measure_x,
-                0.75,
-                egui::Color32::DARK_GRAY,
-            );
+        let color = match i % measures_per_loop {
+            0 => egui::Color32::LIGHT_GRAY,
+            _ => egui::Color32::GRAY,
+        };
+        vline(measure_x, 1.0, color);
+        if show_beats {
+            for j in 1..beats_per_measure {
+                let x = j as f32 * beat_width + measure_x;
+                vline(x, 0.75, egui::Color32::DARK_GRAY);
+            }
         }
     }
-    vline(&painter, 1.0, 1.0, egui::Color32::GRAY);
+    vline(1.0, 1.0, egui::Color32::LIGHT_GRAY);
 
-    if let (Some(epoch), Some(duration)) = (state.epoch, state.duration) {
-        let x = ((Instant::now() - epoch).as_secs_f32() / duration.as_secs_f32()).fract();
-        vline(&painter, x, 1.0, egui::Color32::LIGHT_BLUE);
+    if let Some((x, color)) = playhead {
+        vline(x / loop_count.max(1) as f32, 1.0, color);
+    }
+}
+
+/// Returns the number of beats until a bloop starts recording, along with how
+/// close the next beat is (from 0.0 just after a beat to 1.0 just before one).
+fn recording_countdown(state: &UiState, bloop: &BloopUiState) -> Option<(u32, f32)> {
+    let remaining = bloop.time_until_recording?.as_secs_f32();
+    let beat_count = state.config.beats_per_loop();
+    let beat_duration = state.duration?.as_secs_f32() / beat_count as f32;
+    let beats = remaining / beat_duration;
+    Some((beats.ceil() as u32, 1.0 - beats.fract()))
+}
+
+/// Draws controls for editing a groove.
+fn groove_ui(ui: &mut egui::Ui, groove: &mut Groove) {
+    let mut percent = groove.swing * 100.0;
+    ui.add(egui::Slider::new(&mut percent, 50.0..=75.0).suffix("%"));
+    groove.swing = percent / 100.0;
+    ui.label("on");
+    let subdivision = &mut groove.subdivision;
+    ui.selectable_value(subdivision, SwingSubdivision::Eighth, "8ths");
+    ui.selectable_value(subdivision, SwingSubdivision::Sixteenth, "16ths");
+}
+
+/// Draws controls for editing the configuration of a single bloop.
+fn bloop_config_ui(ui: &mut egui::Ui, config: &mut BloopConfig, input_port_names: &[String]) {
+    ui.horizontal_wrapped(|ui| {
+        ui.label("Inputs:");
+        if ui
+            .selectable_label(config.input_ports.is_none(), "All")
+            .clicked()
+        {
+            config.input_ports = None;
+        }
+        for port_name in input_port_names {
+            let is_selected = config
+                .input_ports
+                .as_ref()
+                .is_some_and(|ports| ports.contains(port_name));
+            if ui.selectable_label(is_selected, port_name).clicked() {
+                let ports = config.input_ports.get_or_insert_with(Default::default);
+                if is_selected {
+                    ports.remove(port_name);
+                } else {
+                    ports.insert(port_name.clone());
+                }
+            }
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.label("Input channels:");
+        let map = &mut config.input_channel_map;
+        if ui
+            .selectable_label(*map == InputChannelMap::Identity, "Unchanged")
+            .clicked()
+        {
+            *map = InputChannelMap::Identity;
+        }
+        let is_merge = matches!(map, InputChannelMap::MergeTo(_));
+        if ui.selectable_label(is_merge, "Merge").clicked() && !is_merge {
+            *map = InputChannelMap::MergeTo(0.into());
+        }
+        let is_table = matches!(map, InputChannelMap::Table(_));
+        if ui.selectable_label(is_table, "Custom").clicked() && !is_table {
+            *map = InputChannelMap::Table(map.to_table());
+        }
+        if let InputChannelMap::MergeTo(channel) = map {
+            ui.label("into");
+            channel_ui(ui, channel);
+        }
+    });
+    if let InputChannelMap::Table(table) = &mut config.input_channel_map {
+        egui::Grid::new("input_channel_map").show(ui, |ui| {
+            for (i, channel) in table.iter_mut().enumerate() {
+                ui.label(format!("{} →", i + 1));
+                channel_ui(ui, channel);
+                if i % 4 == 3 {
+                    ui.end_row();
+                }
+            }
+        });
     }
+    ui.checkbox(&mut config.keep_input_channels, "Keep input channels")
+        .on_hover_text("Play each note on the channel it was recorded on, not the output channel");
+    ui.checkbox(&mut config.latch, "Latch")
+        .on_hover_text("Keep each key sounding after it is released, until it is pressed again");
+    ui.horizontal(|ui| {
+        let mask = &mut config.record_mask;
+        ui.label("Record:");
+        ui.checkbox(&mut mask.notes, "Notes");
+        ui.checkbox(&mut mask.controllers, "CC");
+        ui.checkbox(&mut mask.pitch_bend, "Pitch bend");
+        ui.checkbox(&mut mask.aftertouch, "Aftertouch");
+        ui.checkbox(&mut mask.program, "Program");
+        ui.checkbox(&mut config.mask_passthrough, "Filter live input too")
+            .on_hover_text("Only pass through the kinds of message that are recorded");
+    });
+    ui.horizontal(|ui| {
+        let mut is_overridden = config.groove.is_some();
+        ui.checkbox(&mut is_overridden, "Override swing");
+        match (is_overridden, &mut config.groove) {
+            (true, None) => config.groove = Some(Groove::default()),
+            (false, Some(_)) => config.groove = None,
+            (true, Some(groove)) => groove_ui(ui, groove),
+            (false, None) => (),
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.label("Live level:");
+        ui.add(egui::Slider::new(&mut config.live_level, 0.0..=1.0))
+            .on_hover_text("Velocity scale for live input passed through");
+        ui.label("Loop level:");
+        ui.add(egui::Slider::new(&mut config.loop_level, 0.0..=1.0))
+            .on_hover_text("Velocity scale for the loop played back");
+    });
+    ui.horizontal(|ui| {
+        ui.label("Humanize velocity: ±");
+        ui.add(egui::Slider::new(&mut config.velocity_humanization, 0..=32));
+    });
+    ui.horizontal(|ui| {
+        ui.label("Humanize timing: ±");
+        ui.add(egui::Slider::new(&mut config.timing_humanization_ms, 0..=50).suffix(" ms"));
+    });
+    ui.checkbox(&mut config.free_length, "Free length")
+        .on_hover_text("Record until stopped, then round to a whole number of master loops");
+    ui.add_enabled_ui(!config.free_length, |ui| {
+        ui.horizontal(|ui| {
+            let mut is_fixed = config.length_measures.is_some();
+            ui.checkbox(&mut is_fixed, "Fixed length");
+            match (is_fixed, &mut config.length_measures) {
+                (true, None) => config.length_measures = Some(4),
+                (false, Some(_)) => config.length_measures = None,
+                (true, Some(measures)) => {
+                    ui.add(egui::DragValue::new(measures).range(1..=64));
+                    ui.label("measures");
+                }
+                (false, None) => (),
+            }
+        });
+    });
+    ui.checkbox(&mut config.velocity_to_volume, "Velocity as volume (CC 7)")
+        .on_hover_text(
+            "Send the velocity of each note played back as a volume change, \
+             for synths that ignore velocity",
+        );
+    ui.horizontal(|ui| {
+        ui.label("Phase offset:");
+        ui.add(egui::Slider::new(&mut config.phase_offset, 0.0..=1.0));
+    });
+    ui.horizontal(|ui| {
+        let mut is_arpeggiating = config.arpeggiator.is_some();
+        ui.checkbox(&mut is_arpeggiating, "Arpeggiate");
+        match (is_arpeggiating, &mut config.arpeggiator) {
+            (true, None) => config.arpeggiator = Some(Arpeggiator::default()),
+            (false, Some(_)) => config.arpeggiator = None,
+            (true, Some(arpeggiator)) => {
+                let pattern = &mut arpeggiator.pattern;
+                ui.selectable_value(pattern, ArpPattern::Up, "Up");
+                ui.selectable_value(pattern, ArpPattern::Down, "Down");
+                ui.selectable_value(pattern, ArpPattern::UpDown, "Up-down");
+                ui.selectable_value(pattern, ArpPattern::Random, "Random");
+                ui.add(egui::DragValue::new(&mut arpeggiator.steps_per_beat).range(1..=8));
+                ui.label("per beat");
+            }
+            (false, None) => (),
+        }
+    });
+    ui.add_enabled(
+        cfg!(unix),
+        egui::Checkbox::new(&mut config.track_output, "Own MIDI port"),
+    )
+    .on_hover_text("Send to a separate virtual output, such as \"Bloop.rs Track 1\"")
+    .on_disabled_hover_text(
+        "Separate ports are only available on macOS and Linux. \
+         Give each bloop its own output channel instead.",
+    );
+    ui.horizontal(|ui| {
+        let mut is_chord = config.chord.is_some();
+        ui.checkbox(&mut is_chord, "Chord")
+            .on_hover_text("Play a chord for each key, with these intervals in semitones");
+        match (is_chord, &mut config.chord) {
+            (true, None) => config.chord = Some(vec![0, 4, 7]),
+            (false, Some(_)) => config.chord = None,
+            (true, Some(intervals)) => {
+                for interval in intervals.iter_mut() {
+                    ui.add(egui::DragValue::new(interval).range(-36..=36));
+                }
+                if ui.small_button("+").clicked() {
+                    let next = intervals.last().map_or(0, |&last| last.saturating_add(3));
+                    intervals.push(next.min(36));
+                }
+                if intervals.len() > 1 && ui.small_button("−").clicked() {
+                    intervals.pop();
+                }
+            }
+            (false, None) => (),
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.label("Restart on:");
+        let quantize = &mut config.launch_quantize;
+        ui.selectable_value(quantize, LaunchQuantize::Free, "Immediately");
+        ui.selectable_value(quantize, LaunchQuantize::Beat, "Beat");
+        ui.selectable_value(quantize, LaunchQuantize::Bar, "Bar");
+        ui.selectable_value(quantize, LaunchQuantize::Loop, "Loop");
+    });
+    ui.horizontal(|ui| {
+        let mut is_limited = config.max_playbacks.is_some();
+        ui.checkbox(&mut is_limited, "Limit simultaneous playbacks");
+        match (is_limited, &mut config.max_playbacks) {
+            (true, None) => config.max_playbacks = Some(1),
+            (false, Some(_)) => config.max_playbacks = None,
+            (true, Some(max)) => {
+                ui.add(egui::DragValue::new(max).range(1..=16));
+                let limit = &mut config.playback_limit;
+                ui.selectable_value(limit, PlaybackLimit::DropOldest, "Drop oldest");
+                ui.selectable_value(limit, PlaybackLimit::SkipNew, "Skip new");
+            }
+            (false, None) => (),
+        }
+    });
+    ui.horizontal(|ui| {
+        let mut send_program = config.program.is_some();
+        ui.checkbox(&mut send_program, "Program change on connect");
+        match (send_program, &mut config.program) {
+            (true, None) => config.program = Some(0.into()),
+            (false, Some(_)) => config.program = None,
+            (true, Some(program)) => {
+                let mut n = program.as_int() + 1;
+                ui.add(egui::DragValue::new(&mut n).range(1..=128));
+                *program = u7::from(n - 1);
+            }
+            (false, None) => (),
+        }
+    });
+}
+
+/// Draws controls for editing the key range of a bloop, including buttons that
+/// set a bound to the next key pressed on the MIDI input.
+fn key_range_ui(
+    ui: &mut egui::Ui,
+    i: usize,
+    config: &mut BloopConfig,
+    state: &UiState,
+    learn: &mut Option<KeyRangeLearn>,
+) {
+    ui.horizontal(|ui| {
+        ui.label("Keys:");
+        for is_high in [false, true] {
+            if is_high {
+                ui.label("to");
+            }
+            let key = match is_high {
+                true => &mut config.key_range.1,
+                false => &mut config.key_range.0,
+            };
+            key_ui(ui, key, state.config.middle_c);
+
+            let this_learn = KeyRangeLearn {
+                bloop: i,
+                is_high,
+                key_press_count: state.key_press_count,
+            };
+            let is_learning = learn.is_some_and(|l| l.bloop == i && l.is_high == is_high);
+            let r = ui.selectable_label(is_learning, "Learn");
+            if r.on_hover_text("Set to the next key pressed").clicked() {
+                *learn = (!is_learning).then_some(this_learn);
+            }
+        }
+    });
+}
+
+/// Draws controls for editing the keys on the MIDI input that control bloops.
+fn shortcuts_ui(ui: &mut egui::Ui, shortcuts: &mut ControllerShortcuts, middle_c: MiddleC) {
+    egui::Grid::new("controller_shortcuts").show(ui, |ui| {
+        let row = |ui: &mut egui::Ui, label: String, (channel, key): &mut (u4, u7)| {
+            ui.label(label);
+            ui.label("channel");
+            channel_ui(ui, channel);
+            ui.label("key");
+            key_ui(ui, key, middle_c);
+            ui.end_row();
+        };
+        row(ui, "Clear all".to_owned(), &mut shortcuts.clear_all);
+        for (i, shortcut) in shortcuts.do_key.iter_mut().enumerate() {
+            row(ui, format!("Bloop {} record/play", i + 1), shortcut);
+        }
+        for (i, shortcut) in shortcuts.toggle_listening.iter_mut().enumerate() {
+            row(ui, format!("Bloop {} listen", i + 1), shortcut);
+        }
+    });
+}
+
+/// Draws controls for mapping input velocities.
+fn velocity_map_ui(ui: &mut egui::Ui, map: &mut VelocityMap) {
+    ui.horizontal(|ui| {
+        let mut min = map.min.as_int();
+        let mut max = map.max.as_int();
+        ui.label("Softest:");
+        ui.add(egui::Slider::new(&mut min, 1..=127));
+        ui.label("Hardest:");
+        ui.add(egui::Slider::new(&mut max, 1..=127));
+        map.min = min.into();
+        map.max = max.into();
+    });
+    ui.horizontal(|ui| {
+        ui.label("Curve:");
+        for (curve, label, hover_text) in [
+            (
+                VelocityCurve::Linear,
+                "Linear",
+                "Keep the response of the controller",
+            ),
+            (VelocityCurve::Soft, "Soft", "Make soft notes louder"),
+            (VelocityCurve::Hard, "Hard", "Make soft notes quieter"),
+        ] {
+            ui.selectable_value(&mut map.curve, curve, label)
+                .on_hover_text(hover_text);
+        }
+    });
+}
+
+/// Draws controls for mapping MIDI messages to remote actions.
+fn remote_control_ui(ui: &mut egui::Ui, remote_control: &mut RemoteControl) {
+    ui.checkbox(&mut remote_control.mmc, "Respond to MIDI Machine Control")
+        .on_hover_text(
+            "Stop and Pause pause, Play resumes, Record records on the next bloop, \
+             and Reset panics",
+        );
+    egui::Grid::new("remote_controllers").show(ui, |ui| {
+        for action in RemoteAction::ALL {
+            ui.label(match action {
+                RemoteAction::PauseTransport => "Pause",
+                RemoteAction::ResumeTransport => "Resume",
+                RemoteAction::RecordNext => "Record next",
+                RemoteAction::Panic => "Panic",
+            });
+            let controller = remote_control.controllers.get(&action).copied();
+            let mut is_mapped = controller.is_some();
+            ui.checkbox(&mut is_mapped, "CC");
+            match (is_mapped, controller) {
+                (true, None) => {
+                    remote_control.controllers.insert(action, 0.into());
+                }
+                (false, Some(_)) => {
+                    remote_control.controllers.remove(&action);
+                }
+                (true, Some(controller)) => {
+                    let mut n = controller.as_int();
+                    ui.add(egui::DragValue::new(&mut n).range(0..=127));
+                    remote_control.controllers.insert(action, n.into());
+                }
+                (false, None) => (),
+            }
+            ui.end_row();
+        }
+        ui.label("Scrub").on_hover_text(
+            "Jog wheel that moves the playhead a beat per tick, sending 1 for forward and \
+             127 for backward",
+        );
+        let mut is_mapped = remote_control.jog_controller.is_some();
+        ui.checkbox(&mut is_mapped, "CC");
+        match (is_mapped, remote_control.jog_controller) {
+            (true, None) => remote_control.jog_controller = Some(0.into()),
+            (false, Some(_)) => remote_control.jog_controller = None,
+            (true, Some(controller)) => {
+                let mut n = controller.as_int();
+                ui.add(egui::DragValue::new(&mut n).range(0..=127));
+                remote_control.jog_controller = Some(n.into());
+            }
+            (false, None) => (),
+        }
+        ui.end_row();
+    });
+}
+
+/// Draws a control for selecting a key, shown by name.
+fn key_ui(ui: &mut egui::Ui, key: &mut u7, middle_c: MiddleC) {
+    let mut n = key.as_int();
+    ui.add(
+        egui::DragValue::new(&mut n)
+            .range(0..=127)
+            .custom_formatter(|n, _| middle_c.key_name(u7::from(n as u8))),
+    )
+    .on_hover_text(format!("Key {n}"));
+    *key = u7::from(n);
+}
+
+/// Draws a control for selecting a MIDI channel, numbered from 1 to 16.
+fn channel_ui(ui: &mut egui::Ui, channel: &mut u4) {
+    let mut n = channel.as_int() + 1;
+    ui.add(egui::DragValue::new(&mut n).range(1..=16));
+    *channel = u4::from(n - 1);
 }
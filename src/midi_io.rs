@@ -1,7 +1,12 @@
-use std::collections::HashSet;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use blooprs::bloop::OutputEvent;
+use blooprs::key_effect::{release, KeyEffect};
+use blooprs::key_tracker::{ChannelSet, PerKey};
+use blooprs::output_sink::OutputSink;
 use eframe::egui;
 use eyre::{eyre, OptionExt, Result};
 use itertools::Itertools;
@@ -9,9 +14,14 @@ use itertools::Itertools;
 use midir::os::unix::VirtualOutput;
 use midir::{MidiIO, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
 use midly::live::LiveEvent;
+use midly::num::{u4, u7};
+use midly::MidiMessage;
 use parking_lot::Mutex;
 
-use crate::{APP_NAME, BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME};
+use crate::midi_monitor::{Direction, MidiMonitor};
+use crate::APP_NAME;
+#[cfg(unix)]
+use crate::{BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME, BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME_ENV_VAR};
 
 /// MIDI input/output handlers for the app.
 pub struct AppMidiIO<T> {
@@ -20,19 +30,81 @@ pub struct AppMidiIO<T> {
     input_tx: flume::Sender<T>,
 
     output: MidiOutput,
-    output_port_name: Option<String>,
-    output_connection: Arc<Mutex<Option<MidiOutputConnection>>>,
+    /// Event to send on `input_tx` whenever an output connection opens.
+    output_connected_event: T,
+    /// Names of the enabled MIDI outputs, which all receive every event.
+    output_port_names: Vec<String>,
+    /// Name of the application's virtual MIDI output.
+    #[cfg(unix)]
+    virtual_output_name: String,
+    /// Open connections to the enabled MIDI outputs, in the same order as
+    /// `output_port_names`.
+    output_connections: Arc<Mutex<Vec<OutputConnection>>>,
+    /// Virtual outputs of bloops that send to their own port instead of the
+    /// enabled MIDI outputs, by bloop index.
+    track_connections: Arc<Mutex<HashMap<usize, OutputConnection>>>,
+    /// Time at which a dead output connection was last reopened.
+    last_reconnect_attempt: Option<Instant>,
+    /// Whether to sort output events by their intended send time.
+    sort_output: Arc<AtomicBool>,
+    /// Maximum number of controller changes per second to send for each
+    /// controller, or 0 to send all of them. See [`ControllerThinner`].
+    max_controller_rate: Arc<AtomicU32>,
+    /// How late output events are sent, compared to their intended time.
+    output_jitter: Arc<OutputJitter>,
+
+    feedback_detector: Arc<FeedbackDetector>,
+    /// Recent MIDI events, for debugging.
+    midi_monitor: Arc<MidiMonitor>,
 }
-impl<T: 'static + Send> AppMidiIO<T>
+impl<T: 'static + Send + Clone> AppMidiIO<T>
 where
-    for<'a> LiveEvent<'a>: Into<T>,
+    for<'a> (Arc<str>, LiveEvent<'a>): Into<T>,
 {
     pub fn new(
         midi_in_tx: flume::Sender<T>,
-        midi_out_rx: flume::Receiver<LiveEvent<'static>>,
+        midi_out_rx: flume::Receiver<OutputEvent>,
+        output_connected_event: T,
     ) -> Self {
-        let output_connection = Arc::new(Mutex::new(None));
-        let output_connection_ref = Arc::clone(&output_connection);
+        Self::new_internal(midi_in_tx, midi_out_rx, output_connected_event, None)
+    }
+    /// Returns MIDI handlers that send output events to `sink` instead of to
+    /// the MIDI outputs. MIDI input works as usual.
+    pub fn with_output_sink(
+        midi_in_tx: flume::Sender<T>,
+        midi_out_rx: flume::Receiver<OutputEvent>,
+        output_connected_event: T,
+        sink: Box<dyn OutputSink>,
+    ) -> Self {
+        Self::new_internal(midi_in_tx, midi_out_rx, output_connected_event, Some(sink))
+    }
+    fn new_internal(
+        midi_in_tx: flume::Sender<T>,
+        midi_out_rx: flume::Receiver<OutputEvent>,
+        output_connected_event: T,
+        sink: Option<Box<dyn OutputSink>>,
+    ) -> Self {
+        let output_connections = Arc::new(Mutex::new(vec![]));
+        let track_connections = Arc::new(Mutex::new(HashMap::new()));
+        let feedback_detector = Arc::new(FeedbackDetector::default());
+        let midi_monitor = Arc::new(MidiMonitor::default());
+        let sort_output = Arc::new(AtomicBool::new(false));
+        let sort_output_ref = Arc::clone(&sort_output);
+        let max_controller_rate = Arc::new(AtomicU32::new(0));
+        let max_controller_rate_ref = Arc::clone(&max_controller_rate);
+        let output_jitter = Arc::new(OutputJitter::default());
+        let output_jitter_ref = Arc::clone(&output_jitter);
+        #[cfg(unix)]
+        let is_capturing = sink.is_some();
+        let mut sink = sink.unwrap_or_else(|| {
+            Box::new(MidiOutputSink {
+                output_connections: Arc::clone(&output_connections),
+                track_connections: Arc::clone(&track_connections),
+                feedback_detector: Arc::clone(&feedback_detector),
+                midi_monitor: Arc::clone(&midi_monitor),
+                buffer: vec![],
+            })
+        });
 
         let mut ret = Self {
             input: new_midi_input(),
@@ -40,92 +112,195 @@ where
             input_tx: midi_in_tx,
 
             output: new_midi_output(),
-            output_port_name: None,
-            output_connection,
+            output_connected_event,
+            output_port_names: vec![],
+            #[cfg(unix)]
+            virtual_output_name: virtual_output_name(),
+            output_connections,
+            track_connections,
+            last_reconnect_attempt: None,
+            sort_output,
+            max_controller_rate,
+            output_jitter,
+
+            feedback_detector,
+            midi_monitor,
         };
 
+        // Expose the virtual output by default, unless output is captured.
+        #[cfg(unix)]
+        {
+            if !is_capturing {
+                ret.open_output_connection(&ret.virtual_output_name.clone());
+            }
+        }
+
         ret.refresh_midi_input_connections();
-        ret.refresh_midi_output_connections();
 
         // Spawn output thread.
         std::thread::spawn(move || {
-            let mut buffer = vec![];
-            for event in midi_out_rx {
-                buffer.clear();
-                if let Err(e) = event.write(&mut buffer) {
-                    log::error!("Error writing MIDI event to buffer: {e}");
+            let mut batch = vec![];
+            let mut thinner = ControllerThinner::default();
+            let mut jitter = JitterAccumulator::new(Instant::now());
+            loop {
+                // Wake up in time to send controller changes held back by
+                // thinning.
+                let first = match thinner.next_flush_time() {
+                    Some(deadline) => match midi_out_rx.recv_deadline(deadline) {
+                        Ok(event) => Some(event),
+                        Err(flume::RecvTimeoutError::Timeout) => None,
+                        Err(flume::RecvTimeoutError::Disconnected) => break,
+                    },
+                    None => match midi_out_rx.recv() {
+                        Ok(event) => Some(event),
+                        Err(flume::RecvError::Disconnected) => break,
+                    },
+                };
+                for event in thinner.take_due(Instant::now()) {
+                    sink.send(&event);
+                }
+                let Some(first) = first else {
                     continue;
+                };
+                batch.push(first);
+                if sort_output_ref.load(Ordering::Relaxed) {
+                    // Wait briefly for events from other bloops that were
+                    // intended to be sent at about the same time, then send
+                    // all of them in order.
+                    let deadline = Instant::now() + OUTPUT_SORT_WINDOW;
+                    while let Ok(event) = midi_out_rx.recv_deadline(deadline) {
+                        batch.push(event);
+                    }
+                    // This sort is stable, so events from the same bloop at
+                    // the same time stay in the order they were sent.
+                    batch.sort_by_key(|event| (event.time, event.source));
                 }
-                let mut out_conn_guard = output_connection_ref.lock();
-                if let Some(out_conn) = &mut *out_conn_guard {
-                    if let Err(e) = out_conn.send(&buffer) {
-                        log::error!("Error sending MIDI event to output: {e}");
-                        continue;
+                let max_rate = max_controller_rate_ref.load(Ordering::Relaxed);
+                for event in batch.drain(..) {
+                    if max_rate == 0 || thinner.should_send(&event, max_rate, Instant::now()) {
+                        sink.send(&event);
+                        jitter.record(event.time, Instant::now(), &output_jitter_ref);
                     }
                 }
             }
-            drop(output_connection_ref);
         });
 
         ret
     }
 
     pub fn refresh_midi_input_connections(&mut self) {
-        let previously_disabled_ports: HashSet<String> =
-            std::mem::take(&mut self.input_connections)
-                .into_iter()
-                .filter(|port| !port.is_enabled())
-                .map(|port| port.name)
-                .collect();
+        let previous_connections = std::mem::take(&mut self.input_connections);
+        let previously_disabled_ports: HashSet<&str> = previous_connections
+            .iter()
+            .filter(|port| !port.is_enabled())
+            .map(|port| port.name.as_str())
+            .collect();
+        let previous_ignores: HashMap<&str, InputIgnore> = previous_connections
+            .iter()
+            .map(|port| (port.name.as_str(), port.ignore))
+            .collect();
 
         self.input = new_midi_input();
 
         for port_name in port_names(&self.input) {
-            if port_name == crate::BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME {
+            #[cfg(unix)]
+            if port_name == self.virtual_output_name || port_name.starts_with(TRACK_OUTPUT_PREFIX) {
                 continue;
             }
-            let is_enabled = !previously_disabled_ports.contains(&port_name);
-            match self.open_midi_input_connection(&port_name, is_enabled) {
+            let is_enabled = !previously_disabled_ports.contains(port_name.as_str());
+            let ignore = previous_ignores
+                .get(port_name.as_str())
+                .copied()
+                .unwrap_or_default();
+            match self.open_midi_input_connection(&port_name, is_enabled, ignore) {
                 Ok(midi_input_connection) => self.input_connections.push(midi_input_connection),
                 Err(e) => log::error!("error opening MIDI input connection: {e}"),
             }
         }
     }
-    pub fn refresh_midi_output_connections(&mut self) {
-        self.output = new_midi_output();
-
-        #[cfg(unix)]
-        if self.output_port_name.is_none() {
-            self.output_port_name = Some(BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME.to_owned());
-        }
-
-        if let Some(output_port_name) = self.output_port_name.take() {
-            self.open_output_connection(&output_port_name);
+    /// Reopens a MIDI input connection so that it ignores different messages.
+    fn set_input_ignore(&mut self, i: usize, ignore: InputIgnore) {
+        // Some platforms can't open a port twice, so close it first.
+        let old = self.input_connections.remove(i);
+        let held_keys = old.take_held_keys_state();
+        let (name, is_enabled) = (old.name.clone(), old.is_enabled());
+        drop(old);
+        match self.open_midi_input_connection(&name, is_enabled, ignore) {
+            Ok(conn) => {
+                *conn.held_keys.lock() = held_keys;
+                self.input_connections.insert(i, conn);
+            }
+            Err(e) => log::error!("error reopening MIDI input connection {name:?}: {e}"),
         }
     }
+    /// Rescans the available MIDI outputs, and retries any that have
+    /// disconnected.
+    ///
+    /// Connections that are working are left open, so that refreshing never
+    /// makes the virtual output disappear from under a connected application.
+    pub fn refresh_midi_output_ports(&mut self) {
+        self.output = new_midi_output();
+        self.last_reconnect_attempt = None;
+        self.reconnect_dead_outputs();
+    }
     fn open_midi_input_connection(
         &self,
         port_name: &str,
         is_enabled: bool,
+        ignore: InputIgnore,
     ) -> Result<MidiInputConnectionHandle> {
-        let midi_input = MidiInput::new(&format!("Bloop.rs {port_name:?} Input"))?;
+        let mut midi_input = MidiInput::new(&format!("Bloop.rs {port_name:?} Input"))?;
+        midi_input.ignore(ignore.flags());
         let port = find_port(&midi_input, port_name)?;
 
         let is_enabled = Arc::new(AtomicBool::new(is_enabled));
         let is_enabled_ref = Arc::clone(&is_enabled);
 
+        let stats = Arc::new(InputStats::default());
+        let stats_ref = Arc::clone(&stats);
+        let held_keys = Arc::new(Mutex::new(PerKey::<ChannelSet>::default()));
+        let held_keys_ref = Arc::clone(&held_keys);
+        let mut parser = InputParser::default();
+
         let midi_input_tx = self.input_tx.clone();
+        let port_name_ref: Arc<str> = port_name.into();
+        let feedback_detector = Arc::clone(&self.feedback_detector);
+        let midi_monitor = Arc::clone(&self.midi_monitor);
 
         let _connection = midi_input
             .connect(
                 &port,
                 "blooprs-in",
                 move |_timestamp, message: &[u8], ()| {
+                    // Hold the lock while forwarding, so that disabling the
+                    // input can't miss a key that is being pressed.
+                    let mut held_keys = held_keys_ref.lock();
                     if is_enabled_ref.load(std::sync::atomic::Ordering::Relaxed) {
-                        match midly::live::LiveEvent::parse(message) {
-                            Ok(event) => _ = midi_input_tx.send(event.into()),
-                            Err(e) => log::error!("unable to parse MIDI message {message:x?}: {e}"),
+                        if feedback_detector.is_echo(message) {
+                            return;
                         }
+                        stats_ref.message_count.fetch_add(1, Ordering::Relaxed);
+                        parser.feed(message, |result| match result {
+                            Ok(event) => {
+                                midi_monitor.record(Direction::In, &port_name_ref, &event);
+                                if let LiveEvent::Midi { channel, message } = event {
+                                    match KeyEffect::from(message) {
+                                        KeyEffect::Press { key, .. } => {
+                                            held_keys[key].set_on(channel);
+                                        }
+                                        KeyEffect::Release { key, .. } => {
+                                            held_keys[key].set_off(channel);
+                                        }
+                                        _ => (),
+                                    }
+                                }
+                                _ = midi_input_tx.send((Arc::clone(&port_name_ref), event).into());
+                            }
+                            Err(e) => {
+                                stats_ref.parse_error_count.fetch_add(1, Ordering::Relaxed);
+                                log::error!("unable to parse MIDI message {message:x?}: {e}");
+                            }
+                        });
                     }
                 },
                 (),
@@ -135,28 +310,87 @@ where
         Ok(MidiInputConnectionHandle {
             name: port_name.to_owned(),
             is_enabled,
+            ignore,
+            stats,
+            held_keys,
             _connection,
         })
     }
+    /// Opens a connection to a MIDI output, in addition to any that are
+    /// already open.
     pub fn open_output_connection(&mut self, port_name: &str) {
+        if self.output_port_names.iter().any(|name| name == port_name) {
+            return; // Never send to the same output twice.
+        }
         match self.open_output_connection_internal(port_name) {
-            Ok(out_conn) => {
-                self.output_port_name = Some(port_name.to_owned());
-                *self.output_connection.lock() = Some(out_conn);
+            Ok(conn) => {
+                self.output_port_names.push(port_name.to_owned());
+                self.output_connections
+                    .lock()
+                    .push(OutputConnection::new(conn));
+                _ = self.input_tx.send(self.output_connected_event.clone());
             }
-            Err(e) => {
-                self.output_port_name = None;
-                *self.output_connection.lock() = None;
-                log::error!("error opening MIDI output connection: {e}");
+            Err(e) => log::error!("error opening MIDI output connection: {e}"),
+        }
+    }
+    /// Reopens output connections that the output thread has marked dead,
+    /// at most once every [`OUTPUT_RECONNECT_INTERVAL`].
+    fn reconnect_dead_outputs(&mut self) {
+        if self
+            .last_reconnect_attempt
+            .is_some_and(|t| t.elapsed() < OUTPUT_RECONNECT_INTERVAL)
+        {
+            return;
+        }
+        let dead_port_names = {
+            let out_conns = self.output_connections.lock();
+            std::iter::zip(&self.output_port_names, &*out_conns)
+                .filter(|(_, out_conn)| out_conn.is_dead)
+                .map(|(name, _)| name.clone())
+                .collect_vec()
+        };
+        if dead_port_names.is_empty() {
+            return;
+        }
+        self.last_reconnect_attempt = Some(Instant::now());
+
+        self.output = new_midi_output();
+        let mut any_reconnected = false;
+        for port_name in dead_port_names {
+            match self.open_output_connection_internal(&port_name) {
+                Ok(conn) => {
+                    let Some(i) = self.output_port_names.iter().position(|n| *n == port_name)
+                    else {
+                        continue;
+                    };
+                    log::info!("reconnected to MIDI output {port_name:?}");
+                    self.output_connections.lock()[i].reconnect(conn);
+                    any_reconnected = true;
+                }
+                Err(e) => log::warn!("error reconnecting to MIDI output {port_name:?}: {e}"),
             }
         }
+        if any_reconnected {
+            _ = self.input_tx.send(self.output_connected_event.clone());
+        }
     }
-    fn open_output_connection_internal(&mut self, port_name: &str) -> Result<MidiOutputConnection> {
+    /// Closes the connection to a MIDI output, if it is open.
+    pub fn close_output_connection(&mut self, port_name: &str) {
+        if let Some(i) = self
+            .output_port_names
+            .iter()
+            .position(|name| name == port_name)
+        {
+            self.output_port_names.remove(i);
+            self.output_connections.lock().remove(i);
+        }
+    }
+    fn open_output_connection_internal(&self, port_name: &str) -> Result<MidiOutputConnection> {
         let midi_output = new_midi_output();
 
         #[cfg(unix)]
-        let out_conn = if port_name == BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME {
-            midi_output.create_virtual(BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME)
+        let out_conn = if port_name == self.virtual_output_name {
+            midi_output.create_virtual(&self.virtual_output_name)
         } else {
             let port = find_port(&midi_output, port_name)?;
             midi_output.connect(&port, "blooprs-out")
@@ -172,17 +406,111 @@ where
         Ok(out_conn)
     }
 
+    /// Returns the monitor of recent MIDI events.
+    pub fn midi_monitor(&self) -> &MidiMonitor {
+        &self.midi_monitor
+    }
+
+    /// Returns whether any MIDI output connection is open.
+    pub fn has_output(&self) -> bool {
+        !self.output_connections.lock().is_empty() || !self.track_connections.lock().is_empty()
+    }
+
+    /// Sets whether a bloop sends to its own virtual output, named
+    /// "Bloop.rs Track 1" and so on, instead of the enabled MIDI outputs.
+    ///
+    /// Virtual outputs are only supported on Unix, so elsewhere this does
+    /// nothing.
+    pub fn set_track_output(&mut self, bloop: usize, is_enabled: bool) {
+        let mut track_connections = self.track_connections.lock();
+        if track_connections.contains_key(&bloop) == is_enabled {
+            return;
+        }
+        if !is_enabled {
+            if let Some(mut conn) = track_connections.remove(&bloop) {
+                // Don't leave notes stuck on the port.
+                for channel in 0..16 {
+                    let event = LiveEvent::Midi {
+                        channel: channel.into(),
+                        message: MidiMessage::Controller {
+                            controller: ALL_NOTES_OFF_CONTROLLER.into(),
+                            value: 0.into(),
+                        },
+                    };
+                    let mut buffer = vec![];
+                    if event.write(&mut buffer).is_ok() {
+                        conn.send(&buffer);
+                    }
+                }
+            }
+            return;
+        }
+        #[cfg(unix)]
+        {
+            let name = format!("{TRACK_OUTPUT_PREFIX}{}", bloop + 1);
+            match new_midi_output().create_virtual(&name) {
+                Ok(conn) => {
+                    track_connections.insert(bloop, OutputConnection::new(conn));
+                    drop(track_connections);
+                    _ = self.input_tx.send(self.output_connected_event.clone());
+                }
+                Err(e) => log::error!("error creating virtual output {name:?}: {e}"),
+            }
+        }
+    }
+
+    /// Returns the names of all MIDI input ports.
+    pub fn input_port_names(&self) -> Vec<String> {
+        self.input_connections
+            .iter()
+            .map(|conn| conn.name.clone())
+            .collect()
+    }
+
     pub fn ui(&mut self, ui: &mut egui::Ui) -> Option<flume::Sender<T>> {
         let new_output_tx = None;
 
         ui.set_width(ui.available_width());
 
+        let mut ignore_change = None;
         ui.horizontal(|ui| {
             ui.label("MIDI inputs:");
 
-            for conn in &self.input_connections {
+            for (i, conn) in self.input_connections.iter().enumerate() {
                 if ui.selectable_label(conn.is_enabled(), &conn.name).clicked() {
                     conn.toggle();
+                    if !conn.is_enabled() {
+                        // The releases of keys held on this input will never
+                        // arrive, so release them now.
+                        let port_name: Arc<str> = conn.name.as_str().into();
+                        for (key, channel) in conn.take_held_keys() {
+                            let event = LiveEvent::Midi {
+                                channel,
+                                message: release(key),
+                            };
+                            _ = self.input_tx.send((Arc::clone(&port_name), event).into());
+                        }
+                    }
+                }
+                let parse_error_count = conn.stats.parse_error_count.load(Ordering::Relaxed);
+                if parse_error_count > 0 {
+                    let message_count = conn.stats.message_count.load(Ordering::Relaxed);
+                    ui.colored_label(egui::Color32::YELLOW, format!("⚠ {parse_error_count}"))
+                        .on_hover_text(format!(
+                            "{parse_error_count} of {message_count} messages \
+                             received from this input could not be parsed",
+                        ));
+                }
+                let mut ignore = conn.ignore;
+                ui.menu_button("⏷", |ui| {
+                    ui.checkbox(&mut ignore.clock, "Ignore clock");
+                    ui.checkbox(&mut ignore.active_sensing, "Ignore active sensing");
+                    ui.checkbox(&mut ignore.sysex, "Ignore SysEx");
+                })
+                .response
+                .on_hover_text("Messages to ignore from this input");
+                if ignore != conn.ignore {
+                    ignore_change = Some((i, ignore));
                 }
             }
 
@@ -190,35 +518,300 @@ where
                 self.refresh_midi_input_connections();
             }
         });
+        if let Some((i, ignore)) = ignore_change {
+            self.set_input_ignore(i, ignore);
+        }
+
+        self.reconnect_dead_outputs();
+        let output_health = self
+            .output_connections
+            .lock()
+            .iter()
+            .map(|out_conn| (out_conn.is_dead, out_conn.failure_count))
+            .collect_vec();
 
         ui.horizontal(|ui| {
             ui.label("MIDI outputs:");
 
             let mut port_names = port_names(&self.output);
             #[cfg(unix)]
-            port_names.insert(0, BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME.to_owned());
+            {
+                port_names.retain(|name| *name != self.virtual_output_name);
+                port_names.insert(0, self.virtual_output_name.clone());
+            }
             for port_name in port_names {
-                let is_selected = Some(&port_name) == self.output_port_name.as_ref();
+                let index = self.output_port_names.iter().position(|n| *n == port_name);
+                let is_selected = index.is_some();
                 if ui.selectable_label(is_selected, &port_name).clicked() {
-                    return self.open_output_connection(&port_name);
+                    match is_selected {
+                        true => self.close_output_connection(&port_name),
+                        false => self.open_output_connection(&port_name),
+                    }
+                }
+                match index.and_then(|i| output_health.get(i)) {
+                    Some(&(true, _)) => {
+                        ui.colored_label(egui::Color32::RED, "⚠ disconnected")
+                            .on_hover_text(
+                                "Sending to this output failed repeatedly. \
+                                 Reconnecting; recent events will be sent \
+                                 once it is back.",
+                            );
+                    }
+                    Some(&(false, failure_count)) if failure_count > 0 => {
+                        ui.colored_label(egui::Color32::YELLOW, format!("⚠ {failure_count}"))
+                            .on_hover_text(format!(
+                                "{failure_count} recent events could not be \
+                                 sent to this output",
+                            ));
+                    }
+                    _ => (),
                 }
             }
 
             if ui.button("⟳").on_hover_text("Refresh").clicked() {
-                self.refresh_midi_output_connections();
+                self.refresh_midi_output_ports();
+            }
+        });
+        if !self.has_output() {
+            ui.colored_label(
+                egui::Color32::RED,
+                "⚠ No MIDI output — notes are going nowhere",
+            )
+            .on_hover_text("Select a MIDI output above, or connect a device and refresh");
+        }
+
+        let mut sort_output = self.sort_output.load(Ordering::Relaxed);
+        let r = ui.checkbox(&mut sort_output, "Sort simultaneous output events");
+        if r.on_hover_text(
+            "Send events from different bloops in a stable order, \
+             at the cost of up to 1 ms of extra latency",
+        )
+        .changed()
+        {
+            self.sort_output.store(sort_output, Ordering::Relaxed);
+        }
+
+        ui.horizontal(|ui| {
+            let mut max_rate = self.max_controller_rate.load(Ordering::Relaxed);
+            let mut is_thinning = max_rate > 0;
+            let r = ui.checkbox(&mut is_thinning, "Thin controller output");
+            r.on_hover_text(
+                "Skip controller changes that repeat the last value, and limit how often each \
+                 controller changes, for slow MIDI connections. The final value is always sent.",
+            );
+            match (is_thinning, max_rate) {
+                (true, 0) => max_rate = DEFAULT_MAX_CONTROLLER_RATE,
+                (false, _) => max_rate = 0,
+                (true, _) => {
+                    ui.add(
+                        egui::DragValue::new(&mut max_rate)
+                            .range(1..=1000)
+                            .suffix(" per second"),
+                    );
+                }
             }
+            self.max_controller_rate.store(max_rate, Ordering::Relaxed);
         });
 
+        let avg_micros = self.output_jitter.avg_micros.load(Ordering::Relaxed);
+        let max_micros = self.output_jitter.max_micros.load(Ordering::Relaxed);
+        ui.label(format!(
+            "Output jitter: {:.2} ms average, {:.2} ms max",
+            avg_micros as f32 / 1000.0,
+            max_micros as f32 / 1000.0,
+        ))
+        .on_hover_text(
+            "How late output events were sent compared to when they were scheduled, \
+             during the last second of output. Several milliseconds suggests that the \
+             system's timer resolution is too coarse.",
+        );
+
+        if self.feedback_detector.has_recent_echo() {
+            ui.colored_label(
+                egui::Color32::RED,
+                format!(
+                    "Possible MIDI feedback loop! Dropped {} incoming messages \
+                     identical to ones just sent. Check your MIDI routing.",
+                    self.feedback_detector.echo_count(),
+                ),
+            );
+        }
+
         new_output_tx
     }
 }
 
+/// How long the output thread waits to collect events to sort, when sorting
+/// output events is enabled.
+///
+/// This trades latency for ordering: events intended for the same time that
+/// arrive within this window are sent in a stable order (by time, then by
+/// bloop), but every event may be delayed by up to this long. With sorting
+/// disabled, events are sent immediately in the order they were queued.
+const OUTPUT_SORT_WINDOW: Duration = Duration::from_millis(1);
+
+/// Interval over which output jitter is measured.
+const JITTER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How late output events were sent during the last [`JITTER_INTERVAL`] in
+/// which any were sent.
+#[derive(Debug, Default)]
+struct OutputJitter {
+    /// Average delay, in microseconds.
+    avg_micros: AtomicU64,
+    /// Maximum delay, in microseconds.
+    max_micros: AtomicU64,
+}
+
+/// How late output events were sent during the current [`JITTER_INTERVAL`],
+/// kept by the output thread.
+#[derive(Debug)]
+struct JitterAccumulator {
+    interval_start: Instant,
+    count: u64,
+    total_micros: u64,
+    max_micros: u64,
+}
+impl JitterAccumulator {
+    fn new(now: Instant) -> Self {
+        Self {
+            interval_start: now,
+            count: 0,
+            total_micros: 0,
+            max_micros: 0,
+        }
+    }
+
+    /// Records an event intended to be sent at `intended` that was sent at
+    /// `now`, and publishes the statistics to `jitter` at the end of each
+    /// interval.
+    fn record(&mut self, intended: Instant, now: Instant, jitter: &OutputJitter) {
+        let micros = now.saturating_duration_since(intended).as_micros() as u64;
+        self.count += 1;
+        self.total_micros += micros;
+        self.max_micros = self.max_micros.max(micros);
+        if now >= self.interval_start + JITTER_INTERVAL {
+            let avg_micros = self.total_micros / self.count;
+            jitter.avg_micros.store(avg_micros, Ordering::Relaxed);
+            jitter.max_micros.store(self.max_micros, Ordering::Relaxed);
+            *self = Self::new(now);
+        }
+    }
+}
+
+/// Maximum number of changes per second for each controller when thinning
+/// controller output is first enabled.
+const DEFAULT_MAX_CONTROLLER_RATE: u32 = 50;
+
+/// Prefix of the name of each bloop's own virtual output, which is followed by
+/// the number of the bloop.
+#[cfg(unix)]
+const TRACK_OUTPUT_PREFIX: &str = "Bloop.rs Track ";
+
+/// MIDI controller number for the All Notes Off channel mode message.
+const ALL_NOTES_OFF_CONTROLLER: u8 = 123;
+
+/// Lowest MIDI controller number used for channel mode messages.
+const FIRST_CHANNEL_MODE_CONTROLLER: u8 = 120;
+
+/// Minimum time between attempts to reopen dead output connections.
+const OUTPUT_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Connection to a MIDI output, which tracks whether sending to it is
+/// failing.
+struct OutputConnection {
+    conn: MidiOutputConnection,
+    /// Number of consecutive events that could not be sent.
+    failure_count: u32,
+    /// Whether sending has failed too many times in a row, in which case the
+    /// connection needs to be reopened.
+    is_dead: bool,
+    /// Events that could not be sent, oldest first, to retry once the
+    /// connection recovers.
+    pending: VecDeque<Vec<u8>>,
+}
+impl OutputConnection {
+    /// Number of consecutive failures after which the connection is
+    /// considered dead.
+    const MAX_FAILURES: u32 = 8;
+    /// Maximum number of events to hold while the connection is failing.
+    ///
+    /// This is enough to cover a brief glitch without buffering minutes of
+    /// stale output to dump all at once when the port comes back.
+    const MAX_PENDING: usize = 256;
+
+    fn new(conn: MidiOutputConnection) -> Self {
+        Self {
+            conn,
+            failure_count: 0,
+            is_dead: false,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Sends a raw MIDI message, or buffers it if the connection is failing.
+    fn send(&mut self, message: &[u8]) {
+        if !self.is_dead {
+            self.flush();
+        }
+        if !self.is_dead && self.pending.is_empty() {
+            match self.conn.send(message) {
+                Ok(()) => {
+                    self.failure_count = 0;
+                    return;
+                }
+                Err(e) => self.record_failure(e),
+            }
+        }
+        if self.pending.len() >= Self::MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(message.to_vec());
+    }
+
+    /// Sends buffered events in order, stopping at the first failure.
+    fn flush(&mut self) {
+        while let Some(message) = self.pending.front() {
+            if let Err(e) = self.conn.send(message) {
+                self.record_failure(e);
+                return;
+            }
+            self.pending.pop_front();
+            self.failure_count = 0;
+        }
+    }
+
+    fn record_failure(&mut self, e: impl std::fmt::Display) {
+        self.failure_count += 1;
+        log::error!("Error sending MIDI event to output: {e}");
+        if self.failure_count >= Self::MAX_FAILURES {
+            log::error!("MIDI output is not responding; marking it dead");
+            self.is_dead = true;
+        }
+    }
+
+    /// Replaces a dead connection and sends any buffered events.
+    fn reconnect(&mut self, conn: MidiOutputConnection) {
+        self.conn = conn;
+        self.failure_count = 0;
+        self.is_dead = false;
+        self.flush();
+    }
+}
+
 /// Handle to an active MIDI connection.
 pub struct MidiInputConnectionHandle {
     /// Name of the connection that is displayed to the user.
     pub name: String,
     /// Whether the application is listening to this MIDI input.
     is_enabled: Arc<AtomicBool>,
+    /// Messages from this MIDI input that are dropped before parsing.
+    ignore: InputIgnore,
+    /// Statistics about messages received from this MIDI input.
+    stats: Arc<InputStats>,
+    /// Channels on which each key has been pressed on this MIDI input and not
+    /// yet released.
+    held_keys: Arc<Mutex<PerKey<ChannelSet>>>,
     /// The MIDI input callback will be called until this field is dropped.
     _connection: MidiInputConnection<()>,
 }
@@ -231,6 +824,340 @@ impl MidiInputConnectionHandle {
     pub fn is_enabled(&self) -> bool {
         self.is_enabled.load(Ordering::Relaxed)
     }
+    /// Returns the channels on which each key is held on this MIDI input, and
+    /// forgets them.
+    fn take_held_keys_state(&self) -> PerKey<ChannelSet> {
+        std::mem::take(&mut *self.held_keys.lock())
+    }
+    /// Returns the keys held on this MIDI input, along with the channel of each
+    /// press, and forgets them.
+    fn take_held_keys(&self) -> Vec<(u7, u4)> {
+        let held_keys = self.take_held_keys_state();
+        held_keys
+            .iter()
+            .flat_map(|(key, channels)| channels.iter_channels().map(move |c| (key, c)))
+            .collect()
+    }
+}
+
+/// Kinds of message that a MIDI input ignores, to keep a noisy device from
+/// interfering while another provides sync.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+struct InputIgnore {
+    /// Whether to ignore SysEx messages, including MIDI Machine Control.
+    sysex: bool,
+    /// Whether to ignore MIDI clock and other timing messages.
+    clock: bool,
+    /// Whether to ignore active sensing.
+    active_sensing: bool,
+}
+impl InputIgnore {
+    /// Returns the flags to pass to [`MidiInput::ignore()`].
+    fn flags(self) -> midir::Ignore {
+        use midir::Ignore;
+
+        match (self.sysex, self.clock, self.active_sensing) {
+            (false, false, false) => Ignore::None,
+            (true, false, false) => Ignore::Sysex,
+            (false, true, false) => Ignore::Time,
+            (true, true, false) => Ignore::SysexAndTime,
+            (false, false, true) => Ignore::ActiveSense,
+            (true, false, true) => Ignore::SysexAndActiveSense,
+            (false, true, true) => Ignore::TimeAndActiveSense,
+            (true, true, true) => Ignore::All,
+        }
+    }
+}
+
+/// Statistics about messages received from a MIDI input.
+#[derive(Debug, Default)]
+struct InputStats {
+    /// Number of raw messages received.
+    message_count: AtomicU64,
+    /// Number of messages that could not be parsed.
+    parse_error_count: AtomicU64,
+}
+
+/// Reassembles raw bytes from a MIDI input into complete messages.
+///
+/// Some interfaces split long SysEx messages across several callbacks or omit
+/// repeated status bytes ("running status"), neither of which
+/// [`LiveEvent::parse()`] can handle on its own.
+#[derive(Debug, Default)]
+struct InputParser {
+    /// Partial SysEx message, starting with `0xF0`, if one is in progress.
+    sysex_buffer: Option<Vec<u8>>,
+    /// Most recent channel status byte, for running status.
+    running_status: Option<u8>,
+    /// Scratch buffer for messages that use running status.
+    scratch: Vec<u8>,
+}
+impl InputParser {
+    /// Maximum length of a SysEx message, to avoid buffering forever if the
+    /// end of a message is lost.
+    const MAX_SYSEX_LEN: usize = 1 << 16;
+
+    /// Parses raw bytes from a MIDI input, calling `handle` for each complete
+    /// message or error.
+    fn feed(&mut self, message: &[u8], mut handle: impl FnMut(Result<LiveEvent<'_>>)) {
+        let Some(&status) = message.first() else {
+            return;
+        };
+
+        // System real-time messages may appear anywhere, even in the middle of
+        // a SysEx message, and do not affect running status.
+        if status >= 0xF8 {
+            handle(LiveEvent::parse(message).map_err(|e| eyre!("{e}")));
+            return;
+        }
+
+        if let Some(sysex_buffer) = &mut self.sysex_buffer {
+            if status < 0x80 || status == 0xF7 {
+                // Continuation of a SysEx message.
+                match message.iter().position(|&b| b == 0xF7) {
+                    Some(end) => {
+                        sysex_buffer.extend_from_slice(&message[..=end]);
+                        let sysex_buffer = self.sysex_buffer.take().unwrap_or_default();
+                        handle(LiveEvent::parse(&sysex_buffer).map_err(|e| eyre!("{e}")));
+                        if end + 1 < message.len() {
+                            self.feed(&message[end + 1..], handle);
+                        }
+                    }
+                    None if sysex_buffer.len() + message.len() > Self::MAX_SYSEX_LEN => {
+                        self.sysex_buffer = None;
+                        handle(Err(eyre!("SysEx message too long")));
+                    }
+                    None => sysex_buffer.extend_from_slice(message),
+                }
+                return;
+            }
+            // Any other status byte terminates the SysEx message early.
+            self.sysex_buffer = None;
+            handle(Err(eyre!("SysEx message interrupted")));
+        }
+
+        match status {
+            0xF0 if !message.contains(&0xF7) => {
+                self.running_status = None;
+                self.sysex_buffer = Some(message.to_vec());
+            }
+            0x80..=0xEF => {
+                self.running_status = Some(status);
+                handle(LiveEvent::parse(message).map_err(|e| eyre!("{e}")));
+            }
+            0xF0..=0xF7 => {
+                self.running_status = None;
+                handle(LiveEvent::parse(message).map_err(|e| eyre!("{e}")));
+            }
+            _ => match self.running_status {
+                Some(running_status) => {
+                    self.scratch.clear();
+                    self.scratch.push(running_status);
+                    self.scratch.extend_from_slice(message);
+                    handle(LiveEvent::parse(&self.scratch).map_err(|e| eyre!("{e}")));
+                }
+                None => handle(Err(eyre!("data bytes without status byte"))),
+            },
+        }
+    }
+}
+
+/// Detects MIDI feedback loops by comparing incoming messages to recently sent
+/// ones.
+///
+/// An incoming message that is identical to one sent within the last
+/// [`FeedbackDetector::ECHO_WINDOW`] is assumed to be our own output routed
+/// back into an input, and is dropped to prevent runaway note storms.
+#[derive(Debug, Default)]
+struct FeedbackDetector {
+    /// Recently sent messages, with the time at which each was sent.
+    recent_outputs: Mutex<VecDeque<(Instant, Vec<u8>)>>,
+    /// Number of incoming messages that have been dropped.
+    echo_count: AtomicU64,
+    /// Time at which an incoming message was most recently dropped.
+    last_echo_time: Mutex<Option<Instant>>,
+}
+impl FeedbackDetector {
+    /// Maximum time between sending a message and receiving it again for it to
+    /// be considered an echo.
+    const ECHO_WINDOW: Duration = Duration::from_millis(20);
+    /// Maximum number of sent messages to remember.
+    const MAX_RECENT_OUTPUTS: usize = 256;
+    /// How long to warn the user after a message is dropped.
+    const WARNING_DURATION: Duration = Duration::from_secs(3);
+
+    /// Records a message that was just sent.
+    fn record_output(&self, message: &[u8]) {
+        let mut recent_outputs = self.recent_outputs.lock();
+        if recent_outputs.len() >= Self::MAX_RECENT_OUTPUTS {
+            recent_outputs.pop_front();
+        }
+        recent_outputs.push_back((Instant::now(), message.to_vec()));
+    }
+    /// Returns whether an incoming message is likely an echo of one that was
+    /// just sent, and records it if so.
+    fn is_echo(&self, message: &[u8]) -> bool {
+        let now = Instant::now();
+        let mut recent_outputs = self.recent_outputs.lock();
+        while recent_outputs
+            .front()
+            .is_some_and(|(time, _)| now - *time > Self::ECHO_WINDOW)
+        {
+            recent_outputs.pop_front();
+        }
+        let Some(i) = recent_outputs.iter().position(|(_, m)| m == message) else {
+            return false;
+        };
+        // Each sent message can only account for one incoming message.
+        recent_outputs.remove(i);
+        self.echo_count.fetch_add(1, Ordering::Relaxed);
+        *self.last_echo_time.lock() = Some(now);
+        true
+    }
+    /// Returns the total number of incoming messages that have been dropped.
+    fn echo_count(&self) -> u64 {
+        self.echo_count.load(Ordering::Relaxed)
+    }
+    /// Returns whether an incoming message has been dropped recently.
+    fn has_recent_echo(&self) -> bool {
+        self.last_echo_time
+            .lock()
+            .is_some_and(|t| t.elapsed() < Self::WARNING_DURATION)
+    }
+}
+
+/// Thins controller changes on the output, to avoid flooding slow MIDI
+/// connections.
+///
+/// A controller change is dropped if it repeats the value last sent for that
+/// channel and controller. A change that comes too soon after the previous one
+/// is held back, replacing any change already held back, and sent once enough
+/// time has passed, so that the final value always arrives. Other messages are
+/// never affected.
+#[derive(Debug, Default)]
+struct ControllerThinner {
+    controllers: HashMap<(u4, u7), ThinnedController>,
+}
+#[derive(Debug, Default)]
+struct ThinnedController {
+    /// Value most recently sent.
+    last_value: Option<u7>,
+    /// Time at which the next change may be sent.
+    next_send_time: Option<Instant>,
+    /// Minimum time between changes.
+    interval: Duration,
+    /// Change held back until `next_send_time`.
+    pending: Option<OutputEvent>,
+}
+impl ControllerThinner {
+    /// Returns whether to send `event` now, holding it back if it is a
+    /// controller change that comes too soon after the previous one.
+    fn should_send(&mut self, event: &OutputEvent, max_rate: u32, now: Instant) -> bool {
+        let LiveEvent::Midi {
+            channel,
+            message: MidiMessage::Controller { controller, value },
+        } = event.event
+        else {
+            return true;
+        };
+        let state = self.controllers.entry((channel, controller)).or_default();
+        if state.last_value == Some(value) {
+            // The output already has this value.
+            state.pending = None;
+            return false;
+        }
+        if state.next_send_time.is_some_and(|t| now < t) {
+            state.pending = Some(event.clone());
+            return false;
+        }
+        state.pending = None;
+        state.last_value = Some(value);
+        state.interval = Duration::from_secs(1) / max_rate.max(1);
+        state.next_send_time = Some(now + state.interval);
+        true
+    }
+    /// Returns the time at which the next held-back change is due.
+    fn next_flush_time(&self) -> Option<Instant> {
+        self.controllers
+            .values()
+            .filter(|state| state.pending.is_some())
+            .filter_map(|state| state.next_send_time)
+            .min()
+    }
+    /// Removes and returns held-back changes that are due at `now`.
+    fn take_due(&mut self, now: Instant) -> Vec<OutputEvent> {
+        let mut due = vec![];
+        for state in self.controllers.values_mut() {
+            if state.next_send_time.is_some_and(|t| t <= now) {
+                if let Some(event) = state.pending.take() {
+                    if let LiveEvent::Midi {
+                        message: MidiMessage::Controller { value, .. },
+                        ..
+                    } = event.event
+                    {
+                        state.last_value = Some(value);
+                    }
+                    state.next_send_time = Some(now + state.interval);
+                    due.push(event);
+                }
+            }
+        }
+        due
+    }
+}
+
+/// Output sink that sends every event to each enabled MIDI output.
+///
+/// Events from a bloop with its own virtual output go only to that output.
+/// Channel mode messages (such as All Notes Off) and system messages go to
+/// every output, including the virtual outputs of bloops.
+struct MidiOutputSink {
+    output_connections: Arc<Mutex<Vec<OutputConnection>>>,
+    track_connections: Arc<Mutex<HashMap<usize, OutputConnection>>>,
+    feedback_detector: Arc<FeedbackDetector>,
+    midi_monitor: Arc<MidiMonitor>,
+    buffer: Vec<u8>,
+}
+impl OutputSink for MidiOutputSink {
+    fn send(&mut self, event: &OutputEvent) {
+        self.buffer.clear();
+        if let Err(e) = event.event.write(&mut self.buffer) {
+            log::error!("Error writing MIDI event to buffer: {e}");
+            return;
+        }
+        let is_for_every_output = match event.event {
+            LiveEvent::Midi {
+                message: MidiMessage::Controller { controller, .. },
+                ..
+            } => controller.as_int() >= FIRST_CHANNEL_MODE_CONTROLLER,
+            LiveEvent::Midi { .. } => false,
+            _ => true,
+        };
+        let mut track_conns = self.track_connections.lock();
+        if is_for_every_output {
+            for track_conn in track_conns.values_mut() {
+                track_conn.send(&self.buffer);
+            }
+        } else if let Some(track_conn) = track_conns.get_mut(&event.source) {
+            track_conn.send(&self.buffer);
+            let port = format!("Track {}", event.source + 1);
+            self.midi_monitor
+                .record(Direction::Out, &port, &event.event);
+            return;
+        }
+        drop(track_conns);
+        self.midi_monitor
+            .record(Direction::Out, "Outputs", &event.event);
+
+        // Send the event exactly once to each enabled output.
+        let mut out_conns = self.output_connections.lock();
+        for out_conn in &mut *out_conns {
+            out_conn.send(&self.buffer);
+        }
+        if !out_conns.is_empty() {
+            self.feedback_detector.record_output(&self.buffer);
+        }
+    }
 }
 
 /// Returns a new `MidiInput`.
@@ -246,6 +1173,24 @@ pub fn new_midi_output() -> MidiOutput {
     MidiOutput::new(&format!("{APP_NAME} Output")).expect("error creating MIDI output")
 }
 
+/// Returns the name to use for the application's virtual MIDI output.
+///
+/// If the environment variable named by
+/// [`BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME_ENV_VAR`] is set, then its value is used.
+/// Otherwise a number is appended to the default name if another instance of
+/// the application is already using it.
+#[cfg(unix)]
+fn virtual_output_name() -> String {
+    if let Ok(name) = std::env::var(BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME_ENV_VAR) {
+        return name;
+    }
+    let existing_ports = port_names(&new_midi_input());
+    std::iter::once(BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME.to_owned())
+        .chain((2..).map(|i| format!("{BLOOPRS_MIDI_VIRTUAL_OUTPUT_NAME} {i}")))
+        .find(|name| !existing_ports.contains(name))
+        .expect("ran out of virtual output names")
+}
+
 /// Returns a list of the names of the MIDI ports on `midi_io`.
 fn port_names<T: MidiIO>(midi_io: &T) -> Vec<String> {
     let mut names = midi_io
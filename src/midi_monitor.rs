@@ -0,0 +1,227 @@
+//! MIDI monitor, which lists recent MIDI events going in and out of the app so
+//! that routing and mapping problems can be diagnosed.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use eframe::egui;
+use itertools::Itertools;
+use midly::live::{LiveEvent, SystemCommon, SystemRealtime};
+use midly::MidiMessage;
+use parking_lot::Mutex;
+
+/// Maximum number of events kept for the monitor. Older events are discarded.
+const MONITOR_CAPACITY: usize = 1000;
+
+/// Direction in which an event passed through the app.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// Kind of event, for filtering.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum EventKind {
+    Note,
+    Clock,
+    ActiveSensing,
+    Other,
+}
+impl EventKind {
+    fn of(event: &LiveEvent<'_>) -> Self {
+        match event {
+            LiveEvent::Midi {
+                message:
+                    MidiMessage::NoteOn { .. }
+                    | MidiMessage::NoteOff { .. }
+                    | MidiMessage::Aftertouch { .. },
+                ..
+            } => EventKind::Note,
+            LiveEvent::Realtime(SystemRealtime::TimingClock) => EventKind::Clock,
+            LiveEvent::Realtime(SystemRealtime::ActiveSensing) => EventKind::ActiveSensing,
+            _ => EventKind::Other,
+        }
+    }
+}
+
+/// MIDI event kept for the monitor.
+struct MonitorLine {
+    /// Time since the monitor was created.
+    seconds: f32,
+    direction: Direction,
+    /// Input port the event came from, or output it went to.
+    port: String,
+    kind: EventKind,
+    /// Decoded event, including its channel.
+    description: String,
+}
+impl std::fmt::Display for MonitorLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            seconds,
+            direction,
+            port,
+            kind: _,
+            description,
+        } = self;
+        let arrow = match direction {
+            Direction::In => "→",
+            Direction::Out => "←",
+        };
+        write!(f, "{seconds:9.3} {arrow} {port:20} {description}")
+    }
+}
+
+/// Which events the monitor shows.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct MonitorFilter {
+    hide_clock: bool,
+    hide_active_sensing: bool,
+    only_notes: bool,
+}
+impl Default for MonitorFilter {
+    fn default() -> Self {
+        Self {
+            hide_clock: true,
+            hide_active_sensing: true,
+            only_notes: false,
+        }
+    }
+}
+impl MonitorFilter {
+    fn shows(self, kind: EventKind) -> bool {
+        match kind {
+            EventKind::Note => true,
+            _ if self.only_notes => false,
+            EventKind::Clock => !self.hide_clock,
+            EventKind::ActiveSensing => !self.hide_active_sensing,
+            EventKind::Other => true,
+        }
+    }
+}
+
+/// Recent MIDI events, shared between the MIDI handlers and the UI.
+///
+/// Events are only recorded while the monitor is enabled, so that it costs
+/// nothing when nobody is looking at it.
+pub struct MidiMonitor {
+    is_enabled: AtomicBool,
+    lines: Mutex<VecDeque<MonitorLine>>,
+    filter: Mutex<MonitorFilter>,
+    start: Instant,
+}
+impl Default for MidiMonitor {
+    fn default() -> Self {
+        Self {
+            is_enabled: AtomicBool::new(false),
+            lines: Mutex::new(VecDeque::new()),
+            filter: Mutex::new(MonitorFilter::default()),
+            start: Instant::now(),
+        }
+    }
+}
+impl MidiMonitor {
+    /// Sets whether events are recorded.
+    pub fn set_enabled(&self, is_enabled: bool) {
+        self.is_enabled.store(is_enabled, Ordering::Relaxed);
+    }
+
+    /// Records an event that came in from or went out to `port`, if the
+    /// monitor is enabled.
+    pub fn record(&self, direction: Direction, port: &str, event: &LiveEvent<'_>) {
+        if !self.is_enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        let line = MonitorLine {
+            seconds: self.start.elapsed().as_secs_f32(),
+            direction,
+            port: port.to_owned(),
+            kind: EventKind::of(event),
+            description: describe(event),
+        };
+        let mut lines = self.lines.lock();
+        if lines.len() >= MONITOR_CAPACITY {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// Draws the recent events that pass the filters, with controls for the
+    /// filters.
+    pub fn ui(&self, ui: &mut egui::Ui) {
+        let mut filter = self.filter.lock();
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut filter.hide_clock, "Hide clock");
+            ui.checkbox(&mut filter.hide_active_sensing, "Hide active sensing");
+            ui.checkbox(&mut filter.only_notes, "Only notes");
+            if ui.button("Copy").clicked() {
+                let text = self
+                    .lines
+                    .lock()
+                    .iter()
+                    .filter(|line| filter.shows(line.kind))
+                    .join("\n");
+                ui.ctx().copy_text(text);
+            }
+            if ui.button("Clear").clicked() {
+                self.lines.lock().clear();
+            }
+        });
+        let filter = *filter;
+        egui::ScrollArea::vertical()
+            .stick_to_bottom(true)
+            .auto_shrink([false, false])
+            .show(ui, |ui| {
+                for line in self.lines.lock().iter() {
+                    if filter.shows(line.kind) {
+                        ui.label(egui::RichText::new(line.to_string()).monospace());
+                    }
+                }
+            });
+    }
+}
+
+/// Returns a human-readable description of a MIDI event.
+fn describe(event: &LiveEvent<'_>) -> String {
+    let mut s = String::new();
+    match event {
+        LiveEvent::Midi { channel, message } => {
+            _ = write!(s, "ch {:2}  ", channel.as_int() + 1);
+            _ = match message {
+                MidiMessage::NoteOn { key, vel } => {
+                    write!(s, "Note on   key {} vel {}", key.as_int(), vel.as_int())
+                }
+                MidiMessage::NoteOff { key, vel } => {
+                    write!(s, "Note off  key {} vel {}", key.as_int(), vel.as_int())
+                }
+                MidiMessage::Aftertouch { key, vel } => {
+                    write!(
+                        s,
+                        "Aftertouch key {} pressure {}",
+                        key.as_int(),
+                        vel.as_int()
+                    )
+                }
+                MidiMessage::Controller { controller, value } => {
+                    write!(s, "Controller {} = {}", controller.as_int(), value.as_int())
+                }
+                MidiMessage::ProgramChange { program } => {
+                    write!(s, "Program change {}", program.as_int())
+                }
+                MidiMessage::ChannelAftertouch { vel } => {
+                    write!(s, "Channel aftertouch pressure {}", vel.as_int())
+                }
+                MidiMessage::PitchBend { bend } => write!(s, "Pitch bend {:+}", bend.as_int()),
+            };
+        }
+        LiveEvent::Common(SystemCommon::SysEx(data)) => {
+            _ = write!(s, "SysEx ({} bytes)", data.len());
+        }
+        LiveEvent::Common(common) => _ = write!(s, "{common:?}"),
+        LiveEvent::Realtime(realtime) => _ = write!(s, "{realtime:?}"),
+    }
+    s
+}
@@ -0,0 +1,97 @@
+//! Destinations for the events that the bloops thread sends to the output.
+
+use std::io::Write;
+use std::time::Instant;
+
+use itertools::Itertools;
+
+use crate::bloop::{OutputEvent, MONITOR_SOURCE};
+
+/// Destination for output events, which receives them one at a time on the
+/// output thread in the order they are to be sent.
+pub trait OutputSink: Send + 'static {
+    /// Sends an event.
+    fn send(&mut self, event: &OutputEvent);
+}
+
+/// Output sink that writes every event to a log instead of sending it to
+/// MIDI, so that the output of a session can be compared against an expected
+/// log.
+///
+/// Each line has the intended time of the event in milliseconds since the
+/// first event, the source of the event, and the bytes of the event in hex:
+///
+/// ```text
+/// 1500.000 0 90 3c 64
+/// ```
+pub struct CaptureSink<W> {
+    writer: W,
+    /// Intended time of the first event.
+    start: Option<Instant>,
+    buffer: Vec<u8>,
+}
+impl<W: Write> CaptureSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            start: None,
+            buffer: vec![],
+        }
+    }
+}
+impl<W: Write + Send + 'static> OutputSink for CaptureSink<W> {
+    fn send(&mut self, event: &OutputEvent) {
+        self.buffer.clear();
+        if let Err(e) = event.event.write(&mut self.buffer) {
+            log::error!("Error writing MIDI event to buffer: {e}");
+            return;
+        }
+        let start = *self.start.get_or_insert(event.time);
+        let ms = event.time.saturating_duration_since(start).as_secs_f64() * 1000.0;
+        let source = match event.source {
+            MONITOR_SOURCE => "monitor".to_owned(),
+            i => i.to_string(),
+        };
+        let bytes = self.buffer.iter().map(|b| format!("{b:02x}")).join(" ");
+        let line = format!("{ms:.3} {source} {bytes}");
+        if let Err(e) = writeln!(self.writer, "{line}") {
+            log::error!("Error writing captured event: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bloop::tests::{note_on, Harness};
+    use crate::bloop::BloopCommand;
+
+    /// Returns the log of a session that records a loop and plays it back.
+    fn capture_record_and_loop() -> String {
+        let mut h = Harness::new();
+        h.command(BloopCommand::StartRecording(0));
+        h.wait_until(100);
+        h.press(60, 100);
+        h.wait_until(250);
+        h.press(64, 90);
+        h.wait_until(300);
+        h.release(60);
+        h.wait_until(600);
+        h.midi(0, note_on(64, 0));
+        h.wait_until(1000);
+        h.command(BloopCommand::StartPlaying(0));
+        h.wait_until(3500);
+
+        let mut sink = CaptureSink::new(vec![]);
+        for event in h.take_output() {
+            sink.send(&event);
+        }
+        String::from_utf8(sink.writer).unwrap()
+    }
+
+    #[test]
+    fn test_record_and_loop_golden() {
+        let golden = include_str!("../tests/golden/record_and_loop.txt");
+        assert_eq!(capture_record_and_loop(), golden);
+    }
+}
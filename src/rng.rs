@@ -0,0 +1,28 @@
+//! Small deterministic pseudorandom number generator.
+
+/// SplitMix64 pseudorandom number generator.
+///
+/// This is nowhere near good enough for cryptography, but it is fast and
+/// reproducible from a seed, which is all that musical randomness needs.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+impl Rng {
+    /// Returns a new generator with the given seed.
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+    /// Returns the next pseudorandom number.
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+    /// Returns a pseudorandom integer between `-amount` and `amount`,
+    /// inclusive.
+    pub fn jitter(&mut self, amount: u32) -> i64 {
+        let range = 2 * amount as u64 + 1;
+        (self.next_u64() % range) as i64 - amount as i64
+    }
+}